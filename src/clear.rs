@@ -1,27 +1,12 @@
-use std::fs;
+use std::{fs, path::Path};
 
 use anyhow::Result;
 
-use crate::{cli, utils};
-
-pub fn run(cli: cli::Clear) -> Result<()> {
-    let files = match cli.dir {
-        Some(d) => {
-            let mut res = vec![];
-            for e in std::fs::read_dir(d)? {
-                let Ok(e) = e else { continue };
-                let Ok(ft) = e.file_type() else { continue };
-                if ft.is_file() {
-                    res.push(e.path())
-                }
-            }
-            res
-        }
-        None => {
-            let xdg = xdg::BaseDirectories::with_prefix(utils::XDG_PREFIX)?;
-            xdg.list_data_files("")
-        }
-    };
+use crate::{cli, config::Config, index, list};
+
+pub fn run(cli: cli::Clear, config: &Config) -> Result<()> {
+    let dir = config.data_dir(cli.dir.as_ref())?;
+    let files = list::list_files(&dir)?;
 
     let mut n_deleted = 0;
     let mut n_errors = 0;
@@ -38,6 +23,7 @@ pub fn run(cli: cli::Clear) -> Result<()> {
             n_errors += 1;
         } else {
             n_deleted += 1;
+            let _ = fs::remove_file(index::index_path(Path::new(f)));
         }
     }
 
@@ -3,6 +3,24 @@ pub struct List {
     /// Storage directory
     #[arg(short = 'd', long = "dir")]
     pub dir: Option<String>,
+    /// Also show the size, number of events and duration of traces.
+    /// The last two come from indexes.
+    #[arg(short = 'l', long = "long")]
+    pub long: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Index {
+    /// Trace files to index. Can be "latest". By default, all the traces
+    /// of the storage directory.
+    #[arg(value_name = "FILE")]
+    pub jsonl_file: Vec<String>,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Index traces from scratch, instead of updating their indexes
+    #[arg(long = "rebuild")]
+    pub rebuild: bool,
 }
 
 #[derive(Debug, clap::Parser)]
@@ -17,13 +35,24 @@ pub struct Dir {
     /// Storage directory
     #[arg(short = 'd', long = "dir")]
     pub dir: Option<String>,
+    /// Show the path to the daemon's unix socket instead
+    #[arg(long = "socket")]
+    pub socket: bool,
 }
 
 #[derive(Debug, clap::Parser)]
 pub struct Serve {
-    /// Path to the unix socket to serve
+    /// Path to the unix socket to serve.
+    /// Defaults to `$TLDRS_SOCKET`, or `$XDG_RUNTIME_DIR/tldrs/tldrs.socket`.
     #[arg(long = "socket")]
     pub unix_socket: Option<String>,
+    /// Permissions of the socket, in octal (default: 0600, or 0660 with `--socket-group`).
+    /// Not used for a socket passed by systemd
+    #[arg(long = "socket-mode", value_name = "MODE")]
+    pub socket_mode: Option<String>,
+    /// Group owning the socket (name or gid). Not used for a socket passed by systemd
+    #[arg(long = "socket-group", value_name = "GROUP")]
+    pub socket_group: Option<String>,
     /// Storage directory
     #[arg(short = 'd', long = "dir")]
     pub dir: Option<String>,
@@ -32,22 +61,325 @@ pub struct Serve {
     /// and should not send `OPEN`.
     #[arg(long = "into-file")]
     pub single_file: Option<String>,
+    /// How traces are stored (default: `jsonl`)
+    #[arg(long = "store")]
+    pub store: Option<Store>,
     /// Daemonize on startup
     #[arg(long = "daemonize")]
     pub daemonize: bool,
+    /// Write the daemon's pid into this file.
+    /// With `--daemonize`, defaults to `$XDG_RUNTIME_DIR/tldrs/tldrs.pid`.
+    #[arg(long = "pid-file")]
+    pub pid_file: Option<String>,
+    /// Write logs into this file instead of stderr. The file is rotated when it gets large.
+    /// With `--daemonize`, defaults to `$XDG_STATE_HOME/tldrs/tldrs.log`.
+    #[arg(long = "log-file")]
+    pub log_file: Option<String>,
+    /// Fill in the `pid` field of events that lack one,
+    /// using the pid of the client process.
+    #[arg(long = "fill-pid")]
+    pub fill_pid: bool,
+    /// Only accept clients running as this uid. Can be repeated.
+    /// If absent, clients of any uid are accepted.
+    #[arg(long = "allow-uid", value_name = "UID")]
+    pub allow_uids: Vec<u32>,
+    /// Also accept OTLP/HTTP traces and logs on this address (e.g. `127.0.0.1:4318`)
+    #[arg(long = "otlp-http", value_name = "ADDR")]
+    pub otlp_http: Option<String>,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Stop {
+    /// Pid file of the daemon (default: `$XDG_RUNTIME_DIR/tldrs/tldrs.pid`)
+    #[arg(long = "pid-file")]
+    pub pid_file: Option<String>,
+    /// Path to the daemon's unix socket, used if there is no pid file
+    #[arg(long = "socket")]
+    pub unix_socket: Option<String>,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Status {
+    /// Path to the daemon's unix socket
+    #[arg(long = "socket")]
+    pub unix_socket: Option<String>,
+    /// Print the raw JSON answer of the daemon
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Storage of traces by the daemon.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Store {
+    /// Append-only `.jsonl` files, one event per line
+    #[default]
+    Jsonl,
+    /// SQLite databases (`.sqlite`), with tables for events, processes, threads and args
+    Sqlite,
+}
+
+/// Output format for traces.
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// A single JSON object in the Trace Event Format
+    #[default]
+    Tef,
+    /// Perfetto's native protobuf format (faster to load for large traces)
+    PerfettoProto,
+    /// speedscope's format, with one evented profile per thread
+    Speedscope,
+    /// Folded stacks for `flamegraph.pl` or inferno, weighted by self time in µs
+    Folded,
+    /// Firefox Profiler's processed profile format
+    Firefox,
+    /// An OpenTelemetry `ExportTraceServiceRequest`, as OTLP/JSON
+    OtlpJson,
+    /// A self-contained HTML report, embedding the trace
+    Html,
+}
+
+/// Options to merge several traces.
+#[derive(Debug, clap::Args)]
+pub struct MergeArgs {
+    /// Move the pids of the n-th input (from 0) by n * 10000000,
+    /// to avoid collisions between hosts
+    #[arg(long = "remap-pids")]
+    pub remap_pids: bool,
+    /// Prefix process names with the trace id of their input
+    #[arg(long = "prefix-names")]
+    pub prefix_names: bool,
+    /// Shift timestamps so that the first event with this name
+    /// happens at the same time in all inputs
+    #[arg(long = "align", value_name = "NAME")]
+    pub align: Option<String>,
 }
 
 #[derive(Debug, clap::Parser)]
 pub struct GetTEF {
-    /// The file to remove. Can be "latest".
+    /// The trace files, merged if there are several. Can be "latest".
+    #[arg(index = 1, value_name = "FILE", num_args = 1.., required = true)]
+    pub jsonl_file: Vec<String>,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Output file (.json file for TEF)
+    #[arg(short = 'o', long = "out")]
+    pub o: Option<String>,
+    /// Output format (default: from the config, or `tef`)
+    #[arg(short = 'f', long = "format")]
+    pub format: Option<OutputFormat>,
+    /// Write OTLP/JSON for the collector's `otlpjsonfile` receiver:
+    /// one `ExportTraceServiceRequest` per line, in batches of spans.
+    /// Implies `--format otlp-json`.
+    #[arg(long = "otlp-file", conflicts_with = "format")]
+    pub otlp_file: bool,
+    /// Only keep the events matching this expression (see `tldrs query`)
+    #[arg(long = "filter", value_name = "EXPR")]
+    pub filter: Option<String>,
+    /// Start of the time window: a timestamp in µs, or a duration since
+    /// the start of the trace (`2s`, `500ms`)
+    #[arg(long = "from", value_name = "TS|DURATION")]
+    pub from: Option<String>,
+    /// End of the time window, like `--from`
+    #[arg(long = "to", value_name = "TS|DURATION")]
+    pub to: Option<String>,
+    /// Only keep these processes
+    #[arg(long = "pid", value_delimiter = ',')]
+    pub pid: Vec<i64>,
+    /// Only keep these threads
+    #[arg(long = "tid", value_delimiter = ',')]
+    pub tid: Vec<i64>,
+    /// Only keep events in these categories
+    #[arg(long = "cat", value_delimiter = ',')]
+    pub cat: Vec<String>,
+    /// Maximum number of events, not counting metadata and the ends of kept spans
+    #[arg(long = "max-events", value_name = "N")]
+    pub max_events: Option<usize>,
+    /// Sampling policies, to make huge traces smaller: `min-dur=<dur>`
+    /// drops shorter spans, `coalesce=<dur>` merges runs of shorter spans
+    /// with the same name, `counter-rate=<n>` keeps n values per second of
+    /// each counter, and `max-per-name=<n>` keeps n events of each name
+    #[arg(long = "sample", value_name = "POLICY", value_delimiter = ',')]
+    pub sample: Vec<String>,
+    #[command(flatten)]
+    pub merge: MergeArgs,
+}
+
+/// Output format for `query`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum QueryFormat {
+    /// The matching events, one per line
+    #[default]
+    Jsonl,
+    /// A TEF trace with the matching events, and all metadata events
+    Tef,
+    /// The number of matching events
+    Count,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Sql {
+    /// The trace file, a SQLite database or a `.jsonl` file. Can be "latest".
     #[arg(index = 1, value_name = "FILE")]
     pub jsonl_file: String,
+    /// SQL query. Tables are `events`, `processes`, `threads` and `args`.
+    #[arg(index = 2, value_name = "QUERY")]
+    pub query: String,
     /// Storage directory.
     #[arg(short = 'd', long = "dir")]
     pub dir: Option<String>,
-    /// Output file (.json file)
+    /// Output format
+    #[arg(short = 'f', long = "format", default_value = "table")]
+    pub format: StatsFormat,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Query {
+    /// The trace file. Can be "latest".
+    #[arg(index = 1, value_name = "FILE")]
+    pub jsonl_file: String,
+    /// Filter expression, such as `name ~ "compile.*" && dur > 10ms && pid == 1234`.
+    ///
+    /// Fields are the keys of the events, `args.<key>`, `end` (`ts + dur`),
+    /// and `t`/`t_end` (start and end since the start of the trace).
+    /// Operators: `==`, `!=`, `<`, `<=`, `>`, `>=`, `~` and `!~` (regex),
+    /// `&&`, `||`, `!`. Numbers can have a duration unit: `10ms`, `2s`.
+    #[arg(index = 2, value_name = "EXPR")]
+    pub expr: String,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Output file
     #[arg(short = 'o', long = "out")]
     pub o: Option<String>,
+    /// Output format
+    #[arg(short = 'f', long = "format", default_value = "jsonl")]
+    pub format: QueryFormat,
+}
+
+/// Output format for `stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatsFormat {
+    /// Aligned columns, for humans
+    #[default]
+    Table,
+    /// CSV with a header row, durations in µs
+    Csv,
+    /// A JSON array, durations in µs
+    Json,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Stats {
+    /// The trace file. Can be "latest".
+    #[arg(index = 1, value_name = "FILE")]
+    pub jsonl_file: String,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Output format
+    #[arg(short = 'f', long = "format", default_value = "table")]
+    pub format: StatsFormat,
+    /// Also break down statistics per pid/tid
+    #[arg(long = "by-thread")]
+    pub by_thread: bool,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Counters {
+    /// The trace file. Can be "latest".
+    #[arg(index = 1, value_name = "FILE")]
+    pub jsonl_file: String,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Output format (`csv` has one row per series and bucket)
+    #[arg(short = 'f', long = "format", default_value = "table")]
+    pub format: StatsFormat,
+    /// Number of time buckets the trace is divided into
+    #[arg(long = "buckets", default_value_t = 10)]
+    pub buckets: usize,
+    /// Show the buckets of each series as a sparkline, instead of a table
+    #[arg(long = "plot")]
+    pub plot: bool,
+}
+
+/// Output format for `diff`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiffFormat {
+    /// Aligned columns, for humans
+    #[default]
+    Table,
+    /// A markdown table, for PR comments
+    Markdown,
+    /// A JSON array, durations in µs
+    Json,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct Diff {
+    /// The reference trace. Can be "latest".
+    #[arg(index = 1, value_name = "BASE")]
+    pub base: String,
+    /// The trace to compare with the reference. Can be "latest".
+    #[arg(index = 2, value_name = "NEW")]
+    pub new: String,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Output format
+    #[arg(short = 'f', long = "format", default_value = "table")]
+    pub format: DiffFormat,
+    /// A span is a regression if its total time grows by at least this percentage...
+    #[arg(long = "threshold-pct", value_name = "PCT", default_value_t = 10.)]
+    pub threshold_pct: f64,
+    /// ... and by at least this duration (e.g. `500us`, `2ms`)
+    #[arg(long = "threshold-abs", value_name = "DUR", default_value = "1ms")]
+    pub threshold_abs: String,
+    /// Only show this many changes (regressions are always shown)
+    #[arg(short = 'n', long = "limit")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, clap::Parser)]
+pub struct CriticalPath {
+    /// The trace file. Can be "latest".
+    #[arg(index = 1, value_name = "FILE")]
+    pub jsonl_file: String,
+    /// Storage directory.
+    #[arg(short = 'd', long = "dir")]
+    pub dir: Option<String>,
+    /// Also write the critical path as a TEF trace (.json) into this file
+    #[arg(short = 'o', long = "out")]
+    pub o: Option<String>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Config {
+    /// Show the effective configuration
+    Show,
 }
 
 #[derive(Debug, clap::Parser)]
@@ -58,8 +390,30 @@ pub enum Command {
     Clear(Clear),
     /// Serve as a daemon
     Serve(Serve),
-    /// get a file as a TEF file
+    /// Stop the daemon
+    Stop(Stop),
+    /// Show the state of the daemon: clients, open traces and rules
+    Status(Status),
+    /// get a file as a TEF file, or merge several files into one
+    #[command(visible_alias = "merge")]
     GetTEF(GetTEF),
+    /// Events of a trace that match an expression
+    Query(Query),
+    /// Run a SQL query on a trace
+    Sql(Sql),
+    /// Statistics on the spans of a trace
+    Stats(Stats),
+    /// Statistics on the counters of a trace, over time
+    Counters(Counters),
+    /// Compare two traces and report regressions
+    Diff(Diff),
+    /// Longest chain of dependent spans, across threads and processes
+    CriticalPath(CriticalPath),
+    /// Create or update the indexes of traces
+    Index(Index),
     /// Show directory
     Dir(Dir),
+    /// Configuration file
+    #[command(subcommand)]
+    Config(Config),
 }
@@ -0,0 +1,173 @@
+//! Configuration file, shared by the daemon and the CLI.
+//!
+//! The configuration is read from `$XDG_CONFIG_HOME/tldrs/config.toml`
+//! (or `$TLDRS_CONFIG`), then environment variables override it,
+//! and command line options override both.
+
+use std::{fs, path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli, utils};
+
+const CONFIG_FILENAME: &str = "config.toml";
+
+/// Environment variable pointing to the config file.
+pub const CONFIG_ENV_VAR: &str = "TLDRS_CONFIG";
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Storage directory (`$TLDRS_DIR`)
+    pub dir: Option<String>,
+    /// Path to the daemon's unix socket (`$TLDRS_SOCKET`)
+    pub socket: Option<String>,
+    /// Additional unix sockets the daemon listens on (`$TLDRS_LISTENERS`, colon separated)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<String>,
+    /// The daemon deletes traces that were not modified
+    /// for this many days (`$TLDRS_RETENTION_DAYS`)
+    pub retention_days: Option<u64>,
+    /// Maximum size of a trace file in bytes. Further events
+    /// are dropped (`$TLDRS_MAX_TRACE_SIZE`)
+    pub max_trace_size: Option<u64>,
+    /// Default output format for `get-tef` (`$TLDRS_FORMAT`)
+    pub format: Option<cli::OutputFormat>,
+    /// Address on which the daemon accepts OTLP/HTTP (`$TLDRS_OTLP_HTTP`)
+    pub otlp_http: Option<String>,
+    /// How the daemon stores traces (`$TLDRS_STORE`)
+    pub store: Option<cli::Store>,
+    /// Rules the daemon applies to incoming events
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+}
+
+/// A rule of the daemon: events matching `expr` are dropped, or written
+/// into the `route` trace instead.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Filter expression, as in `tldrs query`
+    #[serde(rename = "match")]
+    pub expr: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub drop: bool,
+    /// Trace id to write the events to. `{trace_id}` is replaced by the
+    /// trace id of the client.
+    pub route: Option<String>,
+}
+
+/// Path of the config file.
+pub fn config_path() -> Result<PathBuf> {
+    if let Some(p) = std::env::var_os(CONFIG_ENV_VAR) {
+        return Ok(PathBuf::from(p));
+    }
+    let xdg = xdg::BaseDirectories::with_prefix(utils::XDG_PREFIX)?;
+    Ok(xdg.get_config_home().join(CONFIG_FILENAME))
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|s| !s.is_empty())
+}
+
+fn parse_env_var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env_var(name) {
+        None => Ok(None),
+        Some(s) => Ok(Some(
+            s.parse()
+                .with_context(|| format!("Invalid value for ${name}"))?,
+        )),
+    }
+}
+
+impl Config {
+    /// Load the config file, if present, and apply environment overrides.
+    pub fn load() -> Result<Self> {
+        let path = config_path()?;
+        let mut config: Config = match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).with_context(|| format!("Parsing config file {path:?}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Config::default(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Reading config file {path:?}"));
+            }
+        };
+
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(d) = env_var("TLDRS_DIR") {
+            self.dir = Some(d);
+        }
+        if let Some(s) = env_var(utils::SOCKET_ENV_VAR) {
+            self.socket = Some(s);
+        }
+        if let Some(l) = env_var("TLDRS_LISTENERS") {
+            self.listeners = l.split(':').map(|s| s.to_string()).collect();
+        }
+        if let Some(n) = parse_env_var("TLDRS_RETENTION_DAYS")? {
+            self.retention_days = Some(n);
+        }
+        if let Some(n) = parse_env_var("TLDRS_MAX_TRACE_SIZE")? {
+            self.max_trace_size = Some(n);
+        }
+        if let Some(f) = env_var("TLDRS_FORMAT") {
+            let f = clap::ValueEnum::from_str(&f, true)
+                .map_err(|e| anyhow::anyhow!("Invalid value for $TLDRS_FORMAT: {e}"))?;
+            self.format = Some(f);
+        }
+        if let Some(a) = env_var("TLDRS_OTLP_HTTP") {
+            self.otlp_http = Some(a);
+        }
+        if let Some(s) = env_var("TLDRS_STORE") {
+            let s = clap::ValueEnum::from_str(&s, true)
+                .map_err(|e| anyhow::anyhow!("Invalid value for $TLDRS_STORE: {e}"))?;
+            self.store = Some(s);
+        }
+        Ok(())
+    }
+
+    /// Storage directory: `dir` if provided, otherwise the configured one,
+    /// otherwise `$XDG_DATA_HOME/tldrs`.
+    pub fn data_dir(&self, dir: Option<impl AsRef<str>>) -> Result<PathBuf> {
+        if let Some(d) = dir {
+            return Ok(PathBuf::from_str(d.as_ref())?);
+        }
+        if let Some(d) = &self.dir {
+            return Ok(PathBuf::from_str(d)?);
+        }
+        let xdg = xdg::BaseDirectories::with_prefix(utils::XDG_PREFIX)?;
+        Ok(xdg.get_data_home())
+    }
+
+    /// Path to the daemon's socket: `path` if provided, otherwise the configured one,
+    /// otherwise the default one.
+    pub fn socket_path(&self, path: Option<impl AsRef<str>>) -> Result<PathBuf> {
+        let path = path.map(|p| p.as_ref().to_string());
+        utils::socket_path(path.or_else(|| self.socket.clone()))
+    }
+}
+
+pub fn run(cli: cli::Config, config: &Config) -> Result<()> {
+    match cli {
+        cli::Config::Show => {
+            let path = config_path()?;
+            let found = if path.exists() { "" } else { " (not found)" };
+            println!("# config file: {}{found}", path.display());
+
+            // show the effective values
+            let mut config = config.clone();
+            config.dir = Some(config.data_dir(None::<&str>)?.display().to_string());
+            config.socket = Some(config.socket_path(None::<&str>)?.display().to_string());
+            config.format.get_or_insert_with(Default::default);
+            config.store.get_or_insert_with(Default::default);
+            print!("{}", toml::to_string(&config)?);
+        }
+    }
+    Ok(())
+}
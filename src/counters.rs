@@ -0,0 +1,347 @@
+//! Statistics on counter (`C`) events, per series and over time.
+//!
+//! Series are keyed like Perfetto does: by pid, name and `id`, with the name
+//! of the arg appended when a counter event has several args.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{stdout, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{cli, config::Config, event::Events, get_tef, stats::csv_field, utils};
+
+/// Characters of sparklines, from lowest to highest.
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Values of a counter during a time bucket. Buckets without samples carry
+/// the last value of the previous ones.
+#[derive(Serialize)]
+struct Bucket {
+    ts: f64,
+    count: usize,
+    min: Option<f64>,
+    max: Option<f64>,
+    avg: Option<f64>,
+    last: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Series {
+    pid: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    process: Option<String>,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    count: usize,
+    min: f64,
+    max: f64,
+    avg: f64,
+    last: f64,
+    buckets: Vec<Bucket>,
+}
+
+impl Series {
+    fn label(&self) -> String {
+        match &self.process {
+            Some(name) => format!("{name} ({})", self.pid),
+            None => format!("pid {}", self.pid),
+        }
+    }
+
+    /// Name of the counter, with its id if it has one.
+    fn counter(&self) -> String {
+        match &self.id {
+            Some(id) => format!("{} id: {id}", self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    fn sparkline(&self) -> String {
+        let range = self.max - self.min;
+        self.buckets
+            .iter()
+            .map(|b| match b.avg {
+                None => ' ',
+                Some(_) if range <= 0. => SPARKS[0],
+                Some(v) => {
+                    let i = ((v - self.min) / range * (SPARKS.len() - 1) as f64).round();
+                    SPARKS[(i as usize).min(SPARKS.len() - 1)]
+                }
+            })
+            .collect()
+    }
+}
+
+/// Counter values, with their timestamps, and the time range of the trace.
+#[derive(Default)]
+struct Samples {
+    /// Values of each series, keyed by pid, name and id
+    series: BTreeMap<(i64, String, String), Vec<(f64, f64)>>,
+    process_names: HashMap<i64, String>,
+    start: Option<f64>,
+    end: f64,
+}
+
+fn read_samples<R: std::io::BufRead>(events: &mut Events<R>) -> Result<Samples> {
+    let mut res = Samples::default();
+    for ev in events {
+        let ev = ev?;
+        if ev.ph == 'M' {
+            if ev.name == "process_name" {
+                if let Some(name) = ev.arg_str("name") {
+                    res.process_names.insert(ev.pid, name.to_string());
+                }
+            }
+            continue;
+        }
+        res.start = Some(res.start.map_or(ev.ts, |s| s.min(ev.ts)));
+        res.end = res.end.max(ev.end());
+
+        if ev.ph != 'C' {
+            continue;
+        }
+        let Some(args) = &ev.args else { continue };
+        let id = ev.id_str().unwrap_or_default();
+        for (k, v) in args {
+            let Some(value) = v.as_f64() else { continue };
+            let name = if args.len() == 1 {
+                ev.name.clone()
+            } else {
+                format!("{} {k}", ev.name)
+            };
+            res.series
+                .entry((ev.pid, name, id.clone()))
+                .or_default()
+                .push((ev.ts, value));
+        }
+    }
+    Ok(res)
+}
+
+fn compute(samples: Samples, n_buckets: usize) -> Vec<Series> {
+    let n_buckets = n_buckets.max(1);
+    let start = samples.start.unwrap_or_default();
+    let width = (samples.end - start) / n_buckets as f64;
+
+    let mut res = vec![];
+    for ((pid, name, id), mut values) in samples.series {
+        values.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut buckets: Vec<Bucket> = (0..n_buckets)
+            .map(|i| Bucket {
+                ts: start + i as f64 * width,
+                count: 0,
+                min: None,
+                max: None,
+                avg: None,
+                last: None,
+            })
+            .collect();
+        let mut sums = vec![0.; n_buckets];
+        for &(ts, v) in &values {
+            let i = if width > 0. {
+                (((ts - start) / width) as usize).min(n_buckets - 1)
+            } else {
+                0
+            };
+            let b = &mut buckets[i];
+            b.count += 1;
+            b.min = Some(b.min.map_or(v, |m| m.min(v)));
+            b.max = Some(b.max.map_or(v, |m| m.max(v)));
+            b.last = Some(v);
+            sums[i] += v;
+        }
+        let mut carried = None;
+        for (b, sum) in buckets.iter_mut().zip(sums) {
+            if b.count == 0 {
+                b.min = carried;
+                b.max = carried;
+                b.avg = carried;
+                b.last = carried;
+            } else {
+                b.avg = Some(sum / b.count as f64);
+                carried = b.last;
+            }
+        }
+
+        let count = values.len();
+        let vs = values.iter().map(|(_, v)| *v);
+        res.push(Series {
+            pid,
+            process: samples.process_names.get(&pid).cloned(),
+            name,
+            id: Some(id).filter(|id| !id.is_empty()),
+            count,
+            min: vs.clone().fold(f64::INFINITY, f64::min),
+            max: vs.clone().fold(f64::NEG_INFINITY, f64::max),
+            avg: vs.sum::<f64>() / count as f64,
+            last: values.last().map_or(0., |(_, v)| *v),
+            buckets,
+        });
+    }
+    res
+}
+
+/// Counter values are often integers.
+fn fmt_value(v: f64) -> String {
+    if v.fract() == 0. && v.abs() < 1e15 {
+        format!("{v:.0}")
+    } else {
+        format!("{v:.2}")
+    }
+}
+
+fn fmt_opt(v: Option<f64>) -> String {
+    v.map(fmt_value).unwrap_or_default()
+}
+
+fn write_table(series: &[Series], plot: bool, start: f64, w: &mut impl Write) -> Result<()> {
+    let mut header = ["process", "counter", "count", "min", "max", "avg", "last"].to_vec();
+    if plot {
+        header.push("plot");
+    }
+    let mut lines: Vec<Vec<String>> = vec![header.iter().map(|s| s.to_string()).collect()];
+    for s in series {
+        let mut line = vec![s.label(), s.counter(), s.count.to_string()];
+        line.extend([s.min, s.max, s.avg, s.last].map(fmt_value));
+        if plot {
+            line.push(s.sparkline());
+        }
+        lines.push(line);
+    }
+    utils::write_columns(&lines, 2, w)?;
+    if plot {
+        return Ok(());
+    }
+
+    for s in series {
+        writeln!(w, "\n{} / {}", s.label(), s.counter())?;
+        let header = ["  start", "count", "min", "max", "avg", "last"];
+        let mut lines: Vec<Vec<String>> = vec![header.iter().map(|s| s.to_string()).collect()];
+        for b in &s.buckets {
+            lines.push(vec![
+                format!("  {}", utils::fmt_dur(b.ts - start)),
+                b.count.to_string(),
+                fmt_opt(b.min),
+                fmt_opt(b.max),
+                fmt_opt(b.avg),
+                fmt_opt(b.last),
+            ]);
+        }
+        utils::write_columns(&lines, 0, w)?;
+    }
+    Ok(())
+}
+
+fn write_csv(series: &[Series], w: &mut impl Write) -> Result<()> {
+    writeln!(w, "pid,process,name,id,ts_us,count,min,max,avg,last")?;
+    for s in series {
+        for b in &s.buckets {
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{},{},{}",
+                s.pid,
+                csv_field(s.process.as_deref().unwrap_or_default()),
+                csv_field(&s.name),
+                csv_field(s.id.as_deref().unwrap_or_default()),
+                b.ts,
+                b.count,
+                fmt_opt(b.min),
+                fmt_opt(b.max),
+                fmt_opt(b.avg),
+                fmt_opt(b.last)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn run(cli: cli::Counters, config: &Config) -> Result<()> {
+    let file = get_tef::find_trace_file(cli.jsonl_file, cli.dir.as_ref(), config)?;
+    log::debug!("reading trace from file {file:?}");
+    let reader = utils::open_trace(Path::new(&file))?;
+
+    let mut events = Events::new(reader);
+    let samples = read_samples(&mut events)?;
+    events.report_invalid();
+
+    let start = samples.start.unwrap_or_default();
+    let series = compute(samples, cli.buckets);
+
+    let mut out = BufWriter::new(stdout().lock());
+    match cli.format {
+        cli::StatsFormat::Table => write_table(&series, cli.plot, start, &mut out)?,
+        cli::StatsFormat::Csv => write_csv(&series, &mut out)?,
+        cli::StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &series)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(trace: &str, n_buckets: usize) -> Vec<Series> {
+        let mut events = Events::new(trace.as_bytes());
+        compute(read_samples(&mut events).unwrap(), n_buckets)
+    }
+
+    #[test]
+    fn series_by_id() {
+        let trace = r#"{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{"name":"srv"}}
+{"ph":"C","name":"queue","id":"a","pid":1,"tid":1,"ts":0,"args":{"len":4}}
+{"ph":"C","name":"queue","id":"b","pid":1,"tid":1,"ts":10,"args":{"len":1}}
+{"ph":"C","name":"queue","id":"a","pid":1,"tid":1,"ts":60,"args":{"len":8}}
+{"ph":"C","name":"queue","pid":1,"tid":1,"ts":70,"args":{"len":2}}
+{"ph":"C","name":"mem","pid":2,"tid":1,"ts":90,"args":{"rss":3,"heap":1,"note":"x"}}
+{"ph":"X","name":"work","pid":1,"tid":1,"ts":0,"dur":100}
+"#;
+        let res = series(trace, 2);
+        let names: Vec<(i64, String)> = res.iter().map(|s| (s.pid, s.counter())).collect();
+        assert_eq!(
+            names,
+            [
+                (1, "queue".to_string()),
+                (1, "queue id: a".to_string()),
+                (1, "queue id: b".to_string()),
+                (2, "mem heap".to_string()),
+                (2, "mem rss".to_string()),
+            ]
+        );
+
+        let a = &res[1];
+        assert_eq!(a.label(), "srv (1)");
+        assert_eq!((a.count, a.min, a.max, a.avg, a.last), (2, 4., 8., 6., 8.));
+        let buckets: Vec<(f64, usize, Option<f64>)> =
+            a.buckets.iter().map(|b| (b.ts, b.count, b.avg)).collect();
+        assert_eq!(buckets, [(0., 1, Some(4.)), (50., 1, Some(8.))]);
+        assert_eq!(a.sparkline(), "▁█");
+
+        // buckets without samples carry the previous value
+        let b = &res[2];
+        assert_eq!(b.buckets[1].count, 0);
+        assert_eq!(b.buckets[1].last, Some(1.));
+        assert_eq!(res[3].label(), "pid 2");
+    }
+
+    #[test]
+    fn csv() {
+        let trace = r#"{"ph":"C","name":"a,b","id":1,"pid":1,"tid":1,"ts":0,"args":{"v":1.5}}"#;
+        let mut out = vec![];
+        write_csv(&series(trace, 1), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out.lines().nth(1),
+            Some(r#"1,,"a,b",1,0,1,1.50,1.50,1.50,1.50"#)
+        );
+    }
+}
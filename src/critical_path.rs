@@ -0,0 +1,474 @@
+//! Critical path of a trace, across threads and processes.
+//!
+//! The dependency graph has a node per span (`X`, `B`/`E`) and per async
+//! span (`b`/`e`). Dependencies are:
+//! - nesting, and the order of spans on a thread;
+//! - flow events (`s`/`t`/`f`), each step going from the span enclosing
+//!   a flow event to the span enclosing the next one;
+//! - async spans, which start from the span enclosing their `b` event,
+//!   and end in the span enclosing their `e` event.
+//!
+//! The critical path is found by walking backwards from the span that ends
+//! last: at each step, we follow whatever happened last before the current
+//! time (a child ending, or a dependency arriving).
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{stdout, BufWriter, Write},
+    ops::Range,
+    path::Path,
+};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{
+    cli,
+    config::Config,
+    event::{Event, Events},
+    get_tef,
+    spans::{Span, Spans},
+    utils,
+};
+
+/// Category of the events written with `--out`.
+const CRITICAL_PATH_CAT: &str = "critical_path";
+
+/// Something that happened in `from_node` at `from_ts`, and that a node waited for at `at`.
+#[derive(Debug, Clone, Copy)]
+struct Dep {
+    at: f64,
+    from_node: usize,
+    from_ts: f64,
+}
+
+/// A piece of the critical path, spent in `node`.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    node: usize,
+    start: f64,
+    end: f64,
+    /// Set if we got to this segment through a dependency from this point
+    dep_from: Option<(usize, f64)>,
+}
+
+struct Graph {
+    /// Spans, then async spans
+    nodes: Vec<Span>,
+    /// Number of (non async) spans
+    n_spans: usize,
+    children: Vec<Vec<usize>>,
+    deps: Vec<Vec<Dep>>,
+    /// Range of spans for each thread
+    threads: HashMap<(i64, i64), Range<usize>>,
+    /// Top-level spans of each thread, sorted by end
+    roots: HashMap<(i64, i64), Vec<usize>>,
+    /// Number of flow or async events not inside any span
+    n_unbound: usize,
+}
+
+impl Graph {
+    fn new(spans: &Spans, evs: &[Event]) -> Self {
+        let n_spans = spans.spans.len();
+        let mut nodes = spans.spans.clone();
+        // async spans that were never closed are left out, nothing waited for their end
+        nodes.extend(
+            spans
+                .async_spans
+                .iter()
+                .filter(|s| s.end_tid.is_some())
+                .cloned(),
+        );
+        let mut children = vec![vec![]; n_spans];
+        for (i, span) in spans.spans.iter().enumerate() {
+            if let Some(p) = span.parent {
+                children[p].push(i);
+            }
+        }
+
+        let mut threads: HashMap<(i64, i64), Range<usize>> = HashMap::new();
+        for (i, span) in spans.spans.iter().enumerate() {
+            threads
+                .entry((span.pid, span.tid))
+                .and_modify(|r| r.end = i + 1)
+                .or_insert(i..i + 1);
+        }
+        let mut roots: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+        for (i, span) in spans.spans.iter().enumerate() {
+            if span.parent.is_none() {
+                roots.entry((span.pid, span.tid)).or_default().push(i);
+            }
+        }
+        for r in roots.values_mut() {
+            r.sort_by(|&a, &b| nodes[a].end.total_cmp(&nodes[b].end));
+        }
+
+        let mut g = Graph {
+            nodes: vec![],
+            n_spans,
+            children,
+            deps: vec![],
+            threads,
+            roots,
+            n_unbound: 0,
+        };
+
+        let mut deps: Vec<(usize, Dep)> = vec![];
+        for node in n_spans..nodes.len() {
+            let span = &nodes[node];
+            match g.enclosing(&nodes, span.pid, span.tid, span.start) {
+                Some(from_node) => deps.push((
+                    node,
+                    Dep {
+                        at: span.start,
+                        from_node,
+                        from_ts: span.start,
+                    },
+                )),
+                None => g.n_unbound += 1,
+            }
+            let end_tid = span.end_tid.unwrap_or(span.tid);
+            match g.enclosing(&nodes, span.pid, end_tid, span.end) {
+                Some(to_node) => deps.push((
+                    to_node,
+                    Dep {
+                        at: span.end,
+                        from_node: node,
+                        from_ts: span.end,
+                    },
+                )),
+                None => g.n_unbound += 1,
+            }
+        }
+
+        let mut last_flow_point: HashMap<(String, String), (usize, f64)> = HashMap::new();
+        for ev in evs {
+            match ev.ph {
+                's' | 't' | 'f' => {
+                    let key = (ev.cat.clone(), ev.id_str().unwrap_or_default());
+                    let Some(node) = g.enclosing(&nodes, ev.pid, ev.tid, ev.ts) else {
+                        g.n_unbound += 1;
+                        continue;
+                    };
+                    if ev.ph != 's' {
+                        if let Some(&(from_node, from_ts)) = last_flow_point.get(&key) {
+                            let at = ev.ts;
+                            deps.push((
+                                node,
+                                Dep {
+                                    at,
+                                    from_node,
+                                    from_ts,
+                                },
+                            ));
+                        }
+                    }
+                    if ev.ph == 'f' {
+                        last_flow_point.remove(&key);
+                    } else {
+                        last_flow_point.insert(key, (node, ev.ts));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        g.deps = vec![vec![]; nodes.len()];
+        for (node, dep) in deps {
+            g.deps[node].push(dep);
+        }
+        g.children.resize(nodes.len(), vec![]);
+        g.nodes = nodes;
+        g
+    }
+
+    /// Innermost span on the thread that contains `ts`.
+    fn enclosing(&self, nodes: &[Span], pid: i64, tid: i64, ts: f64) -> Option<usize> {
+        let range = self.threads.get(&(pid, tid))?.clone();
+        // last span that started at or before `ts`
+        let k = range.start + nodes[range.clone()].partition_point(|s| s.start <= ts);
+        let mut cur = (k > range.start).then(|| k - 1);
+        // the innermost enclosing span is `cur` or one of its ancestors
+        while let Some(i) = cur {
+            if nodes[i].end >= ts {
+                return Some(i);
+            }
+            cur = nodes[i].parent;
+        }
+        None
+    }
+
+    /// Top-level span on the same thread as `node` that ended last, at or before `t`.
+    fn previous_root(&self, node: usize, t: f64) -> Option<usize> {
+        if node >= self.n_spans {
+            return None;
+        }
+        let span = &self.nodes[node];
+        let roots = &self.roots[&(span.pid, span.tid)];
+        let k = roots.partition_point(|&i| self.nodes[i].end <= t);
+        roots[..k]
+            .iter()
+            .rev()
+            .copied()
+            .find(|&i| i != node && self.nodes[i].start < t)
+    }
+
+    /// Walk backwards from the end of the trace. Returns segments in order.
+    fn critical_path(&self) -> Vec<Segment> {
+        let Some(mut node) =
+            (0..self.nodes.len()).max_by(|&a, &b| self.nodes[a].end.total_cmp(&self.nodes[b].end))
+        else {
+            return vec![];
+        };
+        let mut t = self.nodes[node].end;
+        let mut res: Vec<Segment> = vec![];
+
+        // each step goes back in time or into a child, but bad traces can have cycles
+        let max_steps = 4 * self.nodes.len() + 16;
+        for _ in 0..max_steps {
+            let span = &self.nodes[node];
+
+            // what happened last before `t`, in this span?
+            enum Next {
+                Child(usize),
+                Dep(Dep),
+            }
+            let mut best: Option<(f64, Next)> = None;
+            for &c in &self.children[node] {
+                let cs = &self.nodes[c];
+                if cs.end <= t && cs.start < t && best.as_ref().map_or(true, |b| cs.end > b.0) {
+                    best = Some((cs.end, Next::Child(c)));
+                }
+            }
+            for d in &self.deps[node] {
+                if d.at <= t && d.from_ts < t && best.as_ref().map_or(true, |b| d.at > b.0) {
+                    best = Some((d.at, Next::Dep(*d)));
+                }
+            }
+
+            match best {
+                Some((key, next)) if key >= span.start => {
+                    res.push(Segment {
+                        node,
+                        start: key,
+                        end: t,
+                        dep_from: None,
+                    });
+                    match next {
+                        Next::Child(c) => {
+                            node = c;
+                            t = self.nodes[c].end;
+                        }
+                        Next::Dep(d) => {
+                            res.last_mut().unwrap().dep_from = Some((d.from_node, d.from_ts));
+                            node = d.from_node;
+                            t = d.from_ts;
+                        }
+                    }
+                }
+                _ => {
+                    res.push(Segment {
+                        node,
+                        start: span.start,
+                        end: t,
+                        dep_from: None,
+                    });
+                    t = span.start;
+                    if let Some(p) = span.parent {
+                        node = p;
+                    } else if let Some(prev) = self.previous_root(node, t) {
+                        node = prev;
+                        t = self.nodes[prev].end;
+                    } else {
+                        break;
+                    }
+                }
+            }
+        }
+
+        res.retain(|s| s.end > s.start || s.dep_from.is_some());
+        res.reverse();
+        res
+    }
+
+    fn label(&self, spans: &Spans, node: usize) -> String {
+        let s = &self.nodes[node];
+        let thread = spans.thread_label(s.pid, s.tid);
+        if node >= self.n_spans {
+            format!("{thread} (async)")
+        } else {
+            thread
+        }
+    }
+}
+
+/// The critical path as TEF events, along with metadata of the original trace.
+fn to_tef(g: &Graph, evs: &[Event], path: &[Segment]) -> Vec<Value> {
+    let mut res = vec![];
+    for ev in evs.iter().filter(|ev| ev.ph == 'M') {
+        res.push(json!({
+            "ph": "M", "name": ev.name, "pid": ev.pid, "tid": ev.tid, "args": ev.args,
+        }));
+    }
+    for (i, seg) in path.iter().enumerate() {
+        let span = &g.nodes[seg.node];
+        let mut args = span.args.clone().unwrap_or_default();
+        args.insert("span_cat".into(), span.cat.clone().into());
+        res.push(json!({
+            "ph": "X",
+            "name": span.name,
+            "cat": CRITICAL_PATH_CAT,
+            "ts": seg.start,
+            "dur": seg.end - seg.start,
+            "pid": span.pid,
+            "tid": span.tid,
+            "args": args,
+        }));
+        if let Some((from_node, from_ts)) = seg.dep_from {
+            // arrow from the dependency to this segment
+            let from = &g.nodes[from_node];
+            let common = json!({"name": "critical_path", "cat": CRITICAL_PATH_CAT, "id": i});
+            let mut s = common.clone();
+            s["ph"] = "s".into();
+            s["ts"] = from_ts.into();
+            s["pid"] = from.pid.into();
+            s["tid"] = from.tid.into();
+            let mut f = common;
+            f["ph"] = "f".into();
+            f["bp"] = "e".into();
+            f["ts"] = seg.start.into();
+            f["pid"] = span.pid.into();
+            f["tid"] = span.tid.into();
+            res.push(s);
+            res.push(f);
+        }
+    }
+    res
+}
+
+pub fn run(cli: cli::CriticalPath, config: &Config) -> Result<()> {
+    let file = get_tef::find_trace_file(cli.jsonl_file, cli.dir.as_ref(), config)?;
+    log::debug!("reading trace from file {file:?}");
+    let mut events = Events::new(utils::open_trace(Path::new(&file))?);
+    let evs: Vec<Event> = (&mut events).collect::<Result<_>>()?;
+    events.report_invalid();
+
+    let spans = Spans::from_events(evs.iter().cloned().map(Ok))?;
+    let g = Graph::new(&spans, &evs);
+    if g.n_unbound > 0 {
+        log::warn!(
+            "Ignored {} flow or async events outside of any span.",
+            g.n_unbound
+        );
+    }
+    let path = g.critical_path();
+
+    let mut out = BufWriter::new(stdout().lock());
+    if let (Some(first), Some(last)) = (path.first(), path.last()) {
+        let t0 = first.start;
+        writeln!(
+            out,
+            "critical path: {} in {} segments",
+            utils::fmt_dur(last.end - t0),
+            path.len()
+        )?;
+        let mut lines: Vec<Vec<String>> = vec![["thread", "span", "start", "duration"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect()];
+        for seg in &path {
+            lines.push(vec![
+                g.label(&spans, seg.node),
+                g.nodes[seg.node].name.clone(),
+                utils::fmt_dur(seg.start - t0),
+                utils::fmt_dur(seg.end - seg.start),
+            ]);
+        }
+        utils::write_columns(&lines, 2, &mut out)?;
+    } else {
+        writeln!(out, "no spans")?;
+    }
+    out.flush()?;
+
+    if let Some(path_out) = cli.o {
+        let mut w = BufWriter::new(fs::File::create(&path_out)?);
+        serde_json::to_writer(&mut w, &to_tef(&g, &evs, &path))?;
+        writeln!(w)?;
+        w.flush()?;
+        log::info!("wrote critical path into {path_out:?}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Node names and times of the critical path of `trace`.
+    fn path(trace: &str) -> Vec<(String, f64, f64)> {
+        let evs: Vec<Event> = Events::new(trace.as_bytes())
+            .collect::<Result<_>>()
+            .unwrap();
+        let spans = Spans::from_events(evs.iter().cloned().map(Ok)).unwrap();
+        let g = Graph::new(&spans, &evs);
+        g.critical_path()
+            .iter()
+            .map(|s| (g.nodes[s.node].name.clone(), s.start, s.end))
+            .collect()
+    }
+
+    #[test]
+    fn flow_between_threads() {
+        // main sends work to a worker thread, then waits for its reply
+        let trace = r#"{"ph":"X","name":"main","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"send","pid":1,"tid":1,"ts":10,"dur":5}
+{"ph":"s","name":"job","cat":"c","id":1,"pid":1,"tid":1,"ts":12}
+{"ph":"X","name":"work","pid":1,"tid":2,"ts":20,"dur":60}
+{"ph":"f","name":"job","cat":"c","id":1,"pid":1,"tid":2,"ts":21,"bp":"e"}
+{"ph":"s","name":"reply","cat":"c","id":2,"pid":1,"tid":2,"ts":79}
+{"ph":"X","name":"wait","pid":1,"tid":1,"ts":16,"dur":74}
+{"ph":"f","name":"reply","cat":"c","id":2,"pid":1,"tid":1,"ts":85,"bp":"e"}
+"#;
+        assert_eq!(
+            path(trace),
+            [
+                ("main".to_string(), 0., 10.),
+                ("send".to_string(), 10., 12.),
+                ("work".to_string(), 21., 79.),
+                ("wait".to_string(), 85., 90.),
+                ("main".to_string(), 90., 100.),
+            ]
+        );
+    }
+
+    #[test]
+    fn async_span_and_previous_roots() {
+        let trace = r#"{"ph":"X","name":"a","pid":1,"tid":1,"ts":0,"dur":10}
+{"ph":"X","name":"b","pid":1,"tid":1,"ts":20,"dur":10}
+{"ph":"b","name":"io","cat":"x","id":1,"pid":1,"tid":1,"ts":25}
+{"ph":"X","name":"c","pid":1,"tid":2,"ts":40,"dur":20}
+{"ph":"e","name":"io","cat":"x","id":1,"pid":1,"tid":2,"ts":50}
+{"ph":"b","name":"io","cat":"x","id":2,"pid":2,"tid":1,"ts":5}
+{"ph":"e","name":"io","cat":"x","id":2,"pid":2,"tid":1,"ts":1}
+"#;
+        assert_eq!(
+            path(trace),
+            [
+                ("a".to_string(), 0., 10.),
+                ("b".to_string(), 20., 25.),
+                ("io".to_string(), 25., 50.),
+                ("c".to_string(), 50., 60.),
+            ]
+        );
+
+        // an `e` that happens before its `b` does not make a negative span
+        let evs: Vec<Event> = Events::new(trace.as_bytes())
+            .collect::<Result<_>>()
+            .unwrap();
+        let spans = Spans::from_events(evs.iter().cloned().map(Ok)).unwrap();
+        let g = Graph::new(&spans, &evs);
+        let io = g.nodes.iter().find(|s| s.pid == 2).unwrap();
+        assert_eq!((io.start, io.end, io.self_dur), (5., 5., 0.));
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use daemonize::{Daemonize, Outcome};
+
+/// Used by the daemon to tell the parent process whether it started properly.
+pub struct Startup(UnixStream);
+
+impl Startup {
+    /// Send the outcome of the daemon's startup to the parent process, which then exits.
+    pub fn report<T>(mut self, res: &Result<T>) {
+        let msg = match res {
+            Ok(_) => format!("OK {}\n", std::process::id()),
+            Err(err) => format!("ERR {err:#}\n"),
+        };
+        if let Err(err) = self.0.write_all(msg.as_bytes()) {
+            log::warn!("Could not report startup to parent process: {err:?}");
+        }
+    }
+}
+
+/// Daemonize, chdir into `dir`, and lock and write `pid_file`.
+///
+/// The parent process waits until the daemon reports (using the returned
+/// [`Startup`]) whether it started properly, and exits accordingly.
+/// This returns only in the daemon.
+pub fn daemonize(dir: &Path, pid_file: &Path) -> Result<Startup> {
+    let (parent_end, mut child_end) = UnixStream::pair()?;
+
+    let daemon = Daemonize::new().working_directory(dir).pid_file(pid_file);
+    match daemon.execute() {
+        Outcome::Parent(Ok(_)) => {
+            drop(child_end);
+
+            // wait for the daemon to tell us how it went
+            let mut line = String::new();
+            BufReader::new(parent_end).read_line(&mut line)?;
+            let line = line.trim_end();
+
+            if let Some(pid) = line.strip_prefix("OK ") {
+                log::info!("daemon started with pid {pid}");
+                std::process::exit(0)
+            } else if let Some(err) = line.strip_prefix("ERR ") {
+                anyhow::bail!("daemon failed to start: {err}")
+            } else {
+                anyhow::bail!("daemon exited during startup")
+            }
+        }
+        Outcome::Parent(Err(err)) => Err(err).context("daemonizing"),
+        Outcome::Child(Ok(_)) => {
+            drop(parent_end);
+            Ok(Startup(child_end))
+        }
+        Outcome::Child(Err(err)) => {
+            let _ = writeln!(child_end, "ERR daemonizing: {err}");
+            std::process::exit(1)
+        }
+    }
+}
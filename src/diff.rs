@@ -0,0 +1,256 @@
+//! Compare two traces, to find performance regressions.
+//!
+//! Spans are matched by their call path (the names of the span and its
+//! ancestors). Async spans are matched by name.
+
+use std::{
+    collections::BTreeMap,
+    io::{stdout, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{cli, config::Config, event::Events, get_tef, spans::Spans, utils};
+
+/// Count and total duration of the spans with a given path.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+struct Totals {
+    count: usize,
+    total_us: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    path: String,
+    base: Totals,
+    new: Totals,
+    delta_us: f64,
+    /// `None` for spans that are not in the base trace
+    delta_pct: Option<f64>,
+    regression: bool,
+}
+
+fn load(file: String, dir: Option<&String>, config: &Config) -> Result<BTreeMap<String, Totals>> {
+    let file = get_tef::find_trace_file(file, dir, config)?;
+    log::debug!("reading trace from file {file:?}");
+    let mut events = Events::new(utils::open_trace(Path::new(&file))?);
+    let spans = Spans::from_events(&mut events)?;
+    events.report_invalid();
+
+    let mut res: BTreeMap<String, Totals> = BTreeMap::new();
+    for (i, span) in spans.spans.iter().enumerate() {
+        let t = res.entry(spans.stack(i).join(";")).or_default();
+        t.count += 1;
+        t.total_us += span.dur();
+    }
+    for span in &spans.async_spans {
+        let t = res.entry(format!("async:{}", span.name)).or_default();
+        t.count += 1;
+        t.total_us += span.dur();
+    }
+    Ok(res)
+}
+
+fn compare(
+    base: BTreeMap<String, Totals>,
+    mut new: BTreeMap<String, Totals>,
+    threshold_pct: f64,
+    threshold_abs: f64,
+) -> Vec<Change> {
+    let mut res = vec![];
+    for (path, b) in base {
+        let n = new.remove(&path).unwrap_or_default();
+        res.push((path, b, n));
+    }
+    for (path, n) in new {
+        res.push((path, Totals::default(), n));
+    }
+
+    let mut res: Vec<Change> = res
+        .into_iter()
+        .map(|(path, base, new)| {
+            let delta_us = new.total_us - base.total_us;
+            let delta_pct = (base.total_us > 0.).then(|| 100. * delta_us / base.total_us);
+            let regression =
+                delta_us >= threshold_abs && delta_pct.map_or(true, |p| p >= threshold_pct);
+            Change {
+                path,
+                base,
+                new,
+                delta_us,
+                delta_pct,
+                regression,
+            }
+        })
+        .filter(|c| c.delta_us != 0. || c.base.count != c.new.count)
+        .collect();
+
+    // regressions first, then largest changes
+    res.sort_by(|a, b| {
+        b.regression
+            .cmp(&a.regression)
+            .then(b.delta_us.abs().total_cmp(&a.delta_us.abs()))
+            .then(a.path.cmp(&b.path))
+    });
+    res
+}
+
+fn fmt_pct(c: &Change) -> String {
+    match c.delta_pct {
+        Some(p) => format!("{p:+.1}%"),
+        None => "new".to_string(),
+    }
+}
+
+fn fmt_delta(us: f64) -> String {
+    let sign = if us < 0. { "-" } else { "+" };
+    format!("{sign}{}", utils::fmt_dur(us.abs()))
+}
+
+fn cells(c: &Change) -> Vec<String> {
+    vec![
+        if c.regression { "!" } else { "" }.to_string(),
+        c.path.clone(),
+        format!("{} → {}", c.base.count, c.new.count),
+        utils::fmt_dur(c.base.total_us),
+        utils::fmt_dur(c.new.total_us),
+        fmt_delta(c.delta_us),
+        if c.new.count == 0 {
+            "removed".to_string()
+        } else {
+            fmt_pct(c)
+        },
+    ]
+}
+
+const HEADER: [&str; 7] = ["", "path", "count", "base", "new", "delta", "%"];
+
+fn write_table(changes: &[Change], w: &mut impl Write) -> Result<()> {
+    let mut lines: Vec<Vec<String>> = vec![HEADER.iter().map(|s| s.to_string()).collect()];
+    lines.extend(changes.iter().map(cells));
+
+    utils::write_columns(&lines, 2, w)
+}
+
+fn write_markdown(changes: &[Change], n_regressions: usize, w: &mut impl Write) -> Result<()> {
+    if n_regressions > 0 {
+        writeln!(w, "**{n_regressions} regression(s)**\n")?;
+    } else {
+        writeln!(w, "No regression.\n")?;
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+    writeln!(w, "| | path | count | base | new | delta | % |")?;
+    writeln!(w, "|---|---|---:|---:|---:|---:|---:|")?;
+    for c in changes {
+        let mut cells = cells(c);
+        if c.regression {
+            cells[0] = "⚠️".to_string();
+        }
+        // `|` would end the cell
+        cells[1] = format!("`{}`", cells[1].replace('|', "\\|"));
+        writeln!(w, "| {} |", cells.join(" | "))?;
+    }
+    Ok(())
+}
+
+pub fn run(cli: cli::Diff, config: &Config) -> Result<()> {
+    let base = load(cli.base, cli.dir.as_ref(), config)?;
+    let new = load(cli.new, cli.dir.as_ref(), config)?;
+    let threshold_abs = utils::parse_dur(&cli.threshold_abs)?;
+
+    let mut changes = compare(base, new, cli.threshold_pct, threshold_abs);
+    let n_regressions = changes.iter().filter(|c| c.regression).count();
+    if let Some(limit) = cli.limit {
+        changes.truncate(limit.max(n_regressions));
+    }
+
+    let mut out = BufWriter::new(stdout().lock());
+    match cli.format {
+        cli::DiffFormat::Table => write_table(&changes, &mut out)?,
+        cli::DiffFormat::Markdown => write_markdown(&changes, n_regressions, &mut out)?,
+        cli::DiffFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &changes)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+
+    if n_regressions > 0 {
+        anyhow::bail!(
+            "{n_regressions} span(s) got slower by at least {}% and {}",
+            cli.threshold_pct,
+            utils::fmt_dur(threshold_abs)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_trace(name: &str, trace: &str) -> BTreeMap<String, Totals> {
+        let dir = std::env::temp_dir().join(format!("tldrs-test-diff-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{name}.jsonl"));
+        std::fs::write(&path, trace).unwrap();
+        let res = load(path.display().to_string(), None, &Config::default()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        res
+    }
+
+    #[test]
+    fn regressions() {
+        let base = load_trace(
+            "base",
+            r#"{"ph":"X","name":"main","pid":1,"tid":1,"ts":0,"dur":1000}
+{"ph":"X","name":"parse","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"render","pid":1,"tid":1,"ts":100,"dur":800}
+{"ph":"X","name":"old","pid":1,"tid":1,"ts":900,"dur":10}
+{"ph":"X","name":"same","pid":1,"tid":2,"ts":0,"dur":10}
+"#,
+        );
+        assert_eq!(base["main;parse"].total_us, 100.);
+        let new = load_trace(
+            "new",
+            r#"{"ph":"X","name":"main","pid":1,"tid":1,"ts":0,"dur":1300}
+{"ph":"X","name":"parse","pid":1,"tid":1,"ts":0,"dur":400}
+{"ph":"X","name":"render","pid":1,"tid":1,"ts":400,"dur":790}
+{"ph":"X","name":"parse","pid":1,"tid":1,"ts":1200,"dur":50}
+{"ph":"X","name":"same","pid":1,"tid":2,"ts":0,"dur":10}
+{"ph":"b","name":"io","cat":"a","id":1,"pid":1,"tid":2,"ts":0}
+{"ph":"e","name":"io","cat":"a","id":1,"pid":1,"tid":2,"ts":2000}
+"#,
+        );
+
+        // +10% and +100µs
+        let changes = compare(base, new, 10., 100.);
+        let summary: Vec<(&str, bool, Option<f64>)> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.regression, c.delta_pct))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("async:io", true, None),
+                ("main;parse", true, Some(350.)),
+                ("main", true, Some(30.)),
+                ("main;old", false, Some(-100.)),
+                ("main;render", false, Some(-1.25)),
+            ]
+        );
+        assert_eq!((changes[1].base.count, changes[1].new.count), (1, 2));
+        assert_eq!(cells(&changes[3])[6], "removed");
+        assert_eq!(cells(&changes[1])[5], "+350.0 µs");
+
+        let mut out = vec![];
+        write_markdown(&changes, 3, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("**3 regression(s)**\n"));
+        assert!(out.contains("| ⚠️ | `main;parse` | 1 → 2 |"));
+    }
+}
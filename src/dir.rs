@@ -1,20 +1,18 @@
 use anyhow::Result;
 
-use crate::{cli, utils};
+use crate::{cli, config::Config};
 
-pub fn run(cli: cli::Dir) -> Result<()> {
-    let dir = match cli.dir {
-        Some(d) => d,
-        None => {
-            let xdg = xdg::BaseDirectories::with_prefix(utils::XDG_PREFIX)?;
-            xdg.get_data_home()
-                .to_str()
-                .ok_or_else(|| anyhow::anyhow!("Path is not printable"))?
-                .to_owned()
-        }
+pub fn run(cli: cli::Dir, config: &Config) -> Result<()> {
+    let path = if cli.socket {
+        config.socket_path(None::<&str>)?
+    } else {
+        config.data_dir(cli.dir.as_ref())?
     };
 
-    println!("{}", dir);
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Path is not printable"))?;
+    println!("{}", path);
 
     Ok(())
 }
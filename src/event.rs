@@ -0,0 +1,145 @@
+//! Typed view of TEF events, as stored in the `.jsonl` files.
+
+use std::io::BufRead;
+
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+/// A single TEF event.
+///
+/// Fields we don't interpret are kept in `other`, so that an event
+/// can be written back without losing information.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Event {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cat: String,
+    pub ph: char,
+    /// Timestamp, in microseconds
+    #[serde(default)]
+    pub ts: f64,
+    /// Duration (for `X` events), in microseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    #[serde(default, deserialize_with = "de_pid")]
+    pub pid: i64,
+    #[serde(default, deserialize_with = "de_pid")]
+    pub tid: i64,
+    /// Id for async and flow events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<Map<String, Value>>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// FNV-1a hash, stable across runs and platforms.
+pub fn hash_str(s: &str) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in s.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+/// pid and tid are usually numbers, but some producers use strings.
+fn de_pid<'de, D: Deserializer<'de>>(d: D) -> Result<i64, D::Error> {
+    Ok(match Value::deserialize(d)? {
+        Value::Number(n) => n
+            .as_i64()
+            .or_else(|| n.as_f64().map(|f| f as i64))
+            .unwrap_or(0),
+        Value::String(s) => s
+            .parse()
+            .unwrap_or_else(|_| (hash_str(&s) & 0x7fff_ffff) as i64),
+        _ => 0,
+    })
+}
+
+impl Event {
+    /// Parse a line from a `.jsonl` trace.
+    pub fn parse(line: &str) -> Result<Self> {
+        Ok(serde_json::from_str(line)?)
+    }
+
+    /// End timestamp for `X` events, `ts` otherwise.
+    pub fn end(&self) -> f64 {
+        self.ts + self.dur.unwrap_or(0.)
+    }
+
+    /// The `id` of async and flow events, as a string.
+    pub fn id_str(&self) -> Option<String> {
+        match self.id.as_ref()? {
+            Value::String(s) => Some(s.clone()),
+            v => Some(v.to_string()),
+        }
+    }
+
+    /// Argument `name` as a string, for metadata events like `process_name`.
+    pub fn arg_str(&self, name: &str) -> Option<&str> {
+        self.args.as_ref()?.get(name)?.as_str()
+    }
+}
+
+/// Iterate over the events of a `.jsonl` trace.
+///
+/// Lines that are not valid events are counted and skipped.
+pub struct Events<R> {
+    reader: R,
+    line: String,
+    /// Number of lines that could not be parsed
+    pub n_invalid: usize,
+}
+
+impl<R: BufRead> Events<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: String::new(),
+            n_invalid: 0,
+        }
+    }
+
+    /// Read the next event, along with its raw JSON line.
+    pub fn next_with_raw(&mut self) -> Option<Result<(Event, &str)>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Err(e) => return Some(Err(e.into())),
+                Ok(0) => return None,
+                Ok(_) => (),
+            }
+
+            let line = self.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match Event::parse(line) {
+                Ok(ev) => return Some(Ok((ev, self.line.trim()))),
+                Err(err) => {
+                    log::debug!("invalid event {line:?}: {err}");
+                    self.n_invalid += 1;
+                }
+            }
+        }
+    }
+
+    /// Warn about invalid lines, if any.
+    pub fn report_invalid(&self) {
+        if self.n_invalid > 0 {
+            log::warn!("Skipped {} invalid events.", self.n_invalid);
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Events<R> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_raw().map(|r| r.map(|(ev, _)| ev))
+    }
+}
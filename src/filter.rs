@@ -0,0 +1,735 @@
+//! Filter expressions over events, for `tldrs query` and `get-tef --filter`.
+//!
+//! ```text
+//! name ~ "compile.*" && dur > 10ms && pid == 1234
+//! cat == "gc" || args.reason == "alloc"
+//! !(ph == "C") && t_end >= 2s && t <= 5s
+//! ```
+//!
+//! A comparison is `<field> <op> <literal>`, with `==`, `!=`, `<`, `<=`,
+//! `>`, `>=`, and `~`/`!~` for regular expressions (matching anywhere in the
+//! value, use `^…$` to anchor them). A field on its own is true if it is
+//! present and not `false`, `0`, `""` or `null`. Comparisons combine with
+//! `&&`, `||`, `!` and parentheses.
+//!
+//! Fields are the event's keys (`name`, `cat`, `ph`, `ts`, `dur`, `pid`, …),
+//! `args.<key>` (which can be nested: `args.a.b`), `end` (`ts + dur`), and
+//! `t`/`t_end`, the start and end of the event relative to the start of the
+//! trace. Numbers can have a duration unit (`10ms`), and are in µs otherwise.
+//!
+//! `E` events have the same fate as their `B` event. Note that `dur` is only
+//! known for complete (`X`) events.
+
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::HashMap,
+    io::{self, BufRead, Read},
+};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{event::Event, event::Events, index, utils};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Name,
+    Cat,
+    Ph,
+    Ts,
+    Dur,
+    End,
+    Pid,
+    Tid,
+    Id,
+    /// `ts` relative to the start of the trace
+    T,
+    /// `end` relative to the start of the trace
+    TEnd,
+    Args(Vec<String>),
+    /// Any other key of the event
+    Other(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+enum Literal {
+    Num(f64),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp {
+        field: Field,
+        op: Op,
+        lit: Literal,
+        /// Compiled `lit`, for `~` and `!~`
+        re: Option<Regex>,
+    },
+    Present(Field),
+}
+
+/// A value read from an event.
+enum Val<'a> {
+    Num(f64),
+    Str(Cow<'a, str>),
+    Bool(bool),
+}
+
+impl Val<'_> {
+    fn from_json(v: &Value) -> Option<Val<'_>> {
+        Some(match v {
+            Value::Null => return None,
+            Value::Bool(b) => Val::Bool(*b),
+            Value::Number(n) => Val::Num(n.as_f64()?),
+            Value::String(s) => Val::Str(Cow::Borrowed(s)),
+            v => Val::Str(Cow::Owned(v.to_string())),
+        })
+    }
+
+    fn as_str(&self) -> Cow<'_, str> {
+        match self {
+            Val::Num(n) => Cow::Owned(n.to_string()),
+            Val::Str(s) => Cow::Borrowed(s),
+            Val::Bool(b) => Cow::Owned(b.to_string()),
+        }
+    }
+
+    fn truthy(&self) -> bool {
+        match self {
+            Val::Num(n) => *n != 0.,
+            Val::Str(s) => !s.is_empty(),
+            Val::Bool(b) => *b,
+        }
+    }
+
+    fn compare(&self, lit: &Literal) -> Option<Ordering> {
+        match (self, lit) {
+            (Val::Num(a), Literal::Num(b)) => a.partial_cmp(b),
+            (Val::Str(a), Literal::Str(b)) => Some(a.as_ref().cmp(b.as_str())),
+            (Val::Bool(a), Literal::Bool(b)) => Some(a.cmp(b)),
+            // numbers are sometimes sent as strings
+            (Val::Str(a), Literal::Num(b)) => a.parse::<f64>().ok()?.partial_cmp(b),
+            (Val::Num(a), Literal::Str(b)) => a.partial_cmp(&b.parse::<f64>().ok()?),
+            _ => None,
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        let col = pos + 1;
+        let two = s[pos..].get(..2).unwrap_or("");
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            _ if two == "&&" => Token::And,
+            _ if two == "||" => Token::Or,
+            _ if two == "==" => Token::Op(Op::Eq),
+            _ if two == "!=" => Token::Op(Op::Ne),
+            _ if two == "!~" => Token::Op(Op::NotMatch),
+            _ if two == "<=" => Token::Op(Op::Le),
+            _ if two == ">=" => Token::Op(Op::Ge),
+            '!' => Token::Not,
+            '<' => Token::Op(Op::Lt),
+            '>' => Token::Op(Op::Gt),
+            '~' => Token::Op(Op::Match),
+            '"' | '\'' => {
+                chars.next();
+                let mut lit = String::new();
+                loop {
+                    match chars.next() {
+                        None => anyhow::bail!("column {col}: unterminated string"),
+                        Some((_, q)) if q == c => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, e)) if e == c => lit.push(e),
+                            // keep other escapes as they are, for regexes
+                            Some((_, e)) => {
+                                lit.push('\\');
+                                lit.push(e);
+                            }
+                            None => anyhow::bail!("column {col}: unterminated string"),
+                        },
+                        Some((_, ch)) => lit.push(ch),
+                    }
+                }
+                tokens.push((col, Token::Str(lit)));
+                continue;
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let end = s[pos + 1..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '.'))
+                    .map_or(s.len(), |i| pos + 1 + i);
+                let word = &s[pos..end];
+                let n = match word.parse::<f64>() {
+                    Ok(n) => n,
+                    Err(_) => utils::parse_dur(word)
+                        .with_context(|| format!("column {col}: invalid number {word:?}"))?,
+                };
+                while chars.peek().is_some_and(|&(i, _)| i < end) {
+                    chars.next();
+                }
+                tokens.push((col, Token::Num(n)));
+                continue;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let end = s[pos..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                    .map_or(s.len(), |i| pos + i);
+                while chars.peek().is_some_and(|&(i, _)| i < end) {
+                    chars.next();
+                }
+                tokens.push((col, Token::Ident(s[pos..end].to_string())));
+                continue;
+            }
+            c => anyhow::bail!("column {col}: unexpected character {c:?}"),
+        };
+        let len = match token {
+            Token::LParen | Token::RParen | Token::Not => 1,
+            Token::Op(Op::Lt | Op::Gt | Op::Match) => 1,
+            _ => 2,
+        };
+        for _ in 0..len {
+            chars.next();
+        }
+        tokens.push((col, token));
+    }
+    Ok(tokens)
+}
+
+fn parse_field(name: &str) -> Result<Field> {
+    let path: Vec<String> = name.split('.').map(String::from).collect();
+    if path.iter().any(|p| p.is_empty()) {
+        anyhow::bail!("invalid field {name:?}");
+    }
+    Ok(match name {
+        "name" => Field::Name,
+        "cat" => Field::Cat,
+        "ph" => Field::Ph,
+        "ts" => Field::Ts,
+        "dur" => Field::Dur,
+        "end" => Field::End,
+        "pid" => Field::Pid,
+        "tid" => Field::Tid,
+        "id" => Field::Id,
+        "t" => Field::T,
+        "t_end" => Field::TEnd,
+        _ if path[0] == "args" && path.len() > 1 => Field::Args(path[1..].to_vec()),
+        _ => Field::Other(path),
+    })
+}
+
+/// Recursive descent parser, `||` binding less than `&&`, which binds less than `!`.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Length of the input, for errors at the end
+    len: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn col(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.len + 1, |(c, _)| *c)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        t
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut e = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            e = Expr::Or(Box::new(e), Box::new(self.and()?));
+        }
+        Ok(e)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut e = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            e = Expr::And(Box::new(e), Box::new(self.not()?));
+        }
+        Ok(e)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let col = self.col();
+        match self.next() {
+            Some(Token::LParen) => {
+                let e = self.or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(e),
+                    _ => anyhow::bail!("column {col}: unclosed parenthesis"),
+                }
+            }
+            Some(Token::Ident(name)) => {
+                let field = parse_field(&name).with_context(|| format!("column {col}"))?;
+                let Some(&Token::Op(op)) = self.peek() else {
+                    return Ok(Expr::Present(field));
+                };
+                self.next();
+                let col = self.col();
+                let lit = match self.next() {
+                    Some(Token::Num(n)) => Literal::Num(n),
+                    Some(Token::Str(s)) => Literal::Str(s),
+                    Some(Token::Ident(w)) if w == "true" => Literal::Bool(true),
+                    Some(Token::Ident(w)) if w == "false" => Literal::Bool(false),
+                    _ => anyhow::bail!("column {col}: expected a string or a number"),
+                };
+                let re = match (op, &lit) {
+                    (Op::Match | Op::NotMatch, Literal::Str(s)) => Some(
+                        Regex::new(s)
+                            .with_context(|| format!("column {col}: invalid regex {s:?}"))?,
+                    ),
+                    (Op::Match | Op::NotMatch, _) => {
+                        anyhow::bail!("column {col}: expected a string for the regex")
+                    }
+                    _ => None,
+                };
+                Ok(Expr::Cmp { field, op, lit, re })
+            }
+            Some(_) => anyhow::bail!("column {col}: expected a field or '('"),
+            None => anyhow::bail!("column {col}: unexpected end of the expression"),
+        }
+    }
+}
+
+/// A parsed filter expression.
+#[derive(Debug)]
+pub struct Filter {
+    expr: Expr,
+    /// Start of the trace, for `t` and `t_end`
+    start: f64,
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut p = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            len: s.len(),
+        };
+        let expr = p.or()?;
+        if p.pos < p.tokens.len() {
+            anyhow::bail!("column {}: expected '&&', '||' or the end", p.col());
+        }
+        Ok(Self { expr, start: 0. })
+    }
+
+    /// Whether the expression uses times relative to the start of the trace.
+    pub fn uses_start(&self) -> bool {
+        fn walk(e: &Expr) -> bool {
+            match e {
+                Expr::And(a, b) | Expr::Or(a, b) => walk(a) || walk(b),
+                Expr::Not(a) => walk(a),
+                Expr::Cmp { field, .. } | Expr::Present(field) => {
+                    matches!(field, Field::T | Field::TEnd)
+                }
+            }
+        }
+        walk(&self.expr)
+    }
+
+    pub fn set_start(&mut self, start: f64) {
+        self.start = start;
+    }
+
+    fn value<'a>(&self, ev: &'a Event, field: &Field) -> Option<Val<'a>> {
+        let str = |s: &'a str| Some(Val::Str(Cow::Borrowed(s)));
+        let path = |v: Option<&'a Value>, keys: &[String]| {
+            keys.iter()
+                .try_fold(v?, |v, k| v.get(k))
+                .and_then(Val::from_json)
+        };
+        match field {
+            Field::Name => str(&ev.name),
+            Field::Cat => str(&ev.cat),
+            Field::Ph => Some(Val::Str(Cow::Owned(ev.ph.to_string()))),
+            Field::Ts => Some(Val::Num(ev.ts)),
+            Field::Dur => ev.dur.map(Val::Num),
+            Field::End => Some(Val::Num(ev.end())),
+            Field::Pid => Some(Val::Num(ev.pid as f64)),
+            Field::Tid => Some(Val::Num(ev.tid as f64)),
+            Field::Id => ev.id.as_ref().and_then(Val::from_json),
+            Field::T => Some(Val::Num(ev.ts - self.start)),
+            Field::TEnd => Some(Val::Num(ev.end() - self.start)),
+            Field::Args(keys) => {
+                let args = ev.args.as_ref()?;
+                path(args.get(&keys[0]), &keys[1..])
+            }
+            Field::Other(keys) => path(ev.other.get(&keys[0]), &keys[1..]),
+        }
+    }
+
+    fn eval(&self, e: &Expr, ev: &Event) -> bool {
+        match e {
+            Expr::And(a, b) => self.eval(a, ev) && self.eval(b, ev),
+            Expr::Or(a, b) => self.eval(a, ev) || self.eval(b, ev),
+            Expr::Not(a) => !self.eval(a, ev),
+            Expr::Present(field) => self.value(ev, field).is_some_and(|v| v.truthy()),
+            Expr::Cmp { field, op, lit, re } => {
+                let Some(v) = self.value(ev, field) else {
+                    // a missing field is different from anything
+                    return matches!(op, Op::Ne | Op::NotMatch);
+                };
+                if let Some(re) = re {
+                    return re.is_match(&v.as_str()) == (*op == Op::Match);
+                }
+                let ord = v.compare(lit);
+                match op {
+                    Op::Eq => ord == Some(Ordering::Equal),
+                    Op::Ne => ord != Some(Ordering::Equal),
+                    Op::Lt => ord == Some(Ordering::Less),
+                    Op::Le => matches!(ord, Some(Ordering::Less | Ordering::Equal)),
+                    Op::Gt => ord == Some(Ordering::Greater),
+                    Op::Ge => matches!(ord, Some(Ordering::Greater | Ordering::Equal)),
+                    Op::Match | Op::NotMatch => unreachable!("regex is compiled when parsing"),
+                }
+            }
+        }
+    }
+
+    pub fn matches(&self, ev: &Event) -> bool {
+        self.eval(&self.expr, ev)
+    }
+
+    /// The parts of a trace that can have matching events, from the
+    /// comparisons on time, pid, tid and name that all of them must pass.
+    pub fn selection(&self) -> index::Selection {
+        fn walk(e: &Expr, start: f64, sel: &mut index::Selection) {
+            let (field, op, lit) = match e {
+                Expr::And(a, b) => {
+                    walk(a, start, sel);
+                    walk(b, start, sel);
+                    return;
+                }
+                Expr::Cmp { field, op, lit, .. } => (field, *op, lit),
+                _ => return,
+            };
+            match (field, lit) {
+                (Field::Name, Literal::Str(s)) if op == Op::Eq && sel.names.is_empty() => {
+                    sel.names = vec![s.clone()];
+                }
+                (Field::Pid | Field::Tid, Literal::Num(n)) if op == Op::Eq && n.fract() == 0. => {
+                    let ids = match field {
+                        Field::Pid => &mut sel.pids,
+                        _ => &mut sel.tids,
+                    };
+                    if ids.is_empty() {
+                        *ids = vec![*n as i64];
+                    }
+                }
+                // `ts <= end`: a bound on either is a bound on the event
+                (Field::Ts | Field::End | Field::T | Field::TEnd, Literal::Num(n)) => {
+                    let t = match field {
+                        Field::T | Field::TEnd => n + start,
+                        _ => *n,
+                    };
+                    if matches!(op, Op::Eq | Op::Ge | Op::Gt) {
+                        sel.from = sel.from.max(t);
+                    }
+                    if matches!(op, Op::Eq | Op::Le | Op::Lt) {
+                        sel.to = sel.to.min(t);
+                    }
+                }
+                _ => (),
+            }
+        }
+        let mut sel = index::Selection::all();
+        walk(&self.expr, self.start, &mut sel);
+        sel
+    }
+}
+
+/// Start of the trace: the smallest timestamp of non-metadata events.
+pub fn trace_start(reader: impl BufRead) -> Result<f64> {
+    let mut start = f64::MAX;
+    let mut events = Events::new(reader);
+    for ev in &mut events {
+        let ev = ev?;
+        if ev.ph != 'M' {
+            start = start.min(ev.ts);
+        }
+    }
+    Ok(if start == f64::MAX { 0. } else { start })
+}
+
+/// Lines of a `.jsonl` trace that match a filter, as a reader.
+pub struct Filtered<R> {
+    events: Events<R>,
+    filter: Filter,
+    /// Let metadata (`M`) events through, to keep process and thread names
+    keep_metadata: bool,
+    /// Whether the open `B` events of each thread matched
+    open: HashMap<(i64, i64), Vec<bool>>,
+    buf: Vec<u8>,
+    pos: usize,
+    /// Number of matching events so far
+    pub n_matched: usize,
+}
+
+impl<R: BufRead> Filtered<R> {
+    pub fn new(reader: R, filter: Filter, keep_metadata: bool) -> Self {
+        Self {
+            events: Events::new(reader),
+            filter,
+            keep_metadata,
+            open: HashMap::new(),
+            buf: vec![],
+            pos: 0,
+            n_matched: 0,
+        }
+    }
+}
+
+/// Whether an event is kept by the filter, given the open `B` events.
+fn keep(filter: &Filter, open: &mut HashMap<(i64, i64), Vec<bool>>, ev: &Event) -> bool {
+    match ev.ph {
+        'B' => {
+            let m = filter.matches(ev);
+            open.entry((ev.pid, ev.tid)).or_default().push(m);
+            m
+        }
+        'E' => match open.get_mut(&(ev.pid, ev.tid)).and_then(|s| s.pop()) {
+            Some(m) => m,
+            None => filter.matches(ev),
+        },
+        _ => filter.matches(ev),
+    }
+}
+
+/// Parse `expr`. `start` is only called if the expression needs the start
+/// of the trace.
+pub fn compile(expr: &str, start: impl Fn() -> Result<f64>) -> Result<Filter> {
+    let mut filter = Filter::parse(expr).with_context(|| format!("invalid filter {expr:?}"))?;
+    if filter.uses_start() {
+        filter.set_start(start()?);
+    }
+    Ok(filter)
+}
+
+impl<R: BufRead> BufRead for Filtered<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos >= self.buf.len() {
+            let (ev, raw) = match self.events.next_with_raw() {
+                None => {
+                    self.events.report_invalid();
+                    // only once
+                    self.events.n_invalid = 0;
+                    return Ok(&[]);
+                }
+                Some(Err(err)) => return Err(io::Error::other(err)),
+                Some(Ok(x)) => x,
+            };
+            let matched = keep(&self.filter, &mut self.open, &ev);
+            self.n_matched += matched as usize;
+            if matched || ev.ph == 'M' && self.keep_metadata {
+                self.buf.clear();
+                self.buf.extend_from_slice(raw.as_bytes());
+                self.buf.push(b'\n');
+                self.pos = 0;
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl<R: BufRead> Read for Filtered<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ev(json: &str) -> Event {
+        Event::parse(json).unwrap()
+    }
+
+    fn matches(expr: &str, json: &str) -> bool {
+        Filter::parse(expr).unwrap().matches(&ev(json))
+    }
+
+    const X: &str = r#"{"ph":"X","name":"compile","cat":"build","ts":100,"dur":6,"pid":1,"tid":2,"args":{"file":"a.rs","n":3}}"#;
+
+    #[test]
+    fn comparisons() {
+        assert!(matches(r#"name == "compile""#, X));
+        assert!(!matches(r#"name != "compile""#, X));
+        assert!(matches("pid == 1", X));
+        assert!(matches("tid > 1", X));
+        assert!(matches("end == 106", X));
+        assert!(matches("args.n >= 3", X));
+        assert!(matches(r#"name ~ "^comp""#, X));
+        assert!(matches(r#"name !~ "link""#, X));
+        assert!(matches("args.file", X));
+        assert!(!matches("args.missing", X));
+        // a missing field is different from anything
+        assert!(!matches("args.missing == 1", X));
+        assert!(matches("args.missing != 1", X));
+    }
+
+    #[test]
+    fn boolean_operators() {
+        assert!(matches(r#"pid == 1 && cat == "build""#, X));
+        assert!(!matches(r#"pid == 1 && cat == "gc""#, X));
+        assert!(matches(r#"pid == 2 || cat == "build""#, X));
+        assert!(!matches(r#"pid == 2 || cat == "gc""#, X));
+        assert!(matches("!(pid == 2)", X));
+        assert!(matches("!!(pid == 1)", X));
+    }
+
+    #[test]
+    fn precedence() {
+        // `a || b && c` is `a || (b && c)`
+        assert!(matches("pid == 1 || tid == 3 && pid == 3", X));
+        assert!(!matches("(pid == 1 || tid == 3) && pid == 3", X));
+        // `!` binds tighter than `&&`
+        assert!(!matches("!pid == 1 && tid == 2", X));
+        assert!(matches("!(pid == 1 && tid == 3)", X));
+    }
+
+    #[test]
+    fn durations() {
+        assert!(matches("dur > 5us", X));
+        assert!(!matches("dur > 6us", X));
+        assert!(matches("dur < 0.1ms", X));
+        assert!(matches("ts < 1s && ts >= 100", X));
+        let big = r#"{"ph":"X","name":"a","ts":0,"dur":1500000,"pid":1,"tid":1}"#;
+        assert!(matches("dur == 1.5s", big));
+        assert!(matches("dur > 1s", big));
+        assert!(Filter::parse("dur > 5parsecs").is_err());
+    }
+
+    #[test]
+    fn strings() {
+        assert!(matches("name == 'compile'", X));
+        let quoted = r#"{"ph":"i","name":"say \"hi\" 'there'","ts":0,"pid":1,"tid":1}"#;
+        assert!(matches(r#"name == "say \"hi\" 'there'""#, quoted));
+        assert!(matches(r#"name == 'say "hi" \'there\''"#, quoted));
+        // other escapes are kept, for regexes
+        let dotted = r#"{"ph":"i","name":"a.b","ts":0,"pid":1,"tid":1}"#;
+        let other = r#"{"ph":"i","name":"axb","ts":0,"pid":1,"tid":1}"#;
+        assert!(matches(r#"name ~ "^a\.b$""#, dotted));
+        assert!(!matches(r#"name ~ "^a\.b$""#, other));
+        // numbers sent as strings
+        assert!(matches(r#"args.n == "3""#, X));
+
+        let err = Filter::parse(r#"name == "compile"#).unwrap_err();
+        assert!(
+            err.to_string().contains("column 9: unterminated string"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn relative_times() {
+        let mut f = Filter::parse("t >= 50 && t_end <= 56").unwrap();
+        assert!(f.uses_start());
+        f.set_start(50.);
+        assert!(f.matches(&ev(X)));
+        f.set_start(51.);
+        assert!(!f.matches(&ev(X)));
+        assert!(!Filter::parse("ts > 1 || name").unwrap().uses_start());
+    }
+
+    #[test]
+    fn errors() {
+        for (expr, err) in [
+            ("(pid == 1", "column 1: unclosed parenthesis"),
+            ("pid ==", "column 7: expected a string or a number"),
+            ("pid == 1 &&", "column 12: unexpected end of the expression"),
+            ("pid == 1 pid", "column 10: expected '&&'"),
+            ("name ~ 1", "column 8: expected a string for the regex"),
+            ("pid == 1 # 2", "column 10: unexpected character"),
+        ] {
+            let e = format!("{:#}", Filter::parse(expr).unwrap_err());
+            assert!(e.contains(err), "{expr:?}: {e}");
+        }
+    }
+
+    #[test]
+    fn end_follows_begin() {
+        let f = Filter::parse(r#"name == "outer" || name == "x""#).unwrap();
+        let mut open = HashMap::new();
+        let kept: Vec<bool> = [
+            r#"{"ph":"B","name":"outer","ts":0,"pid":1,"tid":1}"#,
+            r#"{"ph":"B","name":"inner","ts":1,"pid":1,"tid":1}"#,
+            // another thread
+            r#"{"ph":"B","name":"x","ts":1,"pid":1,"tid":2}"#,
+            // named like a matching event, but ends `inner`
+            r#"{"ph":"E","name":"x","ts":2,"pid":1,"tid":1}"#,
+            r#"{"ph":"E","ts":3,"pid":1,"tid":1}"#,
+            r#"{"ph":"E","ts":3,"pid":1,"tid":2}"#,
+            // without a `B`, the `E` is matched on its own
+            r#"{"ph":"E","name":"x","ts":4,"pid":1,"tid":1}"#,
+        ]
+        .iter()
+        .map(|json| keep(&f, &mut open, &ev(json)))
+        .collect();
+        assert_eq!(kept, [true, false, true, false, true, true, true]);
+    }
+}
@@ -0,0 +1,465 @@
+//! Conversion to the Firefox Profiler's processed profile format.
+//!
+//! Each thread gets its call tree (as samples weighted by self time),
+//! and markers for spans and instants. Counter events become counter tracks.
+//! See `src/types/profile.js` in the Firefox Profiler repository.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, Write},
+};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{
+    event::{Event, Events},
+    spans::Spans,
+};
+
+/// Version of the processed format we produce. The profiler upgrades it on load.
+const PROCESSED_PROFILE_VERSION: u32 = 48;
+
+/// Marker phases
+const PHASE_INSTANT: u32 = 0;
+const PHASE_INTERVAL: u32 = 1;
+
+const COLORS: &[&str] = &[
+    "blue",
+    "green",
+    "orange",
+    "purple",
+    "yellow",
+    "red",
+    "lightblue",
+    "brown",
+];
+
+/// TEF microseconds to profiler milliseconds.
+fn ms(ts: f64) -> f64 {
+    ts / 1000.
+}
+
+/// Profile categories, one per TEF category. Index 0 is for events without one.
+#[derive(Default)]
+struct Categories {
+    names: Vec<String>,
+    idx: HashMap<String, usize>,
+}
+
+impl Categories {
+    fn get(&mut self, cat: &str) -> usize {
+        // only the first of comma separated categories is used
+        let cat = cat.split(',').next().unwrap_or("");
+        let cat = if cat.is_empty() { "Other" } else { cat };
+        if let Some(&i) = self.idx.get(cat) {
+            return i;
+        }
+        self.names.push(cat.to_string());
+        self.idx.insert(cat.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+
+    fn to_json(&self) -> Value {
+        let cats: Vec<Value> = self
+            .names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let color = if name == "Other" {
+                    "grey"
+                } else {
+                    COLORS[i % COLORS.len()]
+                };
+                json!({"name": name, "color": color, "subcategories": ["Other"]})
+            })
+            .collect();
+        Value::Array(cats)
+    }
+}
+
+/// Tables of a single thread.
+#[derive(Default)]
+struct Thread {
+    name: String,
+    pid: i64,
+    tid: i64,
+    strings: Vec<String>,
+    string_idx: HashMap<String, usize>,
+
+    funcs: HashMap<(usize, usize), usize>,
+    func_name: Vec<usize>,
+    /// Frames and functions are one-to-one
+    frame_category: Vec<usize>,
+    stacks: HashMap<(Option<usize>, usize), usize>,
+    stack_frame: Vec<usize>,
+    stack_prefix: Vec<Option<usize>>,
+    stack_category: Vec<usize>,
+
+    sample_stack: Vec<usize>,
+    sample_time: Vec<f64>,
+    sample_weight: Vec<f64>,
+
+    marker_name: Vec<usize>,
+    marker_start: Vec<f64>,
+    marker_end: Vec<Option<f64>>,
+    marker_phase: Vec<u32>,
+    marker_category: Vec<usize>,
+    marker_data: Vec<Value>,
+}
+
+impl Thread {
+    fn string(&mut self, s: &str) -> usize {
+        if let Some(&i) = self.string_idx.get(s) {
+            return i;
+        }
+        self.strings.push(s.to_string());
+        self.string_idx
+            .insert(s.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    /// Frame (and function) for `name` in `category`.
+    fn frame(&mut self, name: &str, category: usize) -> usize {
+        let name = self.string(name);
+        let n = self.func_name.len();
+        let idx = *self.funcs.entry((name, category)).or_insert(n);
+        if idx == n {
+            self.func_name.push(name);
+            self.frame_category.push(category);
+        }
+        idx
+    }
+
+    fn stack(&mut self, prefix: Option<usize>, frame: usize) -> usize {
+        let n = self.stack_frame.len();
+        let idx = *self.stacks.entry((prefix, frame)).or_insert(n);
+        if idx == n {
+            self.stack_frame.push(frame);
+            self.stack_prefix.push(prefix);
+            self.stack_category.push(self.frame_category[frame]);
+        }
+        idx
+    }
+
+    fn marker(
+        &mut self,
+        ev_name: &str,
+        category: usize,
+        cat: &str,
+        start: f64,
+        end: Option<f64>,
+        args: Option<&serde_json::Map<String, Value>>,
+    ) {
+        let name = self.string(ev_name);
+        self.marker_name.push(name);
+        self.marker_start.push(ms(start));
+        self.marker_end.push(end.map(ms));
+        self.marker_phase.push(if end.is_some() {
+            PHASE_INTERVAL
+        } else {
+            PHASE_INSTANT
+        });
+        self.marker_category.push(category);
+        let args = args.map(|a| Value::Object(a.clone()).to_string());
+        self.marker_data
+            .push(json!({"type": "TraceEvent", "cat": cat, "args": args}));
+    }
+
+    fn to_json(&self) -> Value {
+        let n_funcs = self.func_name.len();
+        let nulls = |n: usize| vec![Value::Null; n];
+        json!({
+            "processType": "default",
+            "processStartupTime": 0,
+            "processShutdownTime": null,
+            "registerTime": 0,
+            "unregisterTime": null,
+            "pausedRanges": [],
+            "name": self.name,
+            "isMainThread": self.pid == self.tid,
+            "pid": self.pid.to_string(),
+            "tid": self.tid,
+            "samples": {
+                "weightType": "tracing-ms",
+                "weight": self.sample_weight,
+                "stack": self.sample_stack,
+                "time": self.sample_time,
+                "length": self.sample_stack.len(),
+            },
+            "markers": {
+                "data": self.marker_data,
+                "name": self.marker_name,
+                "startTime": self.marker_start,
+                "endTime": self.marker_end,
+                "phase": self.marker_phase,
+                "category": self.marker_category,
+                "length": self.marker_name.len(),
+            },
+            "stackTable": {
+                "frame": self.stack_frame,
+                "prefix": self.stack_prefix,
+                "category": self.stack_category,
+                "subcategory": vec![0; self.stack_frame.len()],
+                "length": self.stack_frame.len(),
+            },
+            "frameTable": {
+                "address": vec![-1; n_funcs],
+                "inlineDepth": vec![0; n_funcs],
+                "category": self.frame_category,
+                "subcategory": vec![0; n_funcs],
+                "func": (0..n_funcs).collect::<Vec<_>>(),
+                "nativeSymbol": nulls(n_funcs),
+                "innerWindowID": vec![0; n_funcs],
+                "implementation": nulls(n_funcs),
+                "line": nulls(n_funcs),
+                "column": nulls(n_funcs),
+                "length": n_funcs,
+            },
+            "funcTable": {
+                "isJS": vec![false; n_funcs],
+                "relevantForJS": vec![false; n_funcs],
+                "name": self.func_name,
+                "resource": vec![-1; n_funcs],
+                "fileName": nulls(n_funcs),
+                "lineNumber": nulls(n_funcs),
+                "columnNumber": nulls(n_funcs),
+                "length": n_funcs,
+            },
+            "resourceTable": {"lib": [], "name": [], "host": [], "type": [], "length": 0},
+            "nativeSymbols": {"libIndex": [], "address": [], "name": [], "functionSize": [], "length": 0},
+            "stringArray": self.strings,
+        })
+    }
+}
+
+fn get_thread<'a>(
+    threads: &'a mut BTreeMap<(i64, i64), Thread>,
+    spans: &Spans,
+    pid: i64,
+    tid: i64,
+) -> &'a mut Thread {
+    threads.entry((pid, tid)).or_insert_with(|| Thread {
+        name: spans.thread_label(pid, tid),
+        pid,
+        tid,
+        ..Default::default()
+    })
+}
+
+/// A counter series: `(pid, name, id)`, with its `(time, value)` samples.
+type Counters = BTreeMap<(i64, String, String), Vec<(f64, f64)>>;
+
+/// Reads jsonl from `reader` and writes a Firefox Profiler profile into `writer`.
+pub fn emit_firefox(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()> {
+    let mut events = Events::new(reader);
+    // instants and counters are kept aside, everything else makes spans
+    let mut evs: Vec<Event> = vec![];
+    let spans = Spans::from_events((&mut events).filter_map(|ev| match ev {
+        Ok(ev) if matches!(ev.ph, 'i' | 'I' | 'n' | 'C') => {
+            evs.push(ev);
+            None
+        }
+        ev => Some(ev),
+    }))?;
+    events.report_invalid();
+
+    let mut categories = Categories::default();
+    categories.get("Other");
+    let mut threads: BTreeMap<(i64, i64), Thread> = BTreeMap::new();
+
+    // call tree and interval markers for spans
+    let mut span_stack: Vec<usize> = Vec::with_capacity(spans.spans.len());
+    for span in &spans.spans {
+        let category = categories.get(&span.cat);
+        let th = get_thread(&mut threads, &spans, span.pid, span.tid);
+        let frame = th.frame(&span.name, category);
+        let prefix = span.parent.map(|p| span_stack[p]);
+        let stack = th.stack(prefix, frame);
+        span_stack.push(stack);
+
+        th.sample_stack.push(stack);
+        th.sample_time.push(ms(span.start));
+        th.sample_weight.push(ms(span.self_dur.max(0.)));
+        th.marker(
+            &span.name,
+            category,
+            &span.cat,
+            span.start,
+            Some(span.end),
+            span.args.as_ref(),
+        );
+    }
+
+    for span in &spans.async_spans {
+        let category = categories.get(&span.cat);
+        get_thread(&mut threads, &spans, span.pid, span.tid).marker(
+            &span.name,
+            category,
+            &span.cat,
+            span.start,
+            Some(span.end),
+            span.args.as_ref(),
+        );
+    }
+
+    // instants and counters
+    let mut counters: Counters = BTreeMap::new();
+    for ev in &evs {
+        match ev.ph {
+            'i' | 'I' | 'n' => {
+                let category = categories.get(&ev.cat);
+                get_thread(&mut threads, &spans, ev.pid, ev.tid).marker(
+                    &ev.name,
+                    category,
+                    &ev.cat,
+                    ev.ts,
+                    None,
+                    ev.args.as_ref(),
+                );
+            }
+            'C' => {
+                let Some(args) = &ev.args else { continue };
+                for (k, v) in args {
+                    let Some(value) = v.as_f64() else { continue };
+                    let name = if args.len() == 1 {
+                        ev.name.clone()
+                    } else {
+                        format!("{} {k}", ev.name)
+                    };
+                    counters
+                        .entry((ev.pid, name, ev.id_str().unwrap_or_default()))
+                        .or_default()
+                        .push((ev.ts, value));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // counters belong to a thread of their process
+    for (pid, _, _) in counters.keys() {
+        if !threads.keys().any(|(p, _)| p == pid) {
+            get_thread(&mut threads, &spans, *pid, *pid);
+        }
+    }
+
+    let thread_keys: Vec<(i64, i64)> = threads.keys().copied().collect();
+    let mut json_counters = vec![];
+    for ((pid, mut name, id), mut samples) in counters {
+        if !id.is_empty() {
+            name = format!("{name} id: {id}");
+        }
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let main_thread = thread_keys.iter().position(|(p, _)| *p == pid).unwrap_or(0);
+
+        // the profiler expects the change since the previous sample
+        let mut prev = 0.;
+        let mut counts = vec![];
+        for (_, v) in &samples {
+            counts.push(v - prev);
+            prev = *v;
+        }
+        let category = categories.get("Other");
+        json_counters.push(json!({
+            "name": name,
+            "category": categories.names[category],
+            "description": name,
+            "pid": pid.to_string(),
+            "mainThreadIndex": main_thread,
+            "sampleGroups": [{
+                "id": 0,
+                "samples": {
+                    "time": samples.iter().map(|(t, _)| ms(*t)).collect::<Vec<_>>(),
+                    "count": counts,
+                    "length": samples.len(),
+                },
+            }],
+        }));
+    }
+
+    let profile = json!({
+        "meta": {
+            "interval": 1,
+            "startTime": 0,
+            "processType": 0,
+            "product": "tldrs",
+            "importedFrom": "tldrs",
+            "stackwalk": 0,
+            "version": 27,
+            "preprocessedProfileVersion": PROCESSED_PROFILE_VERSION,
+            "symbolicated": true,
+            "categories": categories.to_json(),
+            "markerSchema": [{
+                "name": "TraceEvent",
+                "display": ["marker-chart", "marker-table", "timeline-overview"],
+                "data": [
+                    {"key": "cat", "label": "Category", "format": "string", "searchable": true},
+                    {"key": "args", "label": "Arguments", "format": "string"},
+                ],
+            }],
+        },
+        "libs": [],
+        "pages": [],
+        "counters": json_counters,
+        "threads": threads.values().map(|t| t.to_json()).collect::<Vec<_>>(),
+    });
+
+    serde_json::to_writer(&mut *writer, &profile)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_markers_and_counters() {
+        let trace = r#"{"ph":"X","name":"main","cat":"app","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"load","cat":"io","pid":1,"tid":1,"ts":10,"dur":30}
+{"ph":"b","name":"req","cat":"net","id":7,"pid":1,"tid":1,"ts":20}
+{"ph":"i","name":"tick","pid":1,"tid":1,"ts":50,"s":"t"}
+{"ph":"e","name":"req","cat":"net","id":7,"pid":1,"tid":2,"ts":80}
+{"ph":"C","name":"mem","id":1,"pid":1,"tid":1,"ts":0,"args":{"v":4}}
+{"ph":"C","name":"mem","id":1,"pid":1,"tid":1,"ts":60,"args":{"v":6}}
+{"ph":"C","name":"mem","id":2,"pid":1,"tid":1,"ts":30,"args":{"v":1}}
+"#;
+        let mut out = vec![];
+        emit_firefox(&mut trace.as_bytes(), &mut out).unwrap();
+        let profile: Value = serde_json::from_slice(&out).unwrap();
+
+        let threads = profile["threads"].as_array().unwrap();
+        assert_eq!(threads.len(), 1);
+        let th = &threads[0];
+        let strings = th["stringArray"].as_array().unwrap();
+        let name = |v: &Value| strings[v.as_u64().unwrap() as usize].clone();
+
+        // one sample per span, weighted by its self time
+        assert_eq!(th["samples"]["weight"], json!([0.07, 0.03]));
+        assert_eq!(th["stackTable"]["prefix"], json!([null, 0]));
+
+        // spans, then async spans, then instants
+        let markers = &th["markers"];
+        let names: Vec<Value> = markers["name"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(name)
+            .collect();
+        assert_eq!(names, ["main", "load", "req", "tick"]);
+        assert_eq!(markers["startTime"][2], 0.02);
+        assert_eq!(markers["endTime"][2], 0.08);
+        assert_eq!(markers["endTime"][3], Value::Null);
+        assert_eq!(markers["data"][2]["cat"], "net");
+
+        // counters are kept apart by id, with deltas between samples
+        let counters = profile["counters"].as_array().unwrap();
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters[0]["name"], "mem id: 1");
+        let samples = &counters[0]["sampleGroups"][0]["samples"];
+        assert_eq!(samples["time"], json!([0.0, 0.06]));
+        assert_eq!(samples["count"], json!([4.0, 2.0]));
+        assert_eq!(counters[1]["name"], "mem id: 2");
+    }
+}
@@ -0,0 +1,61 @@
+//! Conversion to folded stacks, as used by `flamegraph.pl` and inferno.
+//!
+//! Each line is `frame1;frame2;frame3 weight`, where the weight is the
+//! self time of the innermost frame, in microseconds.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::Result;
+
+use crate::{event::Events, spans::Spans};
+
+/// `;` separates frames, and newlines separate stacks.
+fn sanitize(name: &str) -> String {
+    name.replace(';', ":").replace(['\n', '\r'], " ")
+}
+
+/// Reads jsonl from `reader` and writes folded stacks into `writer`.
+pub fn emit_folded(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()> {
+    let mut events = Events::new(reader);
+    let spans = Spans::from_events(&mut events)?;
+    events.report_invalid();
+
+    // identical stacks are merged, in a deterministic order
+    let mut weights: BTreeMap<String, f64> = BTreeMap::new();
+    for (i, span) in spans.spans.iter().enumerate() {
+        let stack: Vec<String> = spans.stack(i).into_iter().map(sanitize).collect();
+        *weights.entry(stack.join(";")).or_default() += span.self_dur.max(0.);
+    }
+
+    for (stack, weight) in weights {
+        let weight = weight.round() as u64;
+        if weight > 0 {
+            writeln!(writer, "{stack} {weight}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_time_per_stack() {
+        let trace = r#"{"ph":"X","name":"main","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"a;b","pid":1,"tid":1,"ts":10,"dur":30}
+{"ph":"B","name":"c","pid":1,"tid":1,"ts":50}
+{"ph":"E","pid":1,"tid":1,"ts":70}
+{"ph":"X","name":"main","pid":1,"tid":2,"ts":0,"dur":5}
+"#;
+        let mut out = vec![];
+        emit_folded(&mut trace.as_bytes(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main 55\nmain;a:b 30\nmain;c 20\n"
+        );
+    }
+}
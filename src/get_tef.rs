@@ -1,14 +1,18 @@
 use std::{
+    cell::Cell,
     fs,
-    io::{self, stdout, BufReader, BufWriter},
-    path::PathBuf,
+    io::{self, stdout, BufWriter, Cursor},
+    path::{Path, PathBuf},
 };
 
 use anyhow::Result;
 
-use crate::{cli, utils};
+use crate::{cli, config::Config, filter, index, merge, otlp, sample, slice, utils};
 
-fn get_file_in_dir(file: &str, d: &str) -> Result<String> {
+/// Number of spans per request with `--otlp-file`.
+const OTLP_FILE_BATCH_SIZE: usize = 1000;
+
+fn get_file_in_dir(file: &str, d: &Path) -> Result<String> {
     let mut file2 = PathBuf::from(&d);
     file2.push(file);
 
@@ -19,7 +23,7 @@ fn get_file_in_dir(file: &str, d: &str) -> Result<String> {
     }
 }
 
-fn find_latest_file(d: Option<impl AsRef<str>>) -> Result<String> {
+fn find_latest_file(d: &Path) -> Result<String> {
     let mut files = crate::list::list_files(d)?;
     files.sort();
 
@@ -29,26 +33,81 @@ fn find_latest_file(d: Option<impl AsRef<str>>) -> Result<String> {
     Ok(f.to_string_lossy().to_string())
 }
 
-pub fn run(cli: cli::GetTEF) -> Result<()> {
-    let mut file = cli.jsonl_file;
-
+/// Resolve `file`: an existing path, "latest", or the name of a file in the storage directory.
+pub(crate) fn find_trace_file(
+    mut file: String,
+    dir: Option<&String>,
+    config: &Config,
+) -> Result<String> {
     if fs::exists(&file).ok() != Some(true) {
-        dbg!(&file);
+        let dir = config.data_dir(dir)?;
         if file == "latest" {
-            file = find_latest_file(cli.dir.as_ref())?;
+            file = find_latest_file(&dir)?;
         } else {
-            match cli.dir {
-                None => anyhow::bail!("File {file:?} does not exist"),
-                Some(d) => {
-                    file = get_file_in_dir(&file, &d)?;
-                }
-            }
+            file = get_file_in_dir(&file, &dir)?;
         }
     }
+    Ok(file)
+}
+
+pub fn run(cli: cli::GetTEF, config: &Config) -> Result<()> {
+    let mut files = vec![];
+    for file in &cli.jsonl_file {
+        let file = find_trace_file(file.clone(), cli.dir.as_ref(), config)?;
+        log::info!("reading TEF trace from file {file:?}");
+        files.push(file);
+    }
+    let policies = sample::Policies::parse(&cli.sample)?;
+    let inputs = merge::Inputs::new(files, &cli.merge)?;
+    let trace_id = inputs.trace_id();
 
-    log::info!("reading TEF trace from file {file:?}");
-    let file_in = fs::File::open(file)?;
-    let mut reader = BufReader::new(file_in);
+    let file = inputs.single_file().map(Path::new);
+
+    // computed at most once, and only if needed
+    let start = Cell::new(None);
+    let trace_start = || -> Result<f64> {
+        if let Some(s) = start.get() {
+            return Ok(s);
+        }
+        let s = match file {
+            Some(f) => index::trace_start(f)?,
+            None => filter::trace_start(inputs.open()?)?,
+        };
+        start.set(Some(s));
+        Ok(s)
+    };
+
+    let slice = slice::Slice::from_cli(&cli, trace_start)?;
+    let filter = match &cli.filter {
+        Some(expr) => Some(filter::compile(expr, trace_start)?),
+        None => None,
+    };
+    // with an index, only read the parts of the trace that the slice and
+    // the filter need
+    let mut sel = index::Selection::all();
+    if let Some(slice) = &slice {
+        sel = sel.and(slice.selection());
+    }
+    if let Some(filter) = &filter {
+        sel = sel.and(filter.selection());
+    }
+    let selected = match file {
+        Some(f) => index::open_selection(f, &sel)?,
+        None => None,
+    };
+    let mut reader: Box<dyn io::BufRead> = match selected {
+        Some(r) => Box::new(r),
+        None => inputs.open()?,
+    };
+    if let Some(filter) = filter {
+        reader = Box::new(filter::Filtered::new(reader, filter, true));
+    }
+    if let Some(slice) = slice {
+        reader = Box::new(Cursor::new(slice.apply(reader)?));
+    }
+    if !cli.sample.is_empty() {
+        reader = Box::new(sample::Sampled::new(reader, policies));
+    }
 
     let out: Box<dyn io::Write> = match cli.o {
         Some(f) => Box::new(fs::File::create(f)?),
@@ -56,7 +115,17 @@ pub fn run(cli: cli::GetTEF) -> Result<()> {
     };
     let mut writer = BufWriter::new(out);
 
-    utils::emit_tef(&mut reader, &mut writer)?;
+    if cli.otlp_file {
+        otlp::emit_otlp_json(
+            &trace_id,
+            &mut reader,
+            &mut writer,
+            Some(OTLP_FILE_BATCH_SIZE),
+        )?;
+    } else {
+        let format = cli.format.or(config.format).unwrap_or_default();
+        utils::emit(format, &trace_id, &mut reader, &mut writer)?;
+    }
 
     Ok(())
 }
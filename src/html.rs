@@ -0,0 +1,276 @@
+//! Self-contained HTML report.
+//!
+//! The page embeds the trace (in TEF), tables of the top spans by total and
+//! self time, a timeline per process, and a button that opens the embedded
+//! trace in Perfetto with `postMessage`.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{BufRead, Write},
+};
+
+use anyhow::Result;
+
+use crate::{
+    event::{hash_str, Events},
+    spans::Spans,
+    utils,
+};
+
+const TEMPLATE: &str = include_str!("report.html");
+
+/// Number of rows in each "top spans" table
+const TOP_N: usize = 25;
+
+/// Width of the timelines, in SVG units, not counting thread labels
+const TIMELINE_WIDTH: f64 = 1000.;
+const LABEL_WIDTH: f64 = 220.;
+/// Nesting levels shown in the timelines
+const TIMELINE_DEPTH: usize = 3;
+const LEVEL_HEIGHT: f64 = 6.;
+const ROW_GAP: f64 = 6.;
+/// Spans narrower than this (in SVG units) are not drawn
+const MIN_WIDTH: f64 = 0.2;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// JSON to put in a `<script>` tag. `<` can only appear in strings,
+/// where it is escaped so that the tag cannot be closed early.
+fn script_json(json: &str) -> String {
+    json.replace('<', "\\u003c")
+}
+
+/// Replace `{{NAME}}` placeholders in the template, in a single pass.
+fn fill_template(vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(TEMPLATE.len());
+    let mut rest = TEMPLATE;
+    while let Some(i) = rest.find("{{") {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let value = rest.find("}}").and_then(|j| {
+            let (_, v) = vars.iter().find(|(k, _)| *k == &rest[2..j])?;
+            Some((v, j + 2))
+        });
+        match value {
+            Some((v, len)) => {
+                out.push_str(v);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = &rest[2..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Aggregated durations for a span name.
+#[derive(Default)]
+struct Stat {
+    count: usize,
+    total: f64,
+    self_dur: f64,
+}
+
+fn stats_table(title: &str, rows: &[(&str, &Stat)]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "<table>\n<caption><h2>{title}</h2></caption>");
+    let _ = writeln!(
+        out,
+        "<tr><th>name</th><th>count</th><th>total</th><th>self</th></tr>"
+    );
+    for (name, stat) in rows.iter().take(TOP_N) {
+        let _ = writeln!(
+            out,
+            "<tr><td class=\"name\" title=\"{0}\">{0}</td><td>{1}</td><td>{2}</td><td>{3}</td></tr>",
+            escape(name),
+            stat.count,
+            utils::fmt_dur(stat.total),
+            utils::fmt_dur(stat.self_dur),
+        );
+    }
+    out.push_str("</table>\n");
+    out
+}
+
+fn tables(spans: &Spans) -> String {
+    let mut by_name: HashMap<&str, Stat> = HashMap::new();
+    for (i, span) in spans.spans.iter().enumerate() {
+        let stat = by_name.entry(&span.name).or_default();
+        stat.count += 1;
+        stat.self_dur += span.self_dur.max(0.);
+        // don't count recursive calls twice
+        if !spans.is_recursive(i) {
+            stat.total += span.dur();
+        }
+    }
+
+    let mut rows: Vec<(&str, &Stat)> = by_name.iter().map(|(k, v)| (*k, v)).collect();
+    rows.sort_by(|a, b| b.1.total.total_cmp(&a.1.total).then(a.0.cmp(b.0)));
+    let by_total = stats_table("Top spans by total time", &rows);
+    rows.sort_by(|a, b| b.1.self_dur.total_cmp(&a.1.self_dur).then(a.0.cmp(b.0)));
+    let by_self = stats_table("Top spans by self time", &rows);
+
+    format!("<div class=\"tables\">\n{by_total}{by_self}</div>")
+}
+
+/// Color for a span name, stable across runs.
+fn color(name: &str) -> String {
+    format!("hsl({}, 60%, 60%)", hash_str(name) % 360)
+}
+
+/// Start of the first span and end of the last one.
+fn time_range(spans: &Spans) -> Option<(f64, f64)> {
+    if spans.spans.is_empty() {
+        return None;
+    }
+    let start = spans.spans.iter().map(|s| s.start).fold(f64::MAX, f64::min);
+    let end = spans.spans.iter().map(|s| s.end).fold(f64::MIN, f64::max);
+    Some((start, end))
+}
+
+fn timelines(spans: &Spans) -> String {
+    let Some((t_min, t_max)) = time_range(spans) else {
+        return "<p>No spans.</p>".to_string();
+    };
+    let scale = TIMELINE_WIDTH / (t_max - t_min).max(1.);
+    let row_height = LEVEL_HEIGHT * TIMELINE_DEPTH as f64 + ROW_GAP;
+
+    // spans are sorted by pid, then tid
+    let mut out = String::new();
+    for process in spans.spans.chunk_by(|a, b| a.pid == b.pid) {
+        let pid = process[0].pid;
+        let threads: Vec<_> = process.chunk_by(|a, b| a.tid == b.tid).collect();
+        let title = match spans.process_names.get(&pid) {
+            Some(name) => format!("{} ({pid})", escape(name)),
+            None => format!("pid {pid}"),
+        };
+        let _ = writeln!(out, "<h3>{title}</h3>");
+        let _ = writeln!(
+            out,
+            "<svg class=\"timeline\" viewBox=\"0 0 {} {}\">",
+            LABEL_WIDTH + TIMELINE_WIDTH,
+            row_height * threads.len() as f64
+        );
+
+        for (row, thread) in threads.iter().enumerate() {
+            let tid = thread[0].tid;
+            let y = row as f64 * row_height;
+            let label = match spans.thread_names.get(&(pid, tid)) {
+                Some(name) => format!("{} ({tid})", escape(name)),
+                None => format!("tid {tid}"),
+            };
+            let _ = writeln!(
+                out,
+                "<text x=\"4\" y=\"{}\">{label}</text>",
+                y + LEVEL_HEIGHT * 2.
+            );
+            for span in thread.iter().filter(|s| s.depth < TIMELINE_DEPTH) {
+                let w = span.dur() * scale;
+                if w < MIN_WIDTH {
+                    continue;
+                }
+                let x = LABEL_WIDTH + (span.start - t_min) * scale;
+                let _ = writeln!(
+                    out,
+                    "<rect x=\"{x:.2}\" y=\"{:.1}\" width=\"{w:.2}\" height=\"{}\" fill=\"{}\"><title>{} ({})</title></rect>",
+                    y + span.depth as f64 * LEVEL_HEIGHT,
+                    LEVEL_HEIGHT - 1.,
+                    color(&span.name),
+                    escape(&span.name),
+                    utils::fmt_dur(span.dur()),
+                );
+            }
+        }
+        out.push_str("</svg>\n");
+    }
+    out
+}
+
+/// Reads jsonl from `reader` and writes an HTML report into `writer`.
+pub fn emit_html(trace_id: &str, reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()> {
+    // the input is read twice, for the embedded trace and for the summary
+    let mut input = vec![];
+    reader.read_to_end(&mut input)?;
+
+    let mut tef = vec![];
+    utils::emit_tef(&mut &input[..], &mut tef)?;
+    let tef = script_json(&String::from_utf8_lossy(&tef));
+
+    let mut events = Events::new(&input[..]);
+    let spans = Spans::from_events(&mut events)?;
+    events.report_invalid();
+
+    let n_threads = spans
+        .spans
+        .chunk_by(|a, b| (a.pid, a.tid) == (b.pid, b.tid))
+        .count();
+    let duration = time_range(&spans).map_or(0., |(start, end)| end - start);
+    let summary = format!(
+        "{} spans on {n_threads} threads, over {}.",
+        spans.spans.len(),
+        utils::fmt_dur(duration)
+    );
+
+    let title = if trace_id.is_empty() {
+        "trace"
+    } else {
+        trace_id
+    };
+    let html = fill_template(&[
+        ("TITLE", &escape(title)),
+        ("TITLE_JSON", &script_json(&serde_json::to_string(title)?)),
+        ("SUMMARY", &summary),
+        ("TABLES", &tables(&spans)),
+        ("TIMELINES", &timelines(&spans)),
+        ("TRACE", &tef),
+    ]);
+    writer.write_all(html.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let trace = r#"{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{"name":"srv"}}
+{"ph":"X","name":"f","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"f","pid":1,"tid":1,"ts":10,"dur":50}
+{"ph":"X","name":"</script><b>{{TITLE}}","pid":1,"tid":2,"ts":0,"dur":10}
+"#;
+        let mut out = vec![];
+        emit_html("t<1>", &mut trace.as_bytes(), &mut out).unwrap();
+        let html = String::from_utf8(out).unwrap();
+
+        assert!(html.contains("<title>t&lt;1&gt; - tldrs</title>"));
+        assert!(html.contains("3 spans on 2 threads, over 100.0 µs."));
+        assert!(html.contains("<h3>srv (1)</h3>"));
+        // recursive calls are counted once in the total
+        assert!(html.contains(
+            "<td class=\"name\" title=\"f\">f</td><td>2</td><td>100.0 µs</td><td>100.0 µs</td>"
+        ));
+
+        // names cannot close the script tag or fill placeholders
+        assert!(!html.contains("<b>"));
+        assert!(html.contains("&lt;/script&gt;&lt;b&gt;{{TITLE}}"));
+        assert_eq!(html.matches("</script>").count(), 2);
+
+        // the embedded trace is the original one
+        let start = html.find("id=\"trace-data\">").unwrap() + "id=\"trace-data\">".len();
+        let end = start + html[start..].find("</script>").unwrap();
+        let tef: serde_json::Value = serde_json::from_str(&html[start..end]).unwrap();
+        let events = tef.get("traceEvents").unwrap_or(&tef);
+        assert_eq!(events.as_array().unwrap().len(), 4);
+        assert_eq!(events[3]["name"], "</script><b>{{TITLE}}");
+    }
+}
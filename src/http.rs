@@ -0,0 +1,222 @@
+//! Minimal HTTP/1.1 server side, enough for OTLP/HTTP.
+//!
+//! Requests are read with their whole body (`Content-Length` or chunked).
+//! Connections are kept alive unless the client asks otherwise.
+
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{Context, Result};
+
+/// Maximum size of a request body.
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Maximum size of the request line and of each header line.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// Maximum number of headers of a request.
+const MAX_HEADERS: usize = 100;
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Does the client want the connection closed after this request?
+    pub fn wants_close(&self) -> bool {
+        self.header("connection")
+            .is_some_and(|c| c.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// Read a line without its line ending. Returns `None` on EOF.
+fn read_line(r: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    let n = r.by_ref().take(MAX_LINE_LEN).read_line(&mut line)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        anyhow::bail!("Line too long, or truncated");
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn read_chunked(r: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(r)?.context("Truncated chunk")?;
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16).context("Invalid chunk size")?;
+        if size == 0 {
+            // skip trailers
+            while !read_line(r)?.context("Truncated trailers")?.is_empty() {}
+            return Ok(body);
+        }
+        if body.len() + size > MAX_BODY_SIZE {
+            anyhow::bail!("Request body too large");
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        r.read_exact(&mut body[start..])?;
+        if !read_line(r)?.context("Truncated chunk")?.is_empty() {
+            anyhow::bail!("Invalid chunk end");
+        }
+    }
+}
+
+/// Read a request. Returns `None` if the connection was closed.
+pub fn read_request(r: &mut impl BufRead) -> Result<Option<Request>> {
+    let Some(line) = read_line(r)? else {
+        return Ok(None);
+    };
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("Invalid request line {line:?}");
+    };
+    // ignore the query, if any
+    let path = target.split('?').next().unwrap_or(target);
+
+    let mut headers = vec![];
+    loop {
+        let line = read_line(r)?.context("Truncated headers")?;
+        if line.is_empty() {
+            break;
+        }
+        anyhow::ensure!(headers.len() < MAX_HEADERS, "Too many headers");
+        let (k, v) = line
+            .split_once(':')
+            .with_context(|| format!("Invalid header {line:?}"))?;
+        headers.push((k.trim().to_ascii_lowercase(), v.trim().to_string()));
+    }
+
+    let mut req = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+        body: vec![],
+    };
+
+    if req
+        .header("transfer-encoding")
+        .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+    {
+        req.body = read_chunked(r)?;
+    } else if let Some(len) = req.header("content-length") {
+        let len: usize = len.parse().context("Invalid Content-Length")?;
+        if len > MAX_BODY_SIZE {
+            anyhow::bail!("Request body too large");
+        }
+        req.body = vec![0; len];
+        r.read_exact(&mut req.body)?;
+    }
+    Ok(Some(req))
+}
+
+/// Write a complete response.
+pub fn write_response(
+    w: &mut impl Write,
+    status: u16,
+    reason: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )?;
+    w.write_all(body)?;
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8]) -> Result<Option<Request>> {
+        read_request(&mut &input[..])
+    }
+
+    #[test]
+    fn keep_alive() {
+        let mut input: &[u8] =
+            b"POST /v1/traces?x=1 HTTP/1.1\r\nContent-Type: application/json\r\n\
+            Content-Length: 2\r\n\r\n{}GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let req = read_request(&mut input).unwrap().unwrap();
+        assert_eq!(
+            (req.method.as_str(), req.path.as_str()),
+            ("POST", "/v1/traces")
+        );
+        assert_eq!(req.header("content-type"), Some("application/json"));
+        assert_eq!(req.body, b"{}");
+        assert!(!req.wants_close());
+
+        let req = read_request(&mut input).unwrap().unwrap();
+        assert_eq!(req.method, "GET");
+        assert!(req.wants_close());
+        assert!(read_request(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn chunked() {
+        let req = read(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(req.body, b"abcde");
+
+        assert!(read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").is_err());
+        assert!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcd\r\n").is_err()
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let long_line = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "a".repeat(MAX_LINE_LEN as usize)
+        );
+        assert!(read(long_line.as_bytes()).is_err());
+
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: b\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(read(many_headers.as_bytes()).is_err());
+
+        let too_large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(read(too_large.as_bytes()).is_err());
+
+        let chunk = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(read(chunk.as_bytes()).is_err());
+
+        // truncated or malformed requests
+        assert!(read(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(read(b"GET /\r\n\r\n").is_err());
+        assert!(read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab").is_err());
+        assert!(read(b"GET / HTTP/1.1\r\nno colon\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,668 @@
+//! Sidecar index of trace files, to read parts of large traces without
+//! scanning them.
+//!
+//! The index of `foo.jsonl` is `foo.jsonl.idx`, a JSON file. It divides the
+//! trace into blocks of about 1 MiB and records, for each block, its time
+//! range, threads and event names, the last event of each counter, and where
+//! the spans that cross its boundaries begin and end. Reading a time window,
+//! some threads or some names then only takes the blocks that have them and a
+//! few lines outside of them. Traces are only appended to, so an index stays
+//! valid for the part of the file it covers, and updating it only reads the
+//! new lines.
+//!
+//! `get-tef` uses it for its slicing options and `--filter`, and `query` for
+//! its expression. `EMIT_TEF`/`EMIT_PROTO` don't: they write the whole trace.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli, config::Config, event::Event, filter, get_tef, list, sqlite, utils};
+
+/// Blocks end at the first end of line after this many bytes.
+const BLOCK_SIZE: u64 = 1 << 20;
+
+/// Bumped when the format of the index changes; older indexes are rebuilt.
+const VERSION: u32 = 2;
+
+/// Blocks with more distinct event names than this match any name.
+const MAX_NAMES: usize = 256;
+
+const INDEX_EXTENSION: &str = "idx";
+
+/// Path of the index of `trace`.
+pub fn index_path(trace: &Path) -> PathBuf {
+    let mut path = trace.as_os_str().to_owned();
+    path.push(".");
+    path.push(INDEX_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Is `path` an index, or an index being written?
+pub fn is_index_file(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    path.ends_with(&format!(".{INDEX_EXTENSION}"))
+        || path.contains(&format!(".{INDEX_EXTENSION}.tmp."))
+}
+
+/// A span, or an async span.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum SpanKey {
+    Thread(i64, i64),
+    Async(i64, String, String),
+}
+
+/// Byte range of a line: offset and length.
+type Line = (u64, u64);
+
+#[derive(Serialize, Deserialize)]
+struct Block {
+    offset: u64,
+    len: u64,
+    n_events: u64,
+    /// Smallest timestamp of non-metadata events
+    ts_min: f64,
+    /// Largest end of non-metadata events
+    ts_max: f64,
+    threads: BTreeSet<(i64, i64)>,
+    /// Names of non-metadata events, `None` if there are more than `MAX_NAMES`
+    names: Option<BTreeSet<String>>,
+    /// Last event of each counter, keyed by `pid/name/id`
+    counters: BTreeMap<String, Line>,
+    /// Beginnings of the spans open at the start of the block
+    open_begins: Vec<Line>,
+    /// Ends of the spans open at the end of the block
+    open_ends: Vec<Line>,
+}
+
+/// The beginning of a span that is not closed yet, and its block.
+#[derive(Serialize, Deserialize)]
+struct OpenSpan {
+    block: usize,
+    line: Line,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Index {
+    version: u32,
+    /// Number of bytes of the trace covered by the index
+    size: u64,
+    pub n_events: u64,
+    /// Smallest timestamp of non-metadata events
+    pub start: Option<f64>,
+    /// Largest end of non-metadata events
+    pub end: Option<f64>,
+    metadata: Vec<Line>,
+    blocks: Vec<Block>,
+    /// Spans open at the end of the indexed part
+    open: Vec<(SpanKey, Vec<OpenSpan>)>,
+}
+
+/// Parts of a trace to read: a time window, some processes or threads, and
+/// some event names. Empty lists select everything.
+#[derive(Debug, Clone)]
+pub struct Selection {
+    pub from: f64,
+    pub to: f64,
+    pub pids: Vec<i64>,
+    pub tids: Vec<i64>,
+    pub names: Vec<String>,
+}
+
+impl Selection {
+    /// The whole trace.
+    pub fn all() -> Self {
+        Self {
+            from: f64::MIN,
+            to: f64::MAX,
+            pids: vec![],
+            tids: vec![],
+            names: vec![],
+        }
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.from == f64::MIN
+            && self.to == f64::MAX
+            && self.pids.is_empty()
+            && self.tids.is_empty()
+            && self.names.is_empty()
+    }
+
+    /// The parts of the trace that both selections need.
+    pub fn and(self, other: Selection) -> Selection {
+        fn both<T: Clone + PartialEq>(a: Vec<T>, b: Vec<T>) -> Vec<T> {
+            if a.is_empty() {
+                return b;
+            }
+            let res: Vec<T> = a
+                .iter()
+                .filter(|x| b.is_empty() || b.contains(x))
+                .cloned()
+                .collect();
+            // nothing is in both, but an empty list would select everything
+            if res.is_empty() {
+                a
+            } else {
+                res
+            }
+        }
+        Selection {
+            from: self.from.max(other.from),
+            to: self.to.min(other.to),
+            pids: both(self.pids, other.pids),
+            tids: both(self.tids, other.tids),
+            names: both(self.names, other.names),
+        }
+    }
+}
+
+impl Index {
+    fn new() -> Self {
+        Self {
+            version: VERSION,
+            size: 0,
+            n_events: 0,
+            start: None,
+            end: None,
+            metadata: vec![],
+            blocks: vec![],
+            open: vec![],
+        }
+    }
+
+    /// Does the index cover the whole trace, of length `len`?
+    pub fn is_complete(&self, len: u64) -> bool {
+        self.size == len
+    }
+
+    pub fn n_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Index the lines of `trace` that come after the indexed part.
+    /// A last line without its end of line is left for later.
+    fn extend(&mut self, trace: &Path) -> Result<()> {
+        let mut file = fs::File::open(trace)?;
+        file.seek(SeekFrom::Start(self.size))?;
+        let mut reader = BufReader::new(file);
+
+        let mut open: HashMap<SpanKey, Vec<OpenSpan>> =
+            std::mem::take(&mut self.open).into_iter().collect();
+
+        let mut offset = self.size;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)? as u64;
+            if n == 0 || !line.ends_with('\n') {
+                break;
+            }
+            let range = (offset, n);
+            offset += n;
+
+            if self.blocks.last().map_or(true, |b| b.len >= BLOCK_SIZE) {
+                let mut open_begins: Vec<Line> = open.values().flatten().map(|o| o.line).collect();
+                open_begins.sort();
+                self.blocks.push(Block {
+                    offset: range.0,
+                    len: 0,
+                    n_events: 0,
+                    ts_min: f64::MAX,
+                    ts_max: f64::MIN,
+                    threads: BTreeSet::new(),
+                    names: Some(BTreeSet::new()),
+                    counters: BTreeMap::new(),
+                    open_begins,
+                    open_ends: vec![],
+                });
+            }
+            let k = self.blocks.len() - 1;
+            self.blocks[k].len += n;
+
+            let Ok(ev) = Event::parse(line.trim_end()) else {
+                continue;
+            };
+            self.n_events += 1;
+            self.blocks[k].n_events += 1;
+            if ev.ph == 'M' {
+                self.metadata.push(range);
+                continue;
+            }
+
+            self.start = Some(self.start.map_or(ev.ts, |s| s.min(ev.ts)));
+            self.end = Some(self.end.map_or(ev.end(), |e| e.max(ev.end())));
+            let b = &mut self.blocks[k];
+            b.ts_min = b.ts_min.min(ev.ts);
+            b.ts_max = b.ts_max.max(ev.end());
+            b.threads.insert((ev.pid, ev.tid));
+            let too_many_names = match &mut b.names {
+                Some(names) => {
+                    if !names.contains(&ev.name) {
+                        names.insert(ev.name.clone());
+                    }
+                    names.len() > MAX_NAMES
+                }
+                None => false,
+            };
+            if too_many_names {
+                b.names = None;
+            }
+
+            let key = match ev.ph {
+                'C' => {
+                    let id = ev.id_str().unwrap_or_default();
+                    b.counters
+                        .insert(format!("{}/{}/{id}", ev.pid, ev.name), range);
+                    continue;
+                }
+                'B' | 'E' => SpanKey::Thread(ev.pid, ev.tid),
+                'b' | 'e' => {
+                    SpanKey::Async(ev.pid, ev.cat.clone(), ev.id_str().unwrap_or_default())
+                }
+                _ => continue,
+            };
+            if ev.ph == 'B' || ev.ph == 'b' {
+                let span = OpenSpan {
+                    block: k,
+                    line: range,
+                };
+                open.entry(key).or_default().push(span);
+            } else if let Some(span) = open.get_mut(&key).and_then(|s| s.pop()) {
+                for b in &mut self.blocks[span.block..k] {
+                    b.open_ends.push(range);
+                }
+            }
+        }
+
+        self.size = offset;
+        self.open = open.into_iter().filter(|(_, s)| !s.is_empty()).collect();
+        Ok(())
+    }
+
+    fn save(&self, trace: &Path) -> Result<()> {
+        let path = index_path(trace);
+        // write then rename, so that readers never see a partial index. The
+        // daemon and `tldrs index` can update the same index at once, each
+        // writes its own file and the last rename wins.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(".tmp.{}.{nanos}", std::process::id()));
+        let res = fs::write(&tmp, serde_json::to_vec(self)?).and_then(|()| fs::rename(&tmp, &path));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res.with_context(|| format!("writing index {path:?}"))?;
+        Ok(())
+    }
+
+    /// Byte ranges of the trace to read for `sel`, in order: the blocks of
+    /// the window, metadata events, the beginnings and ends of spans that
+    /// cross the window, the last value of each counter before it, and the
+    /// part of the trace that is not indexed.
+    pub fn select(&self, sel: &Selection) -> Vec<Line> {
+        let selected: Vec<bool> = self
+            .blocks
+            .iter()
+            .map(|b| {
+                b.ts_max >= sel.from
+                    && b.ts_min <= sel.to
+                    && b.threads.iter().any(|(pid, tid)| {
+                        (sel.pids.is_empty() || sel.pids.contains(pid))
+                            && (sel.tids.is_empty() || sel.tids.contains(tid))
+                    })
+                    && (sel.names.is_empty()
+                        || b.names
+                            .as_ref()
+                            .map_or(true, |names| sel.names.iter().any(|n| names.contains(n))))
+            })
+            .collect();
+
+        let mut lines: Vec<Line> = self.metadata.clone();
+        // spans that cross the boundaries of each run of selected blocks
+        for (k, b) in self.blocks.iter().enumerate().filter(|(k, _)| selected[*k]) {
+            if k == 0 || !selected[k - 1] {
+                lines.extend(&b.open_begins);
+            }
+            if !selected.get(k + 1).copied().unwrap_or(false) {
+                lines.extend(&b.open_ends);
+            }
+        }
+        // the last value of each counter before the window
+        if let Some(first) = selected.iter().position(|s| *s) {
+            let mut seen = HashSet::new();
+            for b in self.blocks[..first].iter().rev() {
+                for (key, line) in &b.counters {
+                    let pid = key.split('/').next().and_then(|p| p.parse().ok());
+                    let pid_selected =
+                        sel.pids.is_empty() || pid.is_some_and(|p| sel.pids.contains(&p));
+                    if pid_selected && seen.insert(key) {
+                        lines.push(*line);
+                    }
+                }
+            }
+        }
+
+        // lines of selected blocks are read with their block
+        let block_of = |offset: u64| self.blocks.partition_point(|b| b.offset <= offset) - 1;
+        lines.retain(|(offset, _)| !selected[block_of(*offset)]);
+        lines.extend(
+            self.blocks
+                .iter()
+                .zip(&selected)
+                .filter(|(_, s)| **s)
+                .map(|(b, _)| (b.offset, b.len)),
+        );
+        lines.push((self.size, u64::MAX - self.size));
+        lines.sort();
+        lines.dedup();
+
+        let mut ranges: Vec<Line> = vec![];
+        for (offset, len) in lines {
+            match ranges.last_mut() {
+                Some(last) if last.0 + last.1 == offset => last.1 += len,
+                _ => ranges.push((offset, len)),
+            }
+        }
+        ranges
+    }
+}
+
+/// The index of `trace`, if it exists and is valid for it.
+pub fn load(trace: &Path) -> Result<Option<Index>> {
+    let path = index_path(trace);
+    let data = match fs::read(&path) {
+        Ok(d) => d,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("reading index {path:?}")),
+    };
+    let index: Index = match serde_json::from_slice(&data) {
+        Ok(i) => i,
+        Err(err) => {
+            log::warn!("ignoring invalid index {path:?}: {err}");
+            return Ok(None);
+        }
+    };
+    if index.version != VERSION {
+        log::debug!("ignoring index {path:?} of version {}", index.version);
+        return Ok(None);
+    }
+
+    // the trace must still have a line ending where the index stops
+    let mut file = fs::File::open(trace)?;
+    if file.metadata()?.len() < index.size {
+        log::warn!("ignoring index {path:?}, the trace is shorter than it");
+        return Ok(None);
+    }
+    if index.size > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::Start(index.size - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            log::warn!("ignoring index {path:?}, it does not match the trace");
+            return Ok(None);
+        }
+    }
+    Ok(Some(index))
+}
+
+/// Create the index of `trace`, or update it with the lines added since.
+pub fn update(trace: &Path, rebuild: bool) -> Result<Index> {
+    let index = if rebuild { None } else { load(trace)? };
+    let mut index = index.unwrap_or_else(Index::new);
+    let len = fs::metadata(trace)?.len();
+    if index.size < len || !index_path(trace).exists() {
+        index.extend(trace)?;
+        index.save(trace)?;
+    }
+    Ok(index)
+}
+
+/// Start of `trace`, from its index if it covers the whole trace.
+pub fn trace_start(trace: &Path) -> Result<f64> {
+    if let Some(index) = load(trace)? {
+        if index.is_complete(fs::metadata(trace)?.len()) {
+            return Ok(index.start.unwrap_or_default());
+        }
+    }
+    filter::trace_start(utils::open_trace(trace)?)
+}
+
+/// Reads byte ranges of a file, one after the other.
+struct Ranges {
+    file: fs::File,
+    ranges: std::vec::IntoIter<Line>,
+    /// Bytes left in the current range
+    remaining: u64,
+}
+
+impl Read for Ranges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.remaining > 0 {
+                let n = buf
+                    .len()
+                    .min(self.remaining.min(usize::MAX as u64) as usize);
+                let n = self.file.read(&mut buf[..n])?;
+                if n > 0 {
+                    self.remaining -= n as u64;
+                    return Ok(n);
+                }
+                // end of file
+                self.remaining = 0;
+            }
+            let Some((offset, len)) = self.ranges.next() else {
+                return Ok(0);
+            };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = len;
+        }
+    }
+}
+
+/// Read the parts of `trace` that `sel` needs, if it has an index and the
+/// selection can skip some of it.
+pub fn open_selection(trace: &Path, sel: &Selection) -> Result<Option<impl BufRead>> {
+    if sel.is_all() {
+        return Ok(None);
+    }
+    let Some(index) = load(trace)? else {
+        return Ok(None);
+    };
+    let ranges = index.select(sel);
+    log::debug!(
+        "reading {} ranges of {trace:?} using its index",
+        ranges.len()
+    );
+    Ok(Some(open_ranges(trace, ranges)?))
+}
+
+/// Read the byte `ranges` of `trace`.
+fn open_ranges(trace: &Path, ranges: Vec<(u64, u64)>) -> Result<impl BufRead> {
+    Ok(BufReader::new(Ranges {
+        file: fs::File::open(trace)?,
+        ranges: ranges.into_iter(),
+        remaining: 0,
+    }))
+}
+
+pub fn run(cli: cli::Index, config: &Config) -> Result<()> {
+    let files: Vec<PathBuf> = if cli.jsonl_file.is_empty() {
+        let mut files = list::list_files(&config.data_dir(cli.dir.as_ref())?)?;
+        files.sort();
+        files
+    } else {
+        cli.jsonl_file
+            .into_iter()
+            .map(|f| {
+                Ok(PathBuf::from(get_tef::find_trace_file(
+                    f,
+                    cli.dir.as_ref(),
+                    config,
+                )?))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    for f in files {
+        if sqlite::is_sqlite(&f) {
+            // databases have their own indexes
+            log::debug!("skipping {f:?}, a SQLite database");
+            continue;
+        }
+        let index = update(&f, cli.rebuild).with_context(|| format!("indexing {f:?}"))?;
+        println!(
+            "{}: {} events in {} blocks",
+            f.display(),
+            index.n_events,
+            index.n_blocks()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Events;
+    use std::io::Write;
+
+    /// A trace of several blocks: a span and a counter start in the first
+    /// block, and the span ends in the last one.
+    fn write_trace(name: &str) -> (PathBuf, usize) {
+        let dir = std::env::temp_dir().join(format!("tldrs-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("t.jsonl");
+        let mut out = io::BufWriter::new(fs::File::create(&path).unwrap());
+        let n = 50_000;
+        writeln!(
+            out,
+            r#"{{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{{"name":"p"}}}}"#
+        )
+        .unwrap();
+        writeln!(out, r#"{{"ph":"B","name":"outer","pid":1,"tid":1,"ts":0}}"#).unwrap();
+        writeln!(
+            out,
+            r#"{{"ph":"C","name":"mem","pid":1,"tid":1,"ts":0,"args":{{"v":1}}}}"#
+        )
+        .unwrap();
+        for i in 0..n {
+            let tid = 2 + i % 2;
+            writeln!(out, r#"{{"ph":"X","name":"work","pid":1,"tid":{tid},"ts":{i},"dur":1,"args":{{"i":{i}}}}}"#).unwrap();
+        }
+        writeln!(
+            out,
+            r#"{{"ph":"i","name":"last","pid":1,"tid":2,"ts":{n},"s":"t"}}"#
+        )
+        .unwrap();
+        writeln!(out, r#"{{"ph":"E","pid":1,"tid":1,"ts":{n}}}"#).unwrap();
+        out.flush().unwrap();
+        (path, n)
+    }
+
+    fn read(trace: &Path, sel: &Selection) -> Vec<Event> {
+        let reader = open_selection(trace, sel).unwrap().unwrap();
+        Events::new(reader).collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn select_blocks() {
+        let (trace, n) = write_trace("index");
+        assert!(load(&trace).unwrap().is_none());
+        let index = update(&trace, false).unwrap();
+        assert!(index.n_blocks() >= 3, "{}", index.n_blocks());
+        assert_eq!(index.n_events, n as u64 + 5);
+        assert_eq!((index.start, index.end), (Some(0.), Some(n as f64)));
+        assert!(load(&trace)
+            .unwrap()
+            .unwrap()
+            .is_complete(fs::metadata(&trace).unwrap().len()));
+
+        // a window in the middle of the trace
+        let mid = (n / 2) as f64;
+        let sel = Selection {
+            from: mid,
+            to: mid + 10.,
+            ..Selection::all()
+        };
+        let events = read(&trace, &sel);
+        assert!(events.len() < n / 2, "{}", events.len());
+        let phases: String = events
+            .iter()
+            .map(|e| e.ph)
+            .filter(|ph| *ph != 'X')
+            .collect();
+        // metadata, the span that crosses the window and the counter before it
+        assert!(phases.starts_with("MBC"), "{phases}");
+        assert!(phases.ends_with('E'), "{phases}");
+        let ts: Vec<f64> = events
+            .iter()
+            .filter(|e| e.ph == 'X')
+            .map(|e| e.ts)
+            .collect();
+        for t in (mid as usize - 1)..=(mid as usize + 10) {
+            assert!(ts.contains(&(t as f64)), "missing {t}");
+        }
+
+        // names and threads
+        let sel = Selection {
+            names: vec!["last".to_string()],
+            ..Selection::all()
+        };
+        let events = read(&trace, &sel);
+        assert!(events.iter().any(|e| e.name == "last"));
+        assert!(events.len() < n / 2);
+        let sel = Selection {
+            tids: vec![9],
+            ..Selection::all()
+        };
+        let phases: String = read(&trace, &sel).iter().map(|e| e.ph).collect();
+        assert_eq!(phases, "M");
+        let _ = fs::remove_dir_all(trace.parent().unwrap());
+    }
+
+    #[test]
+    fn appended_lines() {
+        let (trace, n) = write_trace("index-append");
+        let index = update(&trace, false).unwrap();
+        let size = index.size;
+        let mut f = fs::OpenOptions::new().append(true).open(&trace).unwrap();
+        let late = n + 100;
+        writeln!(
+            f,
+            r#"{{"ph":"i","name":"late","pid":1,"tid":2,"ts":{late},"s":"t"}}"#
+        )
+        .unwrap();
+        write!(f, r#"{{"ph":"i","name":"partial""#).unwrap();
+        drop(f);
+
+        // lines after the indexed part are always read
+        let sel = Selection {
+            names: vec!["nothing".to_string()],
+            ..Selection::all()
+        };
+        let names: Vec<String> = read(&trace, &sel).into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["process_name", "late"]);
+
+        // the partial line is left for later
+        let index = update(&trace, false).unwrap();
+        assert_eq!(index.n_events, n as u64 + 6);
+        assert!(index.size > size && index.size < fs::metadata(&trace).unwrap().len());
+
+        // an index that does not match its trace is ignored
+        fs::write(&trace, "{}\n").unwrap();
+        assert!(load(&trace).unwrap().is_none());
+        let _ = fs::remove_dir_all(trace.parent().unwrap());
+    }
+}
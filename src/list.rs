@@ -1,45 +1,82 @@
-use std::path::PathBuf;
+use std::{
+    io::stdout,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 
-use crate::{cli, utils};
+use crate::{cli, config::Config, index, sqlite, utils};
 
-pub(crate) fn list_files_in_dir(d: &str) -> Result<Vec<PathBuf>> {
+/// List trace files in the storage directory `d`, without their indexes nor
+/// the temporary files of SQLite.
+pub(crate) fn list_files(d: &Path) -> Result<Vec<PathBuf>> {
     let mut res = vec![];
+    if !d.exists() {
+        return Ok(res);
+    }
     for e in std::fs::read_dir(d)? {
         let Ok(e) = e else { continue };
         let Ok(ft) = e.file_type() else { continue };
-        if ft.is_file() {
-            res.push(e.path())
+        let path = e.path();
+        if ft.is_file() && !index::is_index_file(&path) && !sqlite::is_auxiliary_file(&path) {
+            res.push(path)
         }
     }
     Ok(res)
 }
 
-pub(crate) fn list_files_in_xdg() -> Result<Vec<PathBuf>> {
-    let xdg = xdg::BaseDirectories::with_prefix(utils::XDG_PREFIX)?;
-    Ok(xdg.list_data_files(""))
-}
-
-pub(crate) fn list_files(dir: Option<impl AsRef<str>>) -> Result<Vec<PathBuf>> {
-    match dir {
-        Some(d) => list_files_in_dir(d.as_ref()),
-        None => list_files_in_xdg(),
-    }
-}
-
-pub fn run(cli: cli::List) -> Result<()> {
-    let mut files = list_files(cli.dir.as_ref())?;
+pub fn run(cli: cli::List, config: &Config) -> Result<()> {
+    let dir = config.data_dir(cli.dir.as_ref())?;
+    let mut files = list_files(&dir)?;
 
     // deterministic order
     files.sort();
 
+    if !cli.long {
+        for f in files {
+            let Some(f) = f.as_path().to_str() else {
+                continue;
+            };
+            println!("{}", f)
+        }
+        return Ok(());
+    }
+
+    let header = ["trace", "size", "events", "duration"];
+    let mut lines: Vec<Vec<String>> = vec![header.iter().map(|s| s.to_string()).collect()];
     for f in files {
-        let Some(f) = f.as_path().to_str() else {
+        let Some(path) = f.as_path().to_str() else {
             continue;
         };
-        println!("{}", f)
+        let size = std::fs::metadata(&f)?.len();
+        // no index, or an index that is not up to date, is not an error
+        let index = index::load(&f).unwrap_or_else(|err| {
+            log::warn!("could not read the index of {f:?}: {err:#}");
+            None
+        });
+        let (events, duration) = match index {
+            _ if sqlite::is_sqlite(&f) => match sqlite::summary(&f) {
+                Ok((n_events, Some(start), Some(end))) => {
+                    (n_events.to_string(), utils::fmt_dur(end - start))
+                }
+                Ok((n_events, _, _)) => (n_events.to_string(), "-".to_string()),
+                Err(err) => {
+                    log::warn!("could not read {f:?}: {err:#}");
+                    ("-".to_string(), "-".to_string())
+                }
+            },
+            Some(index) => {
+                // the trace grew since it was indexed
+                let more = if index.is_complete(size) { "" } else { "+" };
+                let duration = match (index.start, index.end) {
+                    (Some(start), Some(end)) => utils::fmt_dur(end - start),
+                    _ => "-".to_string(),
+                };
+                (format!("{}{more}", index.n_events), duration)
+            }
+            None => ("-".to_string(), "-".to_string()),
+        };
+        lines.push(vec![path.to_string(), size.to_string(), events, duration]);
     }
-
-    Ok(())
+    utils::write_columns(&lines, 1, &mut stdout().lock())
 }
@@ -0,0 +1,66 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Size after which the log file is rotated.
+const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Number of rotated files (`foo.log.1`, `foo.log.2`, …) to keep.
+const N_KEEP: usize = 3;
+
+/// A log file that is rotated once it becomes too large.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+}
+
+fn open_append(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().append(true).create(true).open(path)
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut p = self.path.clone().into_os_string();
+        p.push(format!(".{i}"));
+        PathBuf::from(p)
+    }
+
+    /// Shift `foo.log.i` to `foo.log.{i+1}`, `foo.log` to `foo.log.1`,
+    /// and start a new `foo.log`.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for i in (1..N_KEEP).rev() {
+            let _ = fs::rename(self.rotated_path(i), self.rotated_path(i + 1));
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > MAX_SIZE {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -4,23 +4,89 @@ use env_logger::Env;
 
 mod clear;
 mod cli;
+mod config;
+mod counters;
+mod critical_path;
+mod daemon;
+mod diff;
 mod dir;
+mod event;
+mod filter;
+mod firefox;
+mod folded;
 mod get_tef;
+mod html;
+mod http;
+mod index;
 mod list;
+mod logfile;
+mod merge;
 mod msg;
+mod otlp;
+mod otlp_ingest;
+mod peer;
+mod perfetto;
+mod proto;
+mod query;
+mod rules;
+mod sample;
 mod serve;
+mod slice;
+mod spans;
+mod speedscope;
+mod sqlite;
+mod stats;
+mod status;
+mod stop;
+mod systemd;
 mod utils;
+mod wire;
 
-fn main() -> Result<()> {
-    env_logger::init_from_env(Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"));
+/// Setup logging, into a log file for the daemon if it asks for it.
+fn init_logger(cmd: &cli::Command) -> Result<()> {
+    let mut builder = env_logger::Builder::from_env(
+        Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
+    );
+
+    let log_file = match cmd {
+        cli::Command::Serve(s) if s.log_file.is_some() || s.daemonize => {
+            Some(utils::log_file_path(s.log_file.as_ref())?)
+        }
+        _ => None,
+    };
+
+    if let Some(path) = log_file {
+        let file = logfile::RotatingFile::open(&path)
+            .with_context(|| format!("opening log file {path:?}"))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
 
+    builder.init();
+    Ok(())
+}
+
+fn main() -> Result<()> {
     let cmd = cli::Command::try_parse().context("Parsing command line")?;
+    init_logger(&cmd)?;
+
+    let config = config::Config::load()?;
+
     match cmd {
-        cli::Command::List(list) => list::run(list),
-        cli::Command::Serve(serve) => serve::run(serve),
-        cli::Command::GetTEF(g) => get_tef::run(g),
-        cli::Command::Dir(d) => dir::run(d),
-        cli::Command::Clear(cl) => clear::run(cl),
+        cli::Command::List(list) => list::run(list, &config),
+        cli::Command::Serve(serve) => serve::run(serve, &config),
+        cli::Command::Stop(stop) => stop::run(stop, &config),
+        cli::Command::Status(st) => status::run(st, &config),
+        cli::Command::GetTEF(g) => get_tef::run(g, &config),
+        cli::Command::Query(q) => query::run(q, &config),
+        cli::Command::Sql(q) => sqlite::run(q, &config),
+        cli::Command::Stats(st) => stats::run(st, &config),
+        cli::Command::Counters(c) => counters::run(c, &config),
+        cli::Command::Diff(d) => diff::run(d, &config),
+        cli::Command::CriticalPath(c) => critical_path::run(c, &config),
+        cli::Command::Index(i) => index::run(i, &config),
+        cli::Command::Dir(d) => dir::run(d, &config),
+        cli::Command::Clear(cl) => clear::run(cl, &config),
+        cli::Command::Config(c) => config::run(c, &config),
     }?;
 
     Ok(())
@@ -0,0 +1,319 @@
+//! Merge several traces into one stream of events, for `get-tef` (and its
+//! `merge` alias).
+//!
+//! Inputs are read one after the other, line by line: nothing is loaded into
+//! memory. Events are only rewritten when an option changes them: pids can be
+//! moved to a separate range per input, process names can be prefixed with the
+//! trace id of their input (processes without a name get one at the end of
+//! their input), and timestamps can be shifted so that an anchor
+//! event happens at the same time in all inputs.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::{cli, event::Events, utils};
+
+/// With `--remap-pids`, the pids of the n-th input (from 0) are moved by `n * PID_STRIDE`.
+const PID_STRIDE: i64 = 10_000_000;
+
+/// A trace file, and how to rewrite its events.
+struct Input {
+    file: String,
+    pid_offset: i64,
+    /// Prefix for process names
+    prefix: Option<String>,
+    ts_shift: f64,
+}
+
+impl Input {
+    fn rewrites(&self) -> bool {
+        self.pid_offset != 0 || self.prefix.is_some() || self.ts_shift != 0.
+    }
+}
+
+/// Trace id of a trace file: its stem.
+pub fn trace_id(file: &str) -> String {
+    Path::new(file)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Timestamp of the first event named `name` in `file`.
+fn anchor_ts(file: &str, name: &str) -> Result<f64> {
+    let mut events = Events::new(utils::open_trace(Path::new(file))?);
+    for ev in &mut events {
+        let ev = ev?;
+        if ev.ph != 'M' && ev.name == name {
+            return Ok(ev.ts);
+        }
+    }
+    anyhow::bail!("No event named {name:?} in {file:?}, cannot align it");
+}
+
+/// The inputs of `get-tef`, which can be read several times.
+pub struct Inputs {
+    inputs: Vec<Input>,
+}
+
+impl Inputs {
+    pub fn new(files: Vec<String>, args: &cli::MergeArgs) -> Result<Self> {
+        let anchors = match &args.align {
+            Some(name) => files
+                .iter()
+                .map(|f| anchor_ts(f, name))
+                .collect::<Result<Vec<f64>>>()?,
+            None => vec![],
+        };
+        let inputs = files
+            .into_iter()
+            .enumerate()
+            .map(|(i, file)| Input {
+                pid_offset: if args.remap_pids {
+                    i as i64 * PID_STRIDE
+                } else {
+                    0
+                },
+                prefix: args.prefix_names.then(|| trace_id(&file)),
+                // align on the first input
+                ts_shift: anchors.get(i).map_or(0., |ts| anchors[0] - ts),
+                file,
+            })
+            .collect();
+        Ok(Self { inputs })
+    }
+
+    /// The file, if there is a single input and its events are not rewritten.
+    pub fn single_file(&self) -> Option<&str> {
+        match &self.inputs[..] {
+            [input] if !input.rewrites() => Some(&input.file),
+            _ => None,
+        }
+    }
+
+    /// Trace id for the merged trace.
+    pub fn trace_id(&self) -> String {
+        let ids: Vec<String> = self.inputs.iter().map(|i| trace_id(&i.file)).collect();
+        ids.join("+")
+    }
+
+    /// Read the events of all inputs, as jsonl.
+    pub fn open(&self) -> Result<Box<dyn BufRead + '_>> {
+        if let Some(file) = self.single_file() {
+            return utils::open_trace(Path::new(file));
+        }
+        Ok(Box::new(Merged {
+            inputs: &self.inputs,
+            next: 0,
+            current: None,
+            named: BTreeMap::new(),
+            buf: vec![],
+            pos: 0,
+        }))
+    }
+}
+
+/// Lines of several traces, one after the other, as a reader.
+struct Merged<'a> {
+    inputs: &'a [Input],
+    /// Index of the next input to open
+    next: usize,
+    current: Option<(&'a Input, Events<Box<dyn BufRead>>)>,
+    /// With a prefix, pids of the current input and whether they have a `process_name`
+    named: BTreeMap<i64, bool>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Merged<'_> {
+    /// Put the next line into `buf`. Returns false at the end of the last input.
+    fn next_line(&mut self) -> Result<bool> {
+        loop {
+            let Some((input, events)) = &mut self.current else {
+                let Some(input) = self.inputs.get(self.next) else {
+                    return Ok(false);
+                };
+                self.next += 1;
+                let reader = utils::open_trace(Path::new(&input.file))
+                    .with_context(|| format!("opening {:?}", input.file))?;
+                self.current = Some((input, Events::new(reader)));
+                continue;
+            };
+
+            let Some(r) = events.next_with_raw() else {
+                events.report_invalid();
+                let input = *input;
+                self.current = None;
+                if let Some(prefix) = &input.prefix {
+                    // processes without a name are named after their input
+                    self.buf.clear();
+                    self.pos = 0;
+                    for (pid, _) in self.named.iter().filter(|(_, named)| !**named) {
+                        let ev = json!({
+                            "ph": "M", "name": "process_name", "pid": pid, "tid": 0,
+                            "args": {"name": prefix},
+                        });
+                        serde_json::to_writer(&mut self.buf, &ev)?;
+                        self.buf.push(b'\n');
+                    }
+                    self.named.clear();
+                    if !self.buf.is_empty() {
+                        return Ok(true);
+                    }
+                }
+                continue;
+            };
+            let (mut ev, raw) = r?;
+            self.buf.clear();
+            if !input.rewrites() {
+                self.buf.extend_from_slice(raw.as_bytes());
+            } else {
+                ev.pid += input.pid_offset;
+                if ev.ph != 'M' {
+                    ev.ts += input.ts_shift;
+                }
+                let process_name = ev.ph == 'M' && ev.name == "process_name";
+                if input.prefix.is_some() {
+                    *self.named.entry(ev.pid).or_default() |= process_name;
+                }
+                if let (Some(prefix), true) = (&input.prefix, process_name) {
+                    let args = ev.args.get_or_insert_with(Default::default);
+                    let name = match args.get("name").and_then(|n| n.as_str()) {
+                        Some(name) => format!("{prefix}: {name}"),
+                        None => prefix.clone(),
+                    };
+                    args.insert("name".to_string(), Value::String(name));
+                }
+                serde_json::to_writer(&mut self.buf, &ev)?;
+            }
+            self.buf.push(b'\n');
+            self.pos = 0;
+            return Ok(true);
+        }
+    }
+}
+
+impl BufRead for Merged<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.buf.len() && !self.next_line().map_err(io::Error::other)? {
+            return Ok(&[]);
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl io::Read for Merged<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the traces into a temporary directory, and return their paths.
+    fn write_traces(name: &str, traces: &[(&str, &str)]) -> (std::path::PathBuf, Vec<String>) {
+        let dir = std::env::temp_dir().join(format!("tldrs-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = traces
+            .iter()
+            .map(|(trace_id, trace)| {
+                let path = dir.join(format!("{trace_id}.jsonl"));
+                std::fs::write(&path, trace).unwrap();
+                path.display().to_string()
+            })
+            .collect();
+        (dir, files)
+    }
+
+    fn read(inputs: &Inputs) -> Vec<Value> {
+        let mut out = String::new();
+        inputs.open().unwrap().read_to_string(&mut out).unwrap();
+        out.lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    const CLIENT: &str = r#"{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{"name":"curl"}}
+{"ph":"i","name":"send","pid":1,"tid":1,"ts":100,"s":"t"}
+"#;
+    const SERVER: &str = r#"{"ph":"X","name":"handle","pid":1,"tid":1,"ts":5000,"dur":10}
+{"ph":"i","name":"send","pid":1,"tid":1,"ts":4990,"s":"t"}
+"#;
+
+    #[test]
+    fn merge() {
+        let (dir, files) = write_traces("merge", &[("client", CLIENT), ("server", SERVER)]);
+        let args = cli::MergeArgs {
+            remap_pids: true,
+            prefix_names: true,
+            align: Some("send".to_string()),
+        };
+        let inputs = Inputs::new(files.clone(), &args).unwrap();
+        assert_eq!(inputs.trace_id(), "client+server");
+        assert!(inputs.single_file().is_none());
+
+        let events = read(&inputs);
+        let summary: Vec<(&str, i64, Option<f64>)> = events
+            .iter()
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap(),
+                    e["pid"].as_i64().unwrap(),
+                    e["ts"].as_f64(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("process_name", 1, Some(0.)),
+                ("send", 1, Some(100.)),
+                ("handle", PID_STRIDE + 1, Some(110.)),
+                ("send", PID_STRIDE + 1, Some(100.)),
+                ("process_name", PID_STRIDE + 1, None),
+            ]
+        );
+        assert_eq!(events[0]["args"]["name"], "client: curl");
+        // the server has no process name, it is named after its input
+        assert_eq!(events[4]["args"]["name"], "server");
+
+        // a single input without options is read as is
+        let args = cli::MergeArgs {
+            remap_pids: false,
+            prefix_names: false,
+            align: None,
+        };
+        let inputs = Inputs::new(files[..1].to_vec(), &args).unwrap();
+        assert_eq!(inputs.single_file(), Some(files[0].as_str()));
+        let mut out = String::new();
+        inputs.open().unwrap().read_to_string(&mut out).unwrap();
+        assert_eq!(out, CLIENT);
+
+        assert!(Inputs::new(
+            files,
+            &cli::MergeArgs {
+                align: Some("nope".into()),
+                ..args
+            }
+        )
+        .is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -4,14 +4,37 @@ pub enum Msg<'a> {
     Empty,
     Open {
         trace_id: &'a str,
+        /// `OPEN <trace-id> AS <handle>`, for clients writing several traces
+        handle: Option<&'a str>,
     },
     EmitTef {
         /// Copy the current trace as a .json, TEF formatted file in `path`
         path: &'a str,
     },
+    EmitProto {
+        /// Copy the current trace as a Perfetto protobuf file in `path`
+        path: &'a str,
+    },
     Add {
         json: &'a str,
     },
+    /// The next `n` messages are events, to be written together
+    Batch {
+        n: usize,
+    },
+    /// Client asks for its traces to be written, answered with `OK`
+    Flush {
+        /// Also wait for the data to be on disk
+        sync: bool,
+    },
+    /// Client is done with its trace
+    Close,
+    /// Client asks for the state of the daemon, answered with a line of JSON
+    Status,
+    /// Client switches to the binary protocol (see `wire`)
+    Binary {
+        version: &'a str,
+    },
     /// Client asks whole daemon to die
     Die,
     /// Client asks the whole daemon to die when it has 0 clients
@@ -21,6 +44,16 @@ pub enum Msg<'a> {
     },
 }
 
+/// Decode a line, which may start with `@<handle> ` to send the message to
+/// the trace opened as `handle`.
+pub fn decode(line: &str) -> (Option<&str>, Msg<'_>) {
+    let line = line.trim_start();
+    match line.strip_prefix('@').and_then(|l| l.split_once(' ')) {
+        Some((handle, rest)) if !handle.is_empty() => (Some(handle), decode_line(rest)),
+        _ => (None, decode_line(line)),
+    }
+}
+
 /// Decode a line.
 pub fn decode_line<'a>(line: &'a str) -> Msg<'a> {
     use Msg::*;
@@ -29,8 +62,39 @@ pub fn decode_line<'a>(line: &'a str) -> Msg<'a> {
     if line.is_empty() {
         Empty
     } else if let Some(rest) = line.strip_prefix("OPEN ") {
-        Open {
-            trace_id: rest.trim(),
+        match rest.split_once(" AS ") {
+            Some((_, handle)) if handle.trim().is_empty() || handle.trim().contains(' ') => {
+                ParseError {
+                    msg: "Expected a handle without spaces after AS",
+                }
+            }
+            Some((trace_id, handle)) => Open {
+                trace_id: trace_id.trim(),
+                handle: Some(handle.trim()),
+            },
+            None => Open {
+                trace_id: rest.trim(),
+                handle: None,
+            },
+        }
+    } else if let Some(rest) = line.strip_prefix("BATCH ") {
+        match rest.trim().parse() {
+            Ok(n) => Batch { n },
+            Err(_) => ParseError {
+                msg: "Expected a number of events after BATCH",
+            },
+        }
+    } else if line == "FLUSH" {
+        Flush { sync: false }
+    } else if line == "FLUSH SYNC" {
+        Flush { sync: true }
+    } else if line == "CLOSE" {
+        Close
+    } else if line == "STATUS" {
+        Status
+    } else if let Some(rest) = line.strip_prefix("BINARY ") {
+        Binary {
+            version: rest.trim(),
         }
     } else if line == "DIE" {
         Die
@@ -38,8 +102,10 @@ pub fn decode_line<'a>(line: &'a str) -> Msg<'a> {
         DieWhenIdle
     } else if let Some(rest) = line.strip_prefix("EMIT_TEF ") {
         EmitTef { path: rest.trim() }
+    } else if let Some(rest) = line.strip_prefix("EMIT_PROTO ") {
+        EmitProto { path: rest.trim() }
     } else if !line.is_empty() && line.as_bytes()[0] == b'{' {
-        if line.as_bytes()[line.as_bytes().len() - 1] != b'}' {
+        if line.as_bytes()[line.len() - 1] != b'}' {
             return ParseError {
                 msg: "Non closed JSON object",
             };
@@ -0,0 +1,244 @@
+//! Conversion of TEF spans to OpenTelemetry (OTLP/JSON).
+//!
+//! See `opentelemetry/proto/collector/trace/v1/trace_service.proto`
+//! and the OTLP specification for the JSON mapping.
+
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    event::{hash_str, Events},
+    spans::{Span, Spans},
+};
+
+/// `SPAN_KIND_INTERNAL`
+const SPAN_KIND_INTERNAL: u32 = 1;
+
+/// Name of the instrumentation scope for converted spans.
+const SCOPE_NAME: &str = "tldrs";
+
+/// 16 bytes trace id (as hex), derived from the tldrs trace id.
+pub fn otlp_trace_id(trace_id: &str) -> String {
+    format!(
+        "{:016x}{:016x}",
+        hash_str(trace_id),
+        hash_str(&format!("{trace_id}/otlp"))
+    )
+}
+
+/// 8 bytes span id (as hex), from the span's index in the trace.
+fn span_id(trace_id: &str, idx: usize) -> String {
+    // splitmix64 finalizer, so that consecutive spans get unrelated ids
+    let mut z = hash_str(trace_id).wrapping_add((idx as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    format!("{:016x}", z ^ (z >> 31))
+}
+
+/// TEF microseconds to OTLP nanoseconds (int64 are strings in OTLP/JSON).
+fn unix_nano(ts: f64) -> String {
+    ((ts * 1000.).round().max(0.) as u64).to_string()
+}
+
+/// Convert a JSON value to an OTLP `AnyValue`.
+pub fn any_value(v: &Value) -> Value {
+    match v {
+        Value::Null => json!({}),
+        Value::Bool(b) => json!({"boolValue": b}),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => json!({"intValue": i.to_string()}),
+            None => json!({"doubleValue": n.as_f64()}),
+        },
+        Value::String(s) => json!({"stringValue": s}),
+        Value::Array(vs) => {
+            json!({"arrayValue": {"values": vs.iter().map(any_value).collect::<Vec<_>>()}})
+        }
+        Value::Object(o) => json!({"kvlistValue": {"values": attributes(o)}}),
+    }
+}
+
+/// Convert an object into a list of OTLP `KeyValue`.
+pub fn attributes(o: &Map<String, Value>) -> Vec<Value> {
+    o.iter()
+        .map(|(k, v)| json!({"key": k, "value": any_value(v)}))
+        .collect()
+}
+
+fn otlp_span(trace_id: &str, otlp_trace_id: &str, spans: &Spans, idx: usize, span: &Span) -> Value {
+    let tid = span.tid.to_string();
+    let mut attrs = vec![json!({"key": "thread.id", "value": {"intValue": tid}})];
+    if let Some(name) = spans.thread_names.get(&(span.pid, span.tid)) {
+        attrs.push(json!({"key": "thread.name", "value": {"stringValue": name}}));
+    }
+    if !span.cat.is_empty() {
+        attrs.push(json!({"key": "tef.category", "value": {"stringValue": span.cat}}));
+    }
+    if let Some(args) = &span.args {
+        attrs.extend(attributes(args));
+    }
+
+    let mut res = json!({
+        "traceId": otlp_trace_id,
+        "spanId": span_id(trace_id, idx),
+        "name": span.name,
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": unix_nano(span.start),
+        "endTimeUnixNano": unix_nano(span.end),
+        "attributes": attrs,
+    });
+    if let Some(p) = span.parent {
+        res["parentSpanId"] = span_id(trace_id, p).into();
+    }
+    res
+}
+
+/// Build an `ExportTraceServiceRequest` for `indices` (spans of the same process are grouped).
+fn export_request(trace_id: &str, spans: &Spans, indices: &[usize]) -> Value {
+    let otlp_trace_id = otlp_trace_id(trace_id);
+
+    let mut by_pid: BTreeMap<i64, Vec<Value>> = BTreeMap::new();
+    for &i in indices {
+        let span = &spans.spans[i];
+        by_pid.entry(span.pid).or_default().push(otlp_span(
+            trace_id,
+            &otlp_trace_id,
+            spans,
+            i,
+            span,
+        ));
+    }
+
+    let resource_spans: Vec<Value> = by_pid
+        .into_iter()
+        .map(|(pid, otlp_spans)| {
+            let service = match spans.process_names.get(&pid) {
+                Some(name) => name.clone(),
+                None => format!("pid {pid}"),
+            };
+            json!({
+                "resource": {"attributes": [
+                    {"key": "service.name", "value": {"stringValue": service}},
+                    {"key": "process.pid", "value": {"intValue": pid.to_string()}},
+                    {"key": "tldrs.trace_id", "value": {"stringValue": trace_id}},
+                ]},
+                "scopeSpans": [{
+                    "scope": {"name": SCOPE_NAME},
+                    "spans": otlp_spans,
+                }],
+            })
+        })
+        .collect();
+
+    json!({"resourceSpans": resource_spans})
+}
+
+/// Reads jsonl from `reader` and writes OTLP/JSON into `writer`.
+///
+/// With `batch_size`, this writes one `ExportTraceServiceRequest` per line,
+/// each with at most `batch_size` spans, as read by the collector's
+/// `otlpjsonfile` receiver. Otherwise, a single request is written.
+pub fn emit_otlp_json(
+    trace_id: &str,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    batch_size: Option<usize>,
+) -> Result<()> {
+    let mut events = Events::new(reader);
+    let spans = Spans::from_events(&mut events)?;
+    events.report_invalid();
+
+    let indices: Vec<usize> = (0..spans.spans.len()).collect();
+    match batch_size {
+        None => {
+            let req = export_request(trace_id, &spans, &indices);
+            serde_json::to_writer(&mut *writer, &req)?;
+            writeln!(writer)?;
+        }
+        Some(n) => {
+            for batch in indices.chunks(n.max(1)) {
+                let req = export_request(trace_id, &spans, batch);
+                serde_json::to_writer(&mut *writer, &req)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = r#"{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{"name":"server"}}
+{"ph":"X","name":"request","cat":"http","pid":1,"tid":2,"ts":1000,"dur":500,"args":{"path":"/"}}
+{"ph":"X","name":"query","pid":1,"tid":2,"ts":1100,"dur":100}
+{"ph":"X","name":"other","pid":3,"tid":3,"ts":0,"dur":1}
+"#;
+
+    #[test]
+    fn spans_and_resources() {
+        let mut out = vec![];
+        emit_otlp_json("t1", &mut TRACE.as_bytes(), &mut out, None).unwrap();
+        let req: Value = serde_json::from_slice(&out).unwrap();
+
+        let rs = req["resourceSpans"].as_array().unwrap();
+        assert_eq!(rs.len(), 2);
+        assert_eq!(
+            rs[0]["resource"]["attributes"][0],
+            json!({"key": "service.name", "value": {"stringValue": "server"}})
+        );
+        assert_eq!(
+            rs[1]["resource"]["attributes"][0]["value"]["stringValue"],
+            "pid 3"
+        );
+
+        let spans = rs[0]["scopeSpans"][0]["spans"].as_array().unwrap();
+        let (request, query) = (&spans[0], &spans[1]);
+        assert_eq!(request["traceId"], otlp_trace_id("t1"));
+        assert_eq!(request["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(request["startTimeUnixNano"], "1000000");
+        assert_eq!(request["endTimeUnixNano"], "1500000");
+        assert!(request.get("parentSpanId").is_none());
+        assert_eq!(query["parentSpanId"], request["spanId"]);
+        assert_ne!(query["spanId"], request["spanId"]);
+
+        let attrs = request["attributes"].as_array().unwrap();
+        assert!(attrs.contains(&json!({"key": "tef.category", "value": {"stringValue": "http"}})));
+        assert!(attrs.contains(&json!({"key": "path", "value": {"stringValue": "/"}})));
+    }
+
+    #[test]
+    fn batches() {
+        let mut out = vec![];
+        emit_otlp_json("t1", &mut TRACE.as_bytes(), &mut out, Some(2)).unwrap();
+        let lines: Vec<Value> = out
+            .split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        // the parent link crosses batches
+        let query = &lines[0]["resourceSpans"][0]["scopeSpans"][0]["spans"][1];
+        assert_eq!(query["name"], "query");
+        assert!(query.get("parentSpanId").is_some());
+        assert_eq!(
+            lines[1]["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["name"],
+            "other"
+        );
+    }
+
+    #[test]
+    fn any_values() {
+        assert_eq!(
+            any_value(&json!({"a": [1, 1.5, true, null]})),
+            json!({"kvlistValue": {"values": [{"key": "a", "value": {"arrayValue": {"values": [
+                {"intValue": "1"}, {"doubleValue": 1.5}, {"boolValue": true}, {},
+            ]}}}]}})
+        );
+    }
+}
@@ -0,0 +1,716 @@
+//! Conversion of OTLP requests (traces and logs) into TEF events.
+//!
+//! Requests are first put in their OTLP/JSON shape: JSON bodies are used as
+//! is, protobuf bodies are decoded into the same shape. Then:
+//! - each resource becomes a process, named after `service.name`,
+//!   with the pid from `process.pid` (or a hash of the service name);
+//! - spans become `X` events;
+//! - log records become thread-scoped instant (`i`) events.
+//!
+//! Events go into the trace named by the `tldrs.trace_id` resource attribute,
+//! or after `service.name`, or [`DEFAULT_TRACE_ID`]. The OTel trace id of
+//! each span or log record is kept in `args.otel.trace_id`.
+
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::{event::hash_str, proto, utils::is_valid_trace_id};
+
+/// Trace for resources with neither `tldrs.trace_id` nor `service.name`.
+pub const DEFAULT_TRACE_ID: &str = "otlp";
+
+/// Resource attribute that picks the tldrs trace.
+const TRACE_ID_ATTR: &str = "tldrs.trace_id";
+
+/// Maximum length of the name of a log record event
+const MAX_LOG_NAME_LEN: usize = 80;
+
+/// Maximum nesting of `AnyValue`s in protobuf requests. JSON requests are
+/// limited by serde_json.
+const MAX_DEPTH: usize = 64;
+
+/// The kind of data in an OTLP request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Traces,
+    Logs,
+}
+
+/// Events of one process (resource), and the trace they go into.
+#[derive(Debug)]
+pub struct Batch {
+    pub trace_id: String,
+    pub pid: i64,
+    pub process_name: String,
+    pub events: Vec<Value>,
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Integer fields are strings or numbers in OTLP/JSON.
+fn as_u64(v: &Value) -> Option<u64> {
+    match v {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_i64(v: &Value) -> Option<i64> {
+    match v {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Convert an OTLP `AnyValue` into a plain JSON value.
+fn from_any_value(v: &Value) -> Value {
+    let Some(o) = v.as_object() else {
+        return Value::Null;
+    };
+    if let Some(s) = o.get("stringValue") {
+        s.clone()
+    } else if let Some(b) = o.get("boolValue") {
+        b.clone()
+    } else if let Some(i) = o.get("intValue") {
+        as_i64(i).map_or(Value::Null, Value::from)
+    } else if let Some(d) = o.get("doubleValue") {
+        d.clone()
+    } else if let Some(a) = o.get("arrayValue") {
+        a.get("values")
+            .and_then(|vs| vs.as_array())
+            .map_or(Value::Null, |vs| {
+                vs.iter().map(from_any_value).collect::<Vec<_>>().into()
+            })
+    } else if let Some(kv) = o.get("kvlistValue") {
+        Value::Object(from_attributes(kv.get("values")))
+    } else if let Some(b) = o.get("bytesValue") {
+        b.clone()
+    } else {
+        Value::Null
+    }
+}
+
+/// Convert a list of OTLP `KeyValue` into an object.
+fn from_attributes(attrs: Option<&Value>) -> Map<String, Value> {
+    let mut res = Map::new();
+    for kv in attrs.and_then(|a| a.as_array()).into_iter().flatten() {
+        if let Some(key) = kv.get("key").and_then(|k| k.as_str()) {
+            let value = kv.get("value").map_or(Value::Null, from_any_value);
+            res.insert(key.to_string(), value);
+        }
+    }
+    res
+}
+
+/// Trace named after a service, with the characters that are not valid in
+/// a trace id replaced.
+fn service_trace_id(service: &str) -> Option<String> {
+    let id: String = service
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .take(128)
+        .collect();
+    Some(id).filter(|id| is_valid_trace_id(id))
+}
+
+/// Process of a resource, and the trace its events go into.
+struct Resource {
+    pid: i64,
+    name: String,
+    trace_id: String,
+}
+
+impl Resource {
+    fn new(res: Option<&Value>) -> Self {
+        let attrs = from_attributes(res.and_then(|r| r.get("attributes")));
+        let service = attrs.get("service.name").and_then(|n| n.as_str());
+        let name = service.unwrap_or("otel").to_string();
+        let pid = attrs
+            .get("process.pid")
+            .and_then(as_i64)
+            .unwrap_or_else(|| (hash_str(&name) & 0x7fff_ffff) as i64);
+        let trace_id = match attrs.get(TRACE_ID_ATTR).and_then(|t| t.as_str()) {
+            Some(t) if is_valid_trace_id(t) => Some(t.to_string()),
+            Some(t) => {
+                log::warn!("Ignoring invalid {TRACE_ID_ATTR} {t:?}");
+                None
+            }
+            None => None,
+        }
+        .or_else(|| service.and_then(service_trace_id))
+        .unwrap_or_else(|| DEFAULT_TRACE_ID.to_string());
+        Resource {
+            pid,
+            name,
+            trace_id,
+        }
+    }
+}
+
+/// Non-empty string field.
+fn str_field<'a>(v: &'a Value, name: &str) -> Option<&'a str> {
+    v.get(name)
+        .and_then(|s| s.as_str())
+        .filter(|s| !s.is_empty())
+}
+
+/// Array field, or an empty slice.
+fn array<'a>(v: &'a Value, key: &str) -> &'a [Value] {
+    v.get(key)
+        .and_then(|a| a.as_array())
+        .map_or(&[], |a| a.as_slice())
+}
+
+/// Thread id: the `thread.id` attribute, or one track per OTel trace.
+fn tid(args: &Map<String, Value>, otel_trace_id: Option<&str>) -> i64 {
+    if let Some(tid) = args.get("thread.id").and_then(as_i64) {
+        return tid;
+    }
+    otel_trace_id.map_or(0, |t| (hash_str(t) & 0x7fff_ffff) as i64)
+}
+
+/// Nanoseconds since the epoch to TEF microseconds.
+fn micros(ns: u64) -> f64 {
+    ns as f64 / 1000.
+}
+
+fn span_event(span: &Value, scope: &str, pid: i64) -> Value {
+    let trace_id = str_field(span, "traceId");
+    let start = span.get("startTimeUnixNano").and_then(as_u64).unwrap_or(0);
+    let end = span
+        .get("endTimeUnixNano")
+        .and_then(as_u64)
+        .unwrap_or(start)
+        .max(start);
+
+    let mut args = from_attributes(span.get("attributes"));
+    let tid = tid(&args, trace_id);
+    if let Some(t) = trace_id {
+        args.insert("otel.trace_id".into(), t.into());
+    }
+    if let Some(s) = str_field(span, "spanId") {
+        args.insert("otel.span_id".into(), s.into());
+    }
+    if let Some(p) = str_field(span, "parentSpanId") {
+        args.insert("otel.parent_span_id".into(), p.into());
+    }
+    if let Some(kind) = span.get("kind").and_then(as_i64).filter(|&k| k > 1) {
+        args.insert("otel.kind".into(), kind.into());
+    }
+    if let Some(status) = span.get("status") {
+        // STATUS_CODE_ERROR
+        if status.get("code").and_then(as_i64) == Some(2) {
+            let msg = str_field(status, "message").unwrap_or("error");
+            args.insert("otel.status".into(), msg.into());
+        }
+    }
+
+    json!({
+        "ph": "X",
+        "name": str_field(span, "name").unwrap_or("span"),
+        "cat": scope,
+        "ts": micros(start),
+        "dur": micros(end - start),
+        "pid": pid,
+        "tid": tid,
+        "args": args,
+    })
+}
+
+fn log_event(rec: &Value, scope: &str, pid: i64) -> Value {
+    let trace_id = str_field(rec, "traceId");
+    let time = rec
+        .get("timeUnixNano")
+        .and_then(as_u64)
+        .filter(|&t| t > 0)
+        .or_else(|| rec.get("observedTimeUnixNano").and_then(as_u64))
+        .unwrap_or(0);
+
+    let mut args = from_attributes(rec.get("attributes"));
+    let tid = tid(&args, trace_id);
+    let body = rec.get("body").map_or(Value::Null, from_any_value);
+    let severity = str_field(rec, "severityText");
+
+    let name: String = match (&body, severity) {
+        (Value::String(s), _) => {
+            let line = s.lines().next().unwrap_or("");
+            line.chars().take(MAX_LOG_NAME_LEN).collect()
+        }
+        (_, Some(sev)) => sev.to_string(),
+        _ => "log".to_string(),
+    };
+
+    if !body.is_null() {
+        args.insert("body".into(), body);
+    }
+    if let Some(sev) = severity {
+        args.insert("severity".into(), sev.into());
+    }
+    if let Some(n) = rec.get("severityNumber").and_then(as_i64) {
+        args.insert("severity_number".into(), n.into());
+    }
+    if let Some(t) = trace_id {
+        args.insert("otel.trace_id".into(), t.into());
+    }
+    if let Some(s) = str_field(rec, "spanId") {
+        args.insert("otel.span_id".into(), s.into());
+    }
+
+    json!({
+        "ph": "i",
+        "s": "t",
+        "name": name,
+        "cat": scope,
+        "ts": micros(time),
+        "pid": pid,
+        "tid": tid,
+        "args": args,
+    })
+}
+
+/// Convert an OTLP request, in its JSON shape, into batches of TEF events.
+pub fn to_events(signal: Signal, req: &Value) -> Vec<Batch> {
+    let (resources_key, scopes_key, items_key, default_cat) = match signal {
+        Signal::Traces => ("resourceSpans", "scopeSpans", "spans", "otel"),
+        Signal::Logs => ("resourceLogs", "scopeLogs", "logRecords", "otel.log"),
+    };
+
+    let mut res = vec![];
+    for rs in array(req, resources_key) {
+        let resource = Resource::new(rs.get("resource"));
+        let mut events = vec![];
+
+        for ss in array(rs, scopes_key) {
+            let scope = ss
+                .get("scope")
+                .and_then(|s| str_field(s, "name"))
+                .unwrap_or(default_cat);
+            for item in array(ss, items_key) {
+                events.push(match signal {
+                    Signal::Traces => span_event(item, scope, resource.pid),
+                    Signal::Logs => log_event(item, scope, resource.pid),
+                });
+            }
+        }
+
+        if !events.is_empty() {
+            res.push(Batch {
+                trace_id: resource.trace_id,
+                pid: resource.pid,
+                process_name: resource.name,
+                events,
+            });
+        }
+    }
+    res
+}
+
+/// Parse an OTLP/JSON request body.
+pub fn decode_json(body: &[u8]) -> Result<Value> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// Decode a protobuf request body into its OTLP/JSON shape.
+pub fn decode_proto(signal: Signal, body: &[u8]) -> Result<Value> {
+    let key = match signal {
+        Signal::Traces => "resourceSpans",
+        Signal::Logs => "resourceLogs",
+    };
+    let mut resources = vec![];
+    for field in proto::Fields::new(body) {
+        if let (1, v) = field? {
+            resources.push(pb_resource_items(signal, bytes(v)?)?);
+        }
+    }
+    Ok(json!({ key: resources }))
+}
+
+fn bytes(v: proto::Value<'_>) -> Result<&[u8]> {
+    v.as_bytes()
+        .ok_or_else(|| anyhow::anyhow!("Expected a length-delimited field"))
+}
+
+fn string(v: proto::Value) -> Result<String> {
+    v.as_str()
+        .ok_or_else(|| anyhow::anyhow!("Expected a string field"))
+}
+
+fn uint(v: proto::Value) -> u64 {
+    v.as_u64().unwrap_or(0)
+}
+
+/// `ResourceSpans` or `ResourceLogs`
+fn pb_resource_items(signal: Signal, buf: &[u8]) -> Result<Value> {
+    let (scopes_key, items_key) = match signal {
+        Signal::Traces => ("scopeSpans", "spans"),
+        Signal::Logs => ("scopeLogs", "logRecords"),
+    };
+    let mut res = Map::new();
+    let mut scopes = vec![];
+    for field in proto::Fields::new(buf) {
+        match field? {
+            (1, v) => {
+                res.insert("resource".into(), pb_resource(bytes(v)?)?);
+            }
+            (2, v) => scopes.push(pb_scope_items(signal, items_key, bytes(v)?)?),
+            _ => (),
+        }
+    }
+    res.insert(scopes_key.into(), scopes.into());
+    Ok(Value::Object(res))
+}
+
+/// `ScopeSpans` or `ScopeLogs`
+fn pb_scope_items(signal: Signal, items_key: &str, buf: &[u8]) -> Result<Value> {
+    let mut res = Map::new();
+    let mut items = vec![];
+    for field in proto::Fields::new(buf) {
+        match field? {
+            (1, v) => {
+                res.insert("scope".into(), pb_scope(bytes(v)?)?);
+            }
+            (2, v) => items.push(match signal {
+                Signal::Traces => pb_span(bytes(v)?)?,
+                Signal::Logs => pb_log_record(bytes(v)?)?,
+            }),
+            _ => (),
+        }
+    }
+    res.insert(items_key.into(), items.into());
+    Ok(Value::Object(res))
+}
+
+fn pb_resource(buf: &[u8]) -> Result<Value> {
+    let mut attrs = vec![];
+    for field in proto::Fields::new(buf) {
+        if let (1, v) = field? {
+            attrs.push(pb_key_value(bytes(v)?, 0)?);
+        }
+    }
+    Ok(json!({ "attributes": attrs }))
+}
+
+/// `InstrumentationScope`
+fn pb_scope(buf: &[u8]) -> Result<Value> {
+    let mut res = Map::new();
+    for field in proto::Fields::new(buf) {
+        match field? {
+            (1, v) => {
+                res.insert("name".into(), string(v)?.into());
+            }
+            (2, v) => {
+                res.insert("version".into(), string(v)?.into());
+            }
+            _ => (),
+        }
+    }
+    Ok(Value::Object(res))
+}
+
+fn pb_key_value(buf: &[u8], depth: usize) -> Result<Value> {
+    let mut res = Map::new();
+    for field in proto::Fields::new(buf) {
+        match field? {
+            (1, v) => {
+                res.insert("key".into(), string(v)?.into());
+            }
+            (2, v) => {
+                res.insert("value".into(), pb_any_value(bytes(v)?, depth)?);
+            }
+            _ => (),
+        }
+    }
+    Ok(Value::Object(res))
+}
+
+/// Items of field 1 of a message (`ArrayValue`, `KeyValueList`), nested
+/// `depth` levels deep.
+fn pb_repeated(
+    buf: &[u8],
+    depth: usize,
+    f: impl Fn(&[u8], usize) -> Result<Value>,
+) -> Result<Vec<Value>> {
+    anyhow::ensure!(depth < MAX_DEPTH, "values nested too deeply");
+    let mut res = vec![];
+    for field in proto::Fields::new(buf) {
+        if let (1, v) = field? {
+            res.push(f(bytes(v)?, depth + 1)?);
+        }
+    }
+    Ok(res)
+}
+
+fn pb_any_value(buf: &[u8], depth: usize) -> Result<Value> {
+    let mut res = json!({});
+    for field in proto::Fields::new(buf) {
+        res = match field? {
+            (1, v) => json!({ "stringValue": string(v)? }),
+            (2, v) => json!({ "boolValue": uint(v) != 0 }),
+            (3, v) => json!({ "intValue": (uint(v) as i64).to_string() }),
+            (4, v) => json!({ "doubleValue": f64::from_bits(uint(v)) }),
+            (5, v) => {
+                let values = pb_repeated(bytes(v)?, depth, pb_any_value)?;
+                json!({ "arrayValue": { "values": values } })
+            }
+            (6, v) => {
+                let values = pb_repeated(bytes(v)?, depth, pb_key_value)?;
+                json!({ "kvlistValue": { "values": values } })
+            }
+            // hex rather than base64, this is only read back by `from_any_value`
+            (7, v) => json!({ "bytesValue": hex(bytes(v)?) }),
+            _ => res,
+        };
+    }
+    Ok(res)
+}
+
+fn pb_span(buf: &[u8]) -> Result<Value> {
+    let mut res = Map::new();
+    let mut attrs = vec![];
+    for field in proto::Fields::new(buf) {
+        let (key, value): (&str, Value) = match field? {
+            (1, v) => ("traceId", hex(bytes(v)?).into()),
+            (2, v) => ("spanId", hex(bytes(v)?).into()),
+            (4, v) => ("parentSpanId", hex(bytes(v)?).into()),
+            (5, v) => ("name", string(v)?.into()),
+            (6, v) => ("kind", uint(v).into()),
+            (7, v) => ("startTimeUnixNano", uint(v).to_string().into()),
+            (8, v) => ("endTimeUnixNano", uint(v).to_string().into()),
+            (9, v) => {
+                attrs.push(pb_key_value(bytes(v)?, 0)?);
+                continue;
+            }
+            (15, v) => ("status", pb_status(bytes(v)?)?),
+            _ => continue,
+        };
+        res.insert(key.into(), value);
+    }
+    res.insert("attributes".into(), attrs.into());
+    Ok(Value::Object(res))
+}
+
+fn pb_status(buf: &[u8]) -> Result<Value> {
+    let mut res = Map::new();
+    for field in proto::Fields::new(buf) {
+        match field? {
+            (2, v) => {
+                res.insert("message".into(), string(v)?.into());
+            }
+            (3, v) => {
+                res.insert("code".into(), uint(v).into());
+            }
+            _ => (),
+        }
+    }
+    Ok(Value::Object(res))
+}
+
+fn pb_log_record(buf: &[u8]) -> Result<Value> {
+    let mut res = Map::new();
+    let mut attrs = vec![];
+    for field in proto::Fields::new(buf) {
+        let (key, value): (&str, Value) = match field? {
+            (1, v) => ("timeUnixNano", uint(v).to_string().into()),
+            (11, v) => ("observedTimeUnixNano", uint(v).to_string().into()),
+            (2, v) => ("severityNumber", uint(v).into()),
+            (3, v) => ("severityText", string(v)?.into()),
+            (5, v) => ("body", pb_any_value(bytes(v)?, 0)?),
+            (6, v) => {
+                attrs.push(pb_key_value(bytes(v)?, 0)?);
+                continue;
+            }
+            (9, v) => ("traceId", hex(bytes(v)?).into()),
+            (10, v) => ("spanId", hex(bytes(v)?).into()),
+            _ => continue,
+        };
+        res.insert(key.into(), value);
+    }
+    res.insert("attributes".into(), attrs.into());
+    Ok(Value::Object(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A length-delimited protobuf field.
+    fn field(n: u64, payload: &[u8]) -> Vec<u8> {
+        let mut res = vec![];
+        for mut v in [n << 3 | 2, payload.len() as u64] {
+            while v >= 0x80 {
+                res.push(v as u8 | 0x80);
+                v >>= 7;
+            }
+            res.push(v as u8);
+        }
+        res.extend_from_slice(payload);
+        res
+    }
+
+    /// A traces request with a resource attribute `k` of `n` nested arrays.
+    fn nested_request(n: usize) -> Vec<u8> {
+        let mut value = field(1, b"leaf");
+        for _ in 0..n {
+            value = field(5, &field(1, &value));
+        }
+        let kv = [field(1, b"k"), field(2, &value)].concat();
+        field(1, &field(1, &field(1, &kv)))
+    }
+
+    #[test]
+    fn proto_nesting_limit() {
+        let req = decode_proto(Signal::Traces, &nested_request(10)).unwrap();
+        let attrs = &req["resourceSpans"][0]["resource"]["attributes"];
+        let mut v = from_any_value(&attrs[0]["value"]);
+        for _ in 0..10 {
+            v = v[0].clone();
+        }
+        assert_eq!(v, "leaf");
+
+        let err = decode_proto(Signal::Traces, &nested_request(1000)).unwrap_err();
+        assert!(err.to_string().contains("nested too deeply"), "{err}");
+    }
+
+    fn string_attr(k: &str, v: &str) -> Value {
+        json!({"key": k, "value": {"stringValue": v}})
+    }
+
+    #[test]
+    fn json_spans_go_into_the_service_trace() {
+        let body = json!({"resourceSpans": [{
+            "resource": {"attributes": [
+                string_attr("service.name", "my service"),
+                {"key": "process.pid", "value": {"intValue": "42"}},
+            ]},
+            "scopeSpans": [{
+                "scope": {"name": "lib"},
+                "spans": [
+                    {
+                        "traceId": "aa", "spanId": "01", "name": "a",
+                        "startTimeUnixNano": "1000", "endTimeUnixNano": 3000,
+                        "attributes": [{"key": "thread.id", "value": {"intValue": 7}}],
+                        "status": {"code": 2, "message": "boom"},
+                    },
+                    {
+                        "traceId": "bb", "spanId": "02", "parentSpanId": "01", "name": "b",
+                        "startTimeUnixNano": "2000", "endTimeUnixNano": "1000",
+                    },
+                ],
+            }],
+        }]});
+        let req = decode_json(body.to_string().as_bytes()).unwrap();
+        let batches = to_events(Signal::Traces, &req);
+        assert_eq!(batches.len(), 1);
+        let batch = &batches[0];
+        assert_eq!(batch.trace_id, "my_service");
+        assert_eq!((batch.pid, batch.process_name.as_str()), (42, "my service"));
+
+        let [a, b] = &batch.events[..] else {
+            panic!("{:?}", batch.events)
+        };
+        assert_eq!(
+            *a,
+            json!({
+                "ph": "X", "name": "a", "cat": "lib", "ts": 1.0, "dur": 2.0, "pid": 42, "tid": 7,
+                "args": {
+                    "thread.id": 7, "otel.trace_id": "aa", "otel.span_id": "01",
+                    "otel.status": "boom",
+                },
+            })
+        );
+        // one track per OTel trace, and no negative durations
+        assert_eq!(b["tid"], (hash_str("bb") & 0x7fff_ffff) as i64);
+        assert_eq!(b["dur"], 0.0);
+        assert_eq!(b["args"]["otel.trace_id"], "bb");
+        assert_eq!(b["args"]["otel.parent_span_id"], "01");
+    }
+
+    #[test]
+    fn trace_id_attribute() {
+        let request = |attrs: Value| {
+            let req = json!({"resourceLogs": [{
+                "resource": {"attributes": attrs},
+                "scopeLogs": [{"logRecords": [{
+                    "observedTimeUnixNano": "5000", "severityText": "WARN",
+                    "body": {"stringValue": "disk full\nsecond line"},
+                }]}],
+            }]});
+            to_events(Signal::Logs, &req)
+        };
+
+        let batches = request(json!([
+            string_attr("service.name", "svc"),
+            string_attr(TRACE_ID_ATTR, "run-1"),
+        ]));
+        assert_eq!(batches[0].trace_id, "run-1");
+        assert_eq!(
+            batches[0].events[0],
+            json!({
+                "ph": "i", "s": "t", "name": "disk full", "cat": "otel.log", "ts": 5.0,
+                "pid": batches[0].pid, "tid": 0,
+                "args": {"body": "disk full\nsecond line", "severity": "WARN"},
+            })
+        );
+
+        let batches = request(json!([
+            string_attr("service.name", "../svc"),
+            string_attr(TRACE_ID_ATTR, "../x"),
+        ]));
+        assert_eq!(batches[0].trace_id, "_svc");
+        assert_eq!(request(json!([]))[0].trace_id, DEFAULT_TRACE_ID);
+    }
+
+    #[test]
+    fn proto_spans() {
+        let mut kv = proto::Message::new();
+        let mut value = proto::Message::new();
+        value.int(3, -5);
+        kv.string(1, "n").message(2, &value);
+        let mut span = proto::Message::new();
+        span.bytes(1, &[0xab, 0x01])
+            .bytes(2, &[0x02])
+            .string(5, "work")
+            .fixed64(7, 10_000)
+            .fixed64(8, 12_500)
+            .message(9, &kv);
+        let mut scope = proto::Message::new();
+        scope.string(1, "lib");
+        let mut scope_spans = proto::Message::new();
+        scope_spans.message(1, &scope).message(2, &span);
+        let mut service = proto::Message::new();
+        service.string(1, "svc");
+        let mut service_kv = proto::Message::new();
+        service_kv.string(1, "service.name").message(2, &service);
+        let mut resource = proto::Message::new();
+        resource.message(1, &service_kv);
+        let mut resource_spans = proto::Message::new();
+        resource_spans
+            .message(1, &resource)
+            .message(2, &scope_spans);
+        let mut req = proto::Message::new();
+        req.message(1, &resource_spans);
+
+        let req = decode_proto(Signal::Traces, req.as_bytes()).unwrap();
+        let batches = to_events(Signal::Traces, &req);
+        assert_eq!(batches[0].trace_id, "svc");
+        let ev = &batches[0].events[0];
+        assert_eq!(ev["name"], "work");
+        assert_eq!(ev["cat"], "lib");
+        assert_eq!(
+            (ev["ts"].as_f64(), ev["dur"].as_f64()),
+            (Some(10.), Some(2.5))
+        );
+        assert_eq!(ev["args"]["n"], -5);
+        assert_eq!(ev["args"]["otel.trace_id"], "ab01");
+
+        assert!(decode_proto(Signal::Traces, &[0x0a, 0x05, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,45 @@
+use std::{
+    io, mem,
+    os::{fd::AsRawFd, unix::net::UnixStream},
+};
+
+/// Credentials of the process on the other end of a unix socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeerCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Read the peer's credentials using `SO_PEERCRED`.
+///
+/// The kernel records these when the client calls `connect`, so they
+/// can be trusted even if the client is not.
+pub fn peer_cred(sock: &UnixStream) -> io::Result<PeerCred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `cred` and `len` are valid for writes, and `len` is the size of `cred`.
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(PeerCred {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
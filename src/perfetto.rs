@@ -0,0 +1,424 @@
+//! Conversion to Perfetto's native protobuf trace format.
+//!
+//! See `protos/perfetto/trace/trace_packet.proto` and
+//! `protos/perfetto/trace/track_event/` in the Perfetto repository
+//! for the meaning of field numbers.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, Write},
+};
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::{
+    event::{hash_str, Event, Events},
+    proto::{write_varint, Message},
+};
+
+/// Field numbers, by message.
+mod field {
+    pub const TRACE_PACKET: u32 = 1;
+
+    pub const PACKET_TIMESTAMP: u32 = 8;
+    pub const PACKET_SEQUENCE_ID: u32 = 10;
+    pub const PACKET_TRACK_EVENT: u32 = 11;
+    pub const PACKET_INTERNED_DATA: u32 = 12;
+    pub const PACKET_SEQUENCE_FLAGS: u32 = 13;
+    pub const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+
+    pub const TRACK_UUID: u32 = 1;
+    pub const TRACK_NAME: u32 = 2;
+    pub const TRACK_PROCESS: u32 = 3;
+    pub const TRACK_THREAD: u32 = 4;
+    pub const TRACK_PARENT_UUID: u32 = 5;
+    pub const TRACK_COUNTER: u32 = 8;
+
+    pub const PROCESS_PID: u32 = 1;
+    pub const PROCESS_NAME: u32 = 6;
+
+    pub const THREAD_PID: u32 = 1;
+    pub const THREAD_TID: u32 = 2;
+    pub const THREAD_NAME: u32 = 5;
+
+    pub const EVENT_CATEGORY_IIDS: u32 = 3;
+    pub const EVENT_DEBUG_ANNOTATIONS: u32 = 4;
+    pub const EVENT_TYPE: u32 = 9;
+    pub const EVENT_NAME_IID: u32 = 10;
+    pub const EVENT_TRACK_UUID: u32 = 11;
+    pub const EVENT_DOUBLE_COUNTER_VALUE: u32 = 44;
+    pub const EVENT_FLOW_IDS: u32 = 47;
+    pub const EVENT_TERMINATING_FLOW_IDS: u32 = 48;
+
+    pub const ANNOTATION_BOOL: u32 = 2;
+    pub const ANNOTATION_INT: u32 = 4;
+    pub const ANNOTATION_DOUBLE: u32 = 5;
+    pub const ANNOTATION_STRING: u32 = 6;
+    pub const ANNOTATION_NAME: u32 = 10;
+    pub const ANNOTATION_DICT_ENTRIES: u32 = 11;
+    pub const ANNOTATION_ARRAY_VALUES: u32 = 12;
+
+    pub const INTERNED_CATEGORIES: u32 = 1;
+    pub const INTERNED_NAMES: u32 = 2;
+    pub const INTERNED_IID: u32 = 1;
+    pub const INTERNED_NAME: u32 = 2;
+}
+
+/// `TrackEvent.Type`
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+/// `TracePacket.SequenceFlags`
+const SEQ_INCREMENTAL_STATE_CLEARED: u64 = 1;
+const SEQ_NEEDS_INCREMENTAL_STATE: u64 = 2;
+
+/// All packets are written on this sequence.
+const SEQUENCE_ID: u64 = 1;
+
+fn process_uuid(pid: i64) -> u64 {
+    hash_str(&format!("process/{pid}"))
+}
+
+fn thread_uuid(pid: i64, tid: i64) -> u64 {
+    hash_str(&format!("thread/{pid}/{tid}"))
+}
+
+fn async_uuid(pid: i64, cat: &str, id: &str) -> u64 {
+    hash_str(&format!("async/{pid}/{cat}/{id}"))
+}
+
+fn counter_uuid(pid: i64, name: &str, id: &str) -> u64 {
+    hash_str(&format!("counter/{pid}/{name}/{id}"))
+}
+
+/// Microseconds (TEF) to nanoseconds (perfetto).
+fn ts_ns(ts: f64) -> u64 {
+    (ts * 1000.).round().max(0.) as u64
+}
+
+/// Interning table for strings.
+#[derive(Default)]
+struct Interned {
+    iids: HashMap<String, u64>,
+}
+
+impl Interned {
+    /// Get the iid for `s`. If it's new, also add its definition to `out`.
+    fn iid(&mut self, s: &str, out: &mut Vec<Message>) -> u64 {
+        if let Some(iid) = self.iids.get(s) {
+            return *iid;
+        }
+        let iid = self.iids.len() as u64 + 1;
+        self.iids.insert(s.to_string(), iid);
+
+        let mut m = Message::new();
+        m.uint(field::INTERNED_IID, iid)
+            .string(field::INTERNED_NAME, s);
+        out.push(m);
+        iid
+    }
+}
+
+/// Convert a JSON value into a debug annotation.
+fn debug_annotation(name: Option<&str>, v: &Value) -> Message {
+    let mut m = Message::new();
+    if let Some(name) = name {
+        m.string(field::ANNOTATION_NAME, name);
+    }
+    match v {
+        Value::Null => {
+            m.string(field::ANNOTATION_STRING, "null");
+        }
+        Value::Bool(b) => {
+            m.bool(field::ANNOTATION_BOOL, *b);
+        }
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => {
+                m.int(field::ANNOTATION_INT, i);
+            }
+            None => {
+                m.double(field::ANNOTATION_DOUBLE, n.as_f64().unwrap_or(0.));
+            }
+        },
+        Value::String(s) => {
+            m.string(field::ANNOTATION_STRING, s);
+        }
+        Value::Array(vs) => {
+            for v in vs {
+                m.message(field::ANNOTATION_ARRAY_VALUES, &debug_annotation(None, v));
+            }
+        }
+        Value::Object(o) => {
+            for (k, v) in o {
+                m.message(
+                    field::ANNOTATION_DICT_ENTRIES,
+                    &debug_annotation(Some(k), v),
+                );
+            }
+        }
+    }
+    m
+}
+
+/// State of the conversion.
+struct Converter<W> {
+    out: W,
+    names: Interned,
+    categories: Interned,
+    /// Tracks already described
+    tracks: HashSet<u64>,
+    /// Is the next packet the first one?
+    first_packet: bool,
+    n_skipped: usize,
+}
+
+impl<W: Write> Converter<W> {
+    /// Write a packet. `interned` is true if it uses interned strings.
+    fn write_packet(&mut self, packet: &mut Message, interned: bool) -> Result<()> {
+        packet.uint(field::PACKET_SEQUENCE_ID, SEQUENCE_ID);
+
+        let mut flags = 0;
+        if self.first_packet {
+            flags |= SEQ_INCREMENTAL_STATE_CLEARED;
+            self.first_packet = false;
+        }
+        if interned {
+            flags |= SEQ_NEEDS_INCREMENTAL_STATE;
+        }
+        if flags != 0 {
+            packet.uint(field::PACKET_SEQUENCE_FLAGS, flags);
+        }
+
+        let mut header = vec![];
+        write_varint(&mut header, ((field::TRACE_PACKET as u64) << 3) | 2);
+        write_varint(&mut header, packet.as_bytes().len() as u64);
+        self.out.write_all(&header)?;
+        self.out.write_all(packet.as_bytes())?;
+        Ok(())
+    }
+
+    fn write_track(&mut self, uuid: u64, desc: &mut Message) -> Result<()> {
+        desc.uint(field::TRACK_UUID, uuid);
+        let mut packet = Message::new();
+        packet.message(field::PACKET_TRACK_DESCRIPTOR, desc);
+        self.write_packet(&mut packet, false)
+    }
+
+    fn process_track(&mut self, pid: i64, name: Option<&str>) -> Result<u64> {
+        let uuid = process_uuid(pid);
+        if self.tracks.insert(uuid) || name.is_some() {
+            let mut process = Message::new();
+            process.int(field::PROCESS_PID, pid);
+            if let Some(name) = name {
+                process.string(field::PROCESS_NAME, name);
+            }
+            let mut desc = Message::new();
+            desc.message(field::TRACK_PROCESS, &process);
+            self.write_track(uuid, &mut desc)?;
+        }
+        Ok(uuid)
+    }
+
+    fn thread_track(&mut self, pid: i64, tid: i64, name: Option<&str>) -> Result<u64> {
+        let uuid = thread_uuid(pid, tid);
+        if !self.tracks.contains(&uuid) || name.is_some() {
+            self.process_track(pid, None)?;
+            self.tracks.insert(uuid);
+
+            let mut thread = Message::new();
+            thread
+                .int(field::THREAD_PID, pid)
+                .int(field::THREAD_TID, tid);
+            if let Some(name) = name {
+                thread.string(field::THREAD_NAME, name);
+            }
+            let mut desc = Message::new();
+            desc.uint(field::TRACK_PARENT_UUID, process_uuid(pid))
+                .message(field::TRACK_THREAD, &thread);
+            self.write_track(uuid, &mut desc)?;
+        }
+        Ok(uuid)
+    }
+
+    /// A track that is a child of the process track (async slices and counters).
+    fn child_track(&mut self, uuid: u64, pid: i64, name: &str, counter: bool) -> Result<u64> {
+        if !self.tracks.contains(&uuid) {
+            let parent = self.process_track(pid, None)?;
+            self.tracks.insert(uuid);
+
+            let mut desc = Message::new();
+            desc.uint(field::TRACK_PARENT_UUID, parent)
+                .string(field::TRACK_NAME, name);
+            if counter {
+                desc.message(field::TRACK_COUNTER, &Message::new());
+            }
+            self.write_track(uuid, &mut desc)?;
+        }
+        Ok(uuid)
+    }
+
+    /// Write a `TrackEvent` packet. `f` adds type specific fields to the event.
+    fn write_event(
+        &mut self,
+        ev: &Event,
+        ts: f64,
+        track: u64,
+        ty: u64,
+        f: impl FnOnce(&mut Message),
+    ) -> Result<()> {
+        let mut new_names = vec![];
+        let mut new_categories = vec![];
+
+        let mut tev = Message::new();
+        tev.uint(field::EVENT_TYPE, ty)
+            .uint(field::EVENT_TRACK_UUID, track);
+        if ty != TYPE_SLICE_END {
+            let name_iid = self.names.iid(&ev.name, &mut new_names);
+            tev.uint(field::EVENT_NAME_IID, name_iid);
+            for cat in ev.cat.split(',').filter(|c| !c.is_empty()) {
+                let cat_iid = self.categories.iid(cat, &mut new_categories);
+                tev.uint(field::EVENT_CATEGORY_IIDS, cat_iid);
+            }
+            if let Some(args) = &ev.args {
+                for (k, v) in args {
+                    tev.message(
+                        field::EVENT_DEBUG_ANNOTATIONS,
+                        &debug_annotation(Some(k), v),
+                    );
+                }
+            }
+        }
+        f(&mut tev);
+
+        let mut packet = Message::new();
+        packet
+            .uint(field::PACKET_TIMESTAMP, ts_ns(ts))
+            .message(field::PACKET_TRACK_EVENT, &tev);
+
+        if !new_names.is_empty() || !new_categories.is_empty() {
+            let mut interned = Message::new();
+            for m in &new_categories {
+                interned.message(field::INTERNED_CATEGORIES, m);
+            }
+            for m in &new_names {
+                interned.message(field::INTERNED_NAMES, m);
+            }
+            packet.message(field::PACKET_INTERNED_DATA, &interned);
+        }
+
+        self.write_packet(&mut packet, true)
+    }
+
+    fn add_event(&mut self, ev: &Event) -> Result<()> {
+        match ev.ph {
+            'M' => match ev.name.as_str() {
+                "process_name" => {
+                    self.process_track(ev.pid, ev.arg_str("name"))?;
+                }
+                "thread_name" => {
+                    self.thread_track(ev.pid, ev.tid, ev.arg_str("name"))?;
+                }
+                _ => (),
+            },
+            'X' => {
+                let track = self.thread_track(ev.pid, ev.tid, None)?;
+                self.write_event(ev, ev.ts, track, TYPE_SLICE_BEGIN, |_| ())?;
+                self.write_event(ev, ev.end(), track, TYPE_SLICE_END, |_| ())?;
+            }
+            'B' | 'E' => {
+                let track = self.thread_track(ev.pid, ev.tid, None)?;
+                let ty = if ev.ph == 'B' {
+                    TYPE_SLICE_BEGIN
+                } else {
+                    TYPE_SLICE_END
+                };
+                self.write_event(ev, ev.ts, track, ty, |_| ())?;
+            }
+            'i' | 'I' => {
+                let track = self.thread_track(ev.pid, ev.tid, None)?;
+                self.write_event(ev, ev.ts, track, TYPE_INSTANT, |_| ())?;
+            }
+            'b' | 'e' | 'n' => {
+                let id = ev.id_str().unwrap_or_default();
+                let uuid = async_uuid(ev.pid, &ev.cat, &id);
+                let track = self.child_track(uuid, ev.pid, &ev.name, false)?;
+                let ty = match ev.ph {
+                    'b' => TYPE_SLICE_BEGIN,
+                    'e' => TYPE_SLICE_END,
+                    _ => TYPE_INSTANT,
+                };
+                self.write_event(ev, ev.ts, track, ty, |_| ())?;
+            }
+            's' | 't' | 'f' => {
+                // flow events become instants linked by their flow id
+                let track = self.thread_track(ev.pid, ev.tid, None)?;
+                let flow_id = hash_str(&format!("{}/{}", ev.cat, ev.id_str().unwrap_or_default()));
+                let field = if ev.ph == 'f' {
+                    field::EVENT_TERMINATING_FLOW_IDS
+                } else {
+                    field::EVENT_FLOW_IDS
+                };
+                self.write_event(ev, ev.ts, track, TYPE_INSTANT, |tev| {
+                    tev.fixed64(field, flow_id);
+                })?;
+            }
+            'C' => {
+                let Some(args) = &ev.args else { return Ok(()) };
+                let id = ev.id_str().unwrap_or_default();
+                for (k, v) in args {
+                    let Some(value) = v.as_f64() else { continue };
+                    let mut name = if args.len() == 1 {
+                        ev.name.clone()
+                    } else {
+                        format!("{} {k}", ev.name)
+                    };
+                    if !id.is_empty() {
+                        name = format!("{name} id: {id}");
+                    }
+                    let uuid = counter_uuid(ev.pid, &name, &id);
+                    let track = self.child_track(uuid, ev.pid, &name, true)?;
+
+                    let mut packet = Message::new();
+                    let mut tev = Message::new();
+                    tev.uint(field::EVENT_TYPE, TYPE_COUNTER)
+                        .uint(field::EVENT_TRACK_UUID, track)
+                        .double(field::EVENT_DOUBLE_COUNTER_VALUE, value);
+                    packet
+                        .uint(field::PACKET_TIMESTAMP, ts_ns(ev.ts))
+                        .message(field::PACKET_TRACK_EVENT, &tev);
+                    self.write_packet(&mut packet, false)?;
+                }
+            }
+            _ => self.n_skipped += 1,
+        }
+        Ok(())
+    }
+}
+
+/// Reads jsonl from `reader` and writes a Perfetto protobuf trace into `writer`.
+/// Packets are written as soon as each event is read.
+pub fn emit_perfetto_proto(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()> {
+    let mut events = Events::new(reader);
+    let mut conv = Converter {
+        out: writer,
+        names: Interned::default(),
+        categories: Interned::default(),
+        tracks: HashSet::new(),
+        first_packet: true,
+        n_skipped: 0,
+    };
+
+    for ev in &mut events {
+        conv.add_event(&ev?)?;
+    }
+    conv.out.flush()?;
+
+    events.report_invalid();
+    if conv.n_skipped > 0 {
+        log::warn!("Skipped {} events of unsupported types.", conv.n_skipped);
+    }
+    Ok(())
+}
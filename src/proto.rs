@@ -0,0 +1,188 @@
+//! Minimal protobuf encoding and decoding.
+//!
+//! Messages are built into a byte buffer, field by field. Nested
+//! messages are encoded separately and added with [`Message::message`].
+//! [`Fields`] iterates over the fields of an encoded message.
+
+use anyhow::Result;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// A protobuf message being encoded.
+#[derive(Debug, Default, Clone)]
+pub struct Message {
+    buf: Vec<u8>,
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+impl Message {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u64) {
+        write_varint(&mut self.buf, ((field as u64) << 3) | wire_type);
+    }
+
+    pub fn uint(&mut self, field: u32, v: u64) -> &mut Self {
+        self.tag(field, WIRE_VARINT);
+        write_varint(&mut self.buf, v);
+        self
+    }
+
+    /// `int32` or `int64` field (not `sint`, negative numbers use 10 bytes)
+    pub fn int(&mut self, field: u32, v: i64) -> &mut Self {
+        self.uint(field, v as u64)
+    }
+
+    pub fn bool(&mut self, field: u32, v: bool) -> &mut Self {
+        self.uint(field, v as u64)
+    }
+
+    pub fn double(&mut self, field: u32, v: f64) -> &mut Self {
+        self.fixed64(field, v.to_bits())
+    }
+
+    pub fn fixed64(&mut self, field: u32, v: u64) -> &mut Self {
+        self.tag(field, WIRE_FIXED64);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, field: u32, v: &[u8]) -> &mut Self {
+        self.tag(field, WIRE_LEN);
+        write_varint(&mut self.buf, v.len() as u64);
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    pub fn string(&mut self, field: u32, v: &str) -> &mut Self {
+        self.bytes(field, v.as_bytes())
+    }
+
+    pub fn message(&mut self, field: u32, m: &Message) -> &mut Self {
+        self.bytes(field, &m.buf)
+    }
+}
+
+/// Value of a decoded field.
+#[derive(Debug, Clone, Copy)]
+pub enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    /// Bytes, string, or nested message
+    Len(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    /// Integer value of a varint or fixed field.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Varint(v) | Value::Fixed64(v) => Some(v),
+            Value::Fixed32(v) => Some(v as u64),
+            Value::Len(_) => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Value::Len(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// String value. Invalid UTF-8 is replaced.
+    pub fn as_str(&self) -> Option<String> {
+        self.as_bytes()
+            .map(|b| String::from_utf8_lossy(b).into_owned())
+    }
+}
+
+/// Iterator over the `(field number, value)` pairs of an encoded message.
+pub struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Fields { buf }
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut v: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let (&b, rest) = self
+                .buf
+                .split_first()
+                .ok_or_else(|| anyhow::anyhow!("Truncated varint"))?;
+            self.buf = rest;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(v);
+            }
+        }
+        anyhow::bail!("Varint is too long")
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            anyhow::bail!("Truncated field");
+        }
+        let (res, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(res)
+    }
+
+    fn read_field(&mut self) -> Result<(u32, Value<'a>)> {
+        let tag = self.read_varint()?;
+        let field = (tag >> 3) as u32;
+        let value = match tag & 7 {
+            WIRE_VARINT => Value::Varint(self.read_varint()?),
+            WIRE_FIXED64 => {
+                let b = self.read_bytes(8)?;
+                Value::Fixed64(u64::from_le_bytes(b.try_into().unwrap()))
+            }
+            WIRE_LEN => {
+                let n = self.read_varint()? as usize;
+                Value::Len(self.read_bytes(n)?)
+            }
+            WIRE_FIXED32 => {
+                let b = self.read_bytes(4)?;
+                Value::Fixed32(u32::from_le_bytes(b.try_into().unwrap()))
+            }
+            w => anyhow::bail!("Unsupported wire type {w} for field {field}"),
+        };
+        Ok((field, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let res = self.read_field();
+        if res.is_err() {
+            // stop after an error
+            self.buf = &[];
+        }
+        Some(res)
+    }
+}
@@ -0,0 +1,43 @@
+//! Events of a trace that match a filter expression.
+
+use std::{
+    fs,
+    io::{self, stdout, BufRead, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+
+use crate::{cli, config::Config, filter, get_tef, index, utils};
+
+pub fn run(cli: cli::Query, config: &Config) -> Result<()> {
+    let file = get_tef::find_trace_file(cli.jsonl_file, cli.dir.as_ref(), config)?;
+    log::debug!("reading trace from file {file:?}");
+    let keep_metadata = cli.format == cli::QueryFormat::Tef;
+    let path = Path::new(&file);
+    let filter = filter::compile(&cli.expr, || index::trace_start(path))?;
+    // with an index, only read the parts of the trace that can match
+    let reader: Box<dyn BufRead> = match index::open_selection(path, &filter.selection())? {
+        Some(r) => Box::new(r),
+        None => utils::open_trace(path)?,
+    };
+    let mut events = filter::Filtered::new(reader, filter, keep_metadata);
+
+    let out: Box<dyn Write> = match cli.o {
+        Some(f) => Box::new(fs::File::create(f)?),
+        None => Box::new(stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+    match cli.format {
+        cli::QueryFormat::Jsonl => {
+            io::copy(&mut events, &mut out)?;
+        }
+        cli::QueryFormat::Tef => utils::emit_tef(&mut events, &mut out)?,
+        cli::QueryFormat::Count => {
+            io::copy(&mut events.by_ref(), &mut io::sink())?;
+            writeln!(out, "{}", events.n_matched)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Rules applied by the daemon to incoming events, before they are written:
+//! drop them, or write them into another trace.
+//!
+//! Rules match events with the expressions of `tldrs query`. The first
+//! matching rule applies. `E` events follow their `B` event, and metadata
+//! events are never dropped nor routed.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{self, AtomicU64},
+};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use crate::{config, event::Event, filter::Filter, utils};
+
+/// Placeholder for the current trace id in routes.
+const TRACE_ID_PLACEHOLDER: &str = "{trace_id}";
+
+enum Action {
+    Drop,
+    /// Template of the target trace id
+    Route(String),
+}
+
+struct Rule {
+    expr: String,
+    filter: Filter,
+    action: Action,
+    /// Number of events that matched the rule
+    n_matched: AtomicU64,
+}
+
+/// What to do with an event.
+pub enum Verdict {
+    Write,
+    /// Write it, and into the traces it was routed to. Metadata events with
+    /// the same pid, tid and name replace each other.
+    Metadata((i64, i64, String)),
+    Drop,
+    /// Write into this trace instead
+    Route(String),
+}
+
+/// The rules matched by the open `B` events of each thread, for a client.
+#[derive(Default)]
+pub struct OpenSpans(HashMap<(i64, i64), Vec<Option<usize>>>);
+
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn new(rules: &[config::Rule]) -> Result<Self> {
+        let mut res = vec![];
+        for (i, r) in rules.iter().enumerate() {
+            let ctx = || format!("rule {} ({:?})", i + 1, r.expr);
+            let filter = Filter::parse(&r.expr).with_context(ctx)?;
+            if filter.uses_start() {
+                anyhow::bail!("{}: `t` and `t_end` are not available in the daemon", ctx());
+            }
+            let action = match (r.drop, &r.route) {
+                (true, None) => Action::Drop,
+                (false, Some(route))
+                    if utils::is_valid_trace_id(&route.replace(TRACE_ID_PLACEHOLDER, "x")) =>
+                {
+                    Action::Route(route.clone())
+                }
+                (false, Some(route)) => anyhow::bail!("{}: invalid route {route:?}", ctx()),
+                _ => anyhow::bail!("{}: expected either `drop = true` or `route`", ctx()),
+            };
+            res.push(Rule {
+                expr: r.expr.clone(),
+                filter,
+                action,
+                n_matched: AtomicU64::new(0),
+            });
+        }
+        Ok(Self(res))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn has_routes(&self) -> bool {
+        self.0.iter().any(|r| matches!(r.action, Action::Route(_)))
+    }
+
+    fn find(&self, ev: &Event) -> Option<usize> {
+        self.0.iter().position(|r| r.filter.matches(ev))
+    }
+
+    /// What to do with `json`, an event sent to `trace_id`.
+    pub fn apply(&self, open: &mut OpenSpans, trace_id: &str, json: &str) -> Verdict {
+        if self.is_empty() {
+            return Verdict::Write;
+        }
+        // invalid events are written as they are
+        let Ok(ev) = Event::parse(json) else {
+            return Verdict::Write;
+        };
+        let thread = (ev.pid, ev.tid);
+        let idx = match ev.ph {
+            'M' => return Verdict::Metadata((ev.pid, ev.tid, ev.name)),
+            'B' => {
+                let idx = self.find(&ev);
+                open.0.entry(thread).or_default().push(idx);
+                idx
+            }
+            'E' => match open.0.get_mut(&thread).and_then(|s| s.pop()) {
+                Some(idx) => idx,
+                None => self.find(&ev),
+            },
+            _ => self.find(&ev),
+        };
+        let Some(idx) = idx else {
+            return Verdict::Write;
+        };
+
+        let rule = &self.0[idx];
+        rule.n_matched.fetch_add(1, atomic::Ordering::Relaxed);
+        match &rule.action {
+            Action::Drop => Verdict::Drop,
+            Action::Route(r) => Verdict::Route(r.replace(TRACE_ID_PLACEHOLDER, trace_id)),
+        }
+    }
+
+    /// The rules and their counters, for `STATUS`.
+    pub fn status(&self) -> Vec<serde_json::Value> {
+        self.0
+            .iter()
+            .map(|r| {
+                let n_matched = r.n_matched.load(atomic::Ordering::Relaxed);
+                match &r.action {
+                    Action::Drop => json!({"match": r.expr, "drop": true, "matched": n_matched}),
+                    Action::Route(route) => {
+                        json!({"match": r.expr, "route": route, "matched": n_matched})
+                    }
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,643 @@
+//! Sampling of huge traces, for `get-tef --sample`.
+//!
+//! Policies:
+//! - `min-dur=<dur>` drops spans shorter than `<dur>`;
+//! - `coalesce=<dur>` replaces runs of adjacent spans shorter than `<dur>`,
+//!   with the same name and category on the same thread, by a single span
+//!   with the number of spans in `args.count`;
+//! - `counter-rate=<n>` keeps the first value of each counter series in each
+//!   1/n second;
+//! - `max-per-name=<n>` keeps at most `n` events of each name.
+//!
+//! Events are streamed. The only events held back are `B` events, until we
+//! know whether their span is short (their `E` comes early) or not, and runs
+//! of spans that are being coalesced. Their place in the output is reserved,
+//! so the order of events is preserved.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, VecDeque},
+    io::{self, BufRead, Read},
+};
+
+use anyhow::Result;
+use serde_json::{json, Map};
+
+use crate::{event::Event, event::Events, utils};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Policies {
+    min_dur: f64,
+    coalesce: f64,
+    counter_rate: Option<f64>,
+    max_per_name: usize,
+}
+
+impl Policies {
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut p = Self {
+            min_dur: 0.,
+            coalesce: 0.,
+            counter_rate: None,
+            max_per_name: usize::MAX,
+        };
+        for spec in specs {
+            let Some((key, value)) = spec.split_once('=') else {
+                anyhow::bail!("Invalid sampling policy {spec:?}, expected <policy>=<value>");
+            };
+            match key.trim() {
+                "min-dur" => p.min_dur = utils::parse_dur(value)?,
+                "coalesce" => p.coalesce = utils::parse_dur(value)?,
+                "counter-rate" => {
+                    let rate: f64 = value.trim().parse()?;
+                    if rate <= 0. {
+                        anyhow::bail!("counter-rate must be positive");
+                    }
+                    p.counter_rate = Some(rate);
+                }
+                "max-per-name" => p.max_per_name = value.trim().parse()?,
+                k => anyhow::bail!(
+                    "Unknown sampling policy {k:?}, expected one of \
+                     min-dur, coalesce, counter-rate or max-per-name"
+                ),
+            }
+        }
+        Ok(p)
+    }
+
+    /// Spans shorter than this are not written as they are.
+    fn short(&self) -> f64 {
+        self.min_dur.max(self.coalesce)
+    }
+}
+
+/// Number of events dropped by each policy.
+#[derive(Default)]
+struct Counts {
+    short: usize,
+    coalesced: usize,
+    n_groups: usize,
+    counters: usize,
+    capped: usize,
+}
+
+/// An entry of the output: lines, or a place for lines we don't know yet.
+enum Entry {
+    Waiting,
+    Done(String),
+}
+
+/// Output lines, some of them not known yet.
+struct Out {
+    queue: VecDeque<Entry>,
+    /// Index of the front of `queue` since the start
+    base: usize,
+    policies: Policies,
+    per_name: HashMap<String, usize>,
+    counts: Counts,
+}
+
+impl Out {
+    fn push(&mut self, lines: String) {
+        self.queue.push_back(Entry::Done(lines));
+    }
+
+    fn reserve(&mut self) -> usize {
+        self.queue.push_back(Entry::Waiting);
+        self.base + self.queue.len() - 1
+    }
+
+    /// Fill a reserved place, with no lines if `lines` is empty.
+    fn fill(&mut self, slot: usize, lines: String) {
+        self.queue[slot - self.base] = Entry::Done(lines);
+    }
+
+    /// Check the `max-per-name` cap, for an event that would be kept otherwise.
+    fn under_cap(&mut self, name: &str) -> bool {
+        if self.policies.max_per_name == usize::MAX {
+            return true;
+        }
+        let n = self.per_name.entry(name.to_string()).or_default();
+        if *n >= self.policies.max_per_name {
+            self.counts.capped += 1;
+            return false;
+        }
+        *n += 1;
+        true
+    }
+
+    /// Lines at the front of the queue that are known.
+    fn pop_ready(&mut self, buf: &mut Vec<u8>) {
+        while let Some(Entry::Done(_)) = self.queue.front() {
+            let Some(Entry::Done(lines)) = self.queue.pop_front() else {
+                unreachable!()
+            };
+            self.base += 1;
+            if !lines.is_empty() {
+                buf.extend_from_slice(lines.as_bytes());
+                buf.push(b'\n');
+            }
+        }
+    }
+}
+
+/// A `B` event.
+enum Open {
+    /// Might be the start of a short span
+    Pending {
+        slot: usize,
+        ev: Box<Event>,
+        raw: String,
+    },
+    Emitted,
+    Dropped,
+}
+
+/// A run of short spans being coalesced.
+struct Group {
+    slot: usize,
+    name: String,
+    cat: String,
+    pid: i64,
+    tid: i64,
+    ts: f64,
+    end: f64,
+    count: usize,
+    /// Lines of the first span, written as they are if it stays alone
+    lines: String,
+}
+
+/// When a pending `B` event of a thread becomes long, if it is still open.
+struct Deadline {
+    ts: f64,
+    thread: (i64, i64),
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ts.total_cmp(&other.ts)
+    }
+}
+
+#[derive(Default)]
+struct Thread {
+    open: Vec<Open>,
+    group: Option<Group>,
+}
+
+impl Thread {
+    /// `B` events that started before `ts - short` are not short: write them.
+    fn resolve_long(&mut self, ts: f64, out: &mut Out) {
+        let short = out.policies.short();
+        for open in &mut self.open {
+            let Open::Pending { ev, .. } = open else {
+                continue;
+            };
+            if ev.ts + short > ts {
+                continue;
+            }
+            let Open::Pending { slot, ev, raw } = std::mem::replace(open, Open::Dropped) else {
+                unreachable!()
+            };
+            if out.under_cap(&ev.name) {
+                out.fill(slot, raw);
+                *open = Open::Emitted;
+            } else {
+                out.fill(slot, String::new());
+            }
+        }
+    }
+
+    fn close_group(&mut self, out: &mut Out) {
+        let Some(g) = self.group.take() else {
+            return;
+        };
+        if !out.under_cap(&g.name) {
+            out.counts.capped += g.count - 1;
+            out.fill(g.slot, String::new());
+            return;
+        }
+        let lines = if g.count == 1 {
+            g.lines
+        } else {
+            out.counts.n_groups += 1;
+            let ev = Event {
+                name: g.name,
+                cat: g.cat,
+                ph: 'X',
+                ts: g.ts,
+                dur: Some(g.end - g.ts),
+                pid: g.pid,
+                tid: g.tid,
+                id: None,
+                args: Some(Map::from_iter([("count".to_string(), json!(g.count))])),
+                other: Map::new(),
+            };
+            serde_json::to_string(&ev).unwrap_or_default()
+        };
+        out.fill(g.slot, lines);
+    }
+
+    /// Add a short span to the current run, or start a new one. `slot` is
+    /// the place reserved for the span, if any.
+    fn coalesce(
+        &mut self,
+        ev: &Event,
+        end: f64,
+        lines: String,
+        slot: Option<usize>,
+        out: &mut Out,
+    ) {
+        if let Some(g) = &mut self.group {
+            if g.name == ev.name && g.cat == ev.cat {
+                g.count += 1;
+                g.end = g.end.max(end);
+                out.counts.coalesced += 1;
+                if let Some(slot) = slot {
+                    out.fill(slot, String::new());
+                }
+                return;
+            }
+        }
+        self.close_group(out);
+        self.group = Some(Group {
+            slot: slot.unwrap_or_else(|| out.reserve()),
+            name: ev.name.clone(),
+            cat: ev.cat.clone(),
+            pid: ev.pid,
+            tid: ev.tid,
+            ts: ev.ts,
+            end,
+            count: 1,
+            lines,
+        });
+    }
+
+    /// A complete span from `ev` to `end`, in `lines`.
+    fn span(&mut self, ev: &Event, end: f64, lines: String, slot: Option<usize>, out: &mut Out) {
+        let dur = end - ev.ts;
+        let empty = |out: &mut Out| {
+            if let Some(slot) = slot {
+                out.fill(slot, String::new());
+            }
+        };
+        if dur < out.policies.min_dur {
+            out.counts.short += 1;
+            empty(out);
+        } else if dur < out.policies.coalesce {
+            self.coalesce(ev, end, lines, slot, out);
+        } else {
+            self.close_group(out);
+            match slot {
+                Some(slot) if out.under_cap(&ev.name) => out.fill(slot, lines),
+                None if out.under_cap(&ev.name) => out.push(lines),
+                _ => empty(out),
+            }
+        }
+    }
+}
+
+/// Lines of a `.jsonl` trace, sampled, as a reader.
+pub struct Sampled<R> {
+    events: Events<R>,
+    out: Out,
+    threads: HashMap<(i64, i64), Thread>,
+    /// Deadlines of the pending `B` events of all threads, earliest first
+    deadlines: BinaryHeap<Reverse<Deadline>>,
+    /// Bucket of the last kept value of each counter series
+    counters: HashMap<(i64, String, String), i64>,
+    done: bool,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> Sampled<R> {
+    pub fn new(reader: R, policies: Policies) -> Self {
+        Self {
+            events: Events::new(reader),
+            out: Out {
+                queue: VecDeque::new(),
+                base: 0,
+                policies,
+                per_name: HashMap::new(),
+                counts: Counts::default(),
+            },
+            threads: HashMap::new(),
+            deadlines: BinaryHeap::new(),
+            counters: HashMap::new(),
+            done: false,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    /// Write the pending `B` events of all threads that are not short at `ts`,
+    /// so that a long span on a quiet thread doesn't hold back the output.
+    fn resolve_long(&mut self, ts: f64) {
+        while let Some(Reverse(d)) = self.deadlines.peek() {
+            if d.ts > ts {
+                break;
+            }
+            let thread = d.thread;
+            self.deadlines.pop();
+            if let Some(thread) = self.threads.get_mut(&thread) {
+                thread.resolve_long(ts, &mut self.out);
+            }
+        }
+    }
+
+    fn add(&mut self, ev: Event, raw: String) {
+        self.resolve_long(if ev.ph == 'X' { ev.end() } else { ev.ts });
+        let out = &mut self.out;
+        let short = out.policies.short();
+        if ev.ph == 'M' {
+            out.push(raw);
+            return;
+        }
+        if ev.ph == 'C' {
+            if let Some(rate) = out.policies.counter_rate {
+                let bucket = (ev.ts * rate / 1e6).floor() as i64;
+                let key = (ev.pid, ev.name.clone(), ev.id_str().unwrap_or_default());
+                if self.counters.insert(key, bucket) == Some(bucket) {
+                    out.counts.counters += 1;
+                    return;
+                }
+            }
+            if out.under_cap(&ev.name) {
+                out.push(raw);
+            }
+            return;
+        }
+
+        let thread = self.threads.entry((ev.pid, ev.tid)).or_default();
+        match ev.ph {
+            'X' => {
+                thread.resolve_long(ev.end(), out);
+                thread.span(&ev, ev.end(), raw, None, out);
+            }
+            'B' => {
+                thread.resolve_long(ev.ts, out);
+                let open = if short > 0. {
+                    self.deadlines.push(Reverse(Deadline {
+                        ts: ev.ts + short,
+                        thread: (ev.pid, ev.tid),
+                    }));
+                    Open::Pending {
+                        slot: out.reserve(),
+                        ev: Box::new(ev),
+                        raw,
+                    }
+                } else if out.under_cap(&ev.name) {
+                    out.push(raw);
+                    Open::Emitted
+                } else {
+                    Open::Dropped
+                };
+                thread.open.push(open);
+            }
+            'E' => {
+                match thread.open.pop() {
+                    Some(Open::Pending {
+                        slot,
+                        ev: b,
+                        raw: b_raw,
+                    }) => {
+                        let lines = format!("{b_raw}\n{raw}");
+                        thread.span(&b, ev.ts, lines, Some(slot), out);
+                    }
+                    Some(Open::Emitted) | None => out.push(raw),
+                    Some(Open::Dropped) => (),
+                }
+                thread.resolve_long(ev.ts, out);
+            }
+            _ => {
+                thread.resolve_long(ev.ts, out);
+                thread.close_group(out);
+                if out.under_cap(&ev.name) {
+                    out.push(raw);
+                }
+            }
+        }
+    }
+
+    /// Write what is still held back, at the end of the input.
+    fn finish(&mut self) {
+        for thread in self.threads.values_mut() {
+            thread.resolve_long(f64::MAX, &mut self.out);
+            thread.close_group(&mut self.out);
+        }
+        self.events.report_invalid();
+
+        let p = &self.out.policies;
+        let c = &self.out.counts;
+        let mut report = vec![];
+        if p.min_dur > 0. {
+            report.push(format!(
+                "dropped {} spans shorter than {}",
+                c.short,
+                utils::fmt_dur(p.min_dur)
+            ));
+        }
+        if p.coalesce > 0. {
+            report.push(format!(
+                "coalesced {} spans into {} spans",
+                c.coalesced + c.n_groups,
+                c.n_groups
+            ));
+        }
+        if p.counter_rate.is_some() {
+            report.push(format!("dropped {} counter values", c.counters));
+        }
+        if p.max_per_name != usize::MAX {
+            report.push(format!(
+                "dropped {} events over the limit per name",
+                c.capped
+            ));
+        }
+        if !report.is_empty() {
+            log::info!("sampling: {}", report.join(", "));
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Sampled<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos >= self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            self.out.pop_ready(&mut self.buf);
+            if !self.buf.is_empty() || self.done {
+                break;
+            }
+            match self.events.next_with_raw() {
+                None => {
+                    self.finish();
+                    self.done = true;
+                }
+                Some(Err(err)) => return Err(io::Error::other(err)),
+                Some(Ok((ev, raw))) => {
+                    let raw = raw.to_string();
+                    self.add(ev, raw);
+                }
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl<R: BufRead> Read for Sampled<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled(specs: &[&str]) -> Sampled<io::Empty> {
+        let specs: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+        Sampled::new(io::empty(), Policies::parse(&specs).unwrap())
+    }
+
+    fn add(s: &mut Sampled<io::Empty>, line: &str) {
+        s.add(Event::parse(line).unwrap(), line.to_string());
+    }
+
+    fn ready(s: &mut Sampled<io::Empty>) -> String {
+        let mut buf = vec![];
+        s.out.pop_ready(&mut buf);
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn long_span_on_quiet_thread() {
+        let mut s = sampled(&["min-dur=10us"]);
+        let main = r#"{"ph":"B","name":"main","ts":0,"pid":1,"tid":1}"#;
+        add(&mut s, main);
+        for i in 0..1000 {
+            let ts = 100 + i * 50;
+            add(
+                &mut s,
+                &format!(r#"{{"ph":"X","name":"work","ts":{ts},"dur":20,"pid":1,"tid":2}}"#),
+            );
+        }
+        // `main` is known to be long, so nothing is held back
+        assert!(s.out.queue.iter().all(|e| matches!(e, Entry::Done(_))));
+        let out = ready(&mut s);
+        assert!(out.starts_with(main));
+        assert_eq!(out.lines().count(), 1001);
+    }
+
+    #[test]
+    fn short_span_across_threads() {
+        let mut s = sampled(&["min-dur=10us"]);
+        add(&mut s, r#"{"ph":"B","name":"a","ts":0,"pid":1,"tid":1}"#);
+        add(&mut s, r#"{"ph":"i","name":"x","ts":5,"pid":1,"tid":2}"#);
+        assert_eq!(ready(&mut s), "");
+        add(&mut s, r#"{"ph":"E","ts":8,"pid":1,"tid":1}"#);
+        assert_eq!(
+            ready(&mut s),
+            "{\"ph\":\"i\",\"name\":\"x\",\"ts\":5,\"pid\":1,\"tid\":2}\n"
+        );
+        assert_eq!(s.out.counts.short, 1);
+    }
+
+    /// Sample a whole trace, and return its events.
+    fn sample(specs: &[&str], trace: &str) -> Vec<serde_json::Value> {
+        let specs: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+        let mut out = String::new();
+        Sampled::new(trace.as_bytes(), Policies::parse(&specs).unwrap())
+            .read_to_string(&mut out)
+            .unwrap();
+        out.lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn coalesce() {
+        let mut trace = String::new();
+        for i in 0..5 {
+            let ts = i * 10;
+            trace += &format!(
+                "{{\"ph\":\"X\",\"name\":\"tiny\",\"ts\":{ts},\"dur\":2,\"pid\":1,\"tid\":1}}\n"
+            );
+        }
+        trace += r#"{"ph":"X","name":"big","ts":100,"dur":50,"pid":1,"tid":1}"#;
+        trace += "\n";
+        trace += r#"{"ph":"X","name":"tiny","ts":200,"dur":2,"pid":1,"tid":1}"#;
+
+        let events = sample(&["coalesce=5us"], &trace);
+        let summary: Vec<(&str, f64, f64)> = events
+            .iter()
+            .map(|e| {
+                let name = e["name"].as_str().unwrap();
+                (name, e["ts"].as_f64().unwrap(), e["dur"].as_f64().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [("tiny", 0., 42.), ("big", 100., 50.), ("tiny", 200., 2.)]
+        );
+        assert_eq!(events[0]["args"]["count"], 5);
+        assert!(events[2].get("args").is_none());
+    }
+
+    #[test]
+    fn counters_and_names() {
+        let mut trace = String::new();
+        for i in 0..10 {
+            let ts = i * 100_000;
+            for id in [1, 2] {
+                trace += &format!(
+                    "{{\"ph\":\"C\",\"name\":\"mem\",\"id\":{id},\"ts\":{ts},\"pid\":1,\"tid\":1,\"args\":{{\"v\":{i}}}}}\n"
+                );
+            }
+        }
+        // two values per second, for each id
+        let events = sample(&["counter-rate=2"], &trace);
+        let values: Vec<(i64, i64)> = events
+            .iter()
+            .map(|e| (e["id"].as_i64().unwrap(), e["args"]["v"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(values, [(1, 0), (2, 0), (1, 5), (2, 5)]);
+
+        assert_eq!(sample(&["max-per-name=3"], &trace).len(), 3);
+        let events = sample(&["max-per-name=1", "min-dur=1ms"], &trace);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn policies() {
+        let parse = |s: &str| Policies::parse(&[s.to_string()]);
+        assert_eq!(parse("min-dur=2ms").unwrap().min_dur, 2000.);
+        assert_eq!(parse("coalesce=5").unwrap().short(), 5.);
+        assert!(parse("counter-rate=0").is_err());
+        assert!(parse("max-per-name=x").is_err());
+        assert!(parse("min-dur").is_err());
+        assert!(parse("rate=1").is_err());
+    }
+}
@@ -1,24 +1,35 @@
 use std::{
+    borrow::Cow,
     collections::{
         hash_map::{self},
-        HashMap,
+        BTreeMap, HashMap, HashSet,
     },
+    ffi::CString,
     fs,
-    io::{BufRead, BufReader, BufWriter, Read, Write},
-    os::unix::net::UnixListener,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::{fs::PermissionsExt, net::UnixListener},
+    path::Path,
     path::PathBuf,
     str::FromStr,
     sync::{
-        atomic::{self, AtomicBool},
+        atomic::{self, AtomicBool, AtomicU64},
         Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::{cli, msg, utils};
+use crate::{
+    cli,
+    config::Config,
+    daemon, http, index, msg,
+    otlp_ingest::{self, Signal},
+    peer::{self, PeerCred},
+    rules::{self, Rules, Verdict},
+    sqlite, systemd, utils, wire,
+};
 use anyhow::{Context, Result};
-use daemonize::Daemonize;
 
 /// A trace ID, used to coordinate logs/traces from multiple processes.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -30,12 +41,81 @@ impl<'a> From<&'a str> for TraceID {
     }
 }
 
+/// Where the events of a trace are written.
+enum Sink {
+    Jsonl(BufWriter<fs::File>),
+    Sqlite(sqlite::Writer),
+}
+
+impl Sink {
+    fn open(path: &Path, store: cli::Store) -> Result<(Self, u64)> {
+        match store {
+            cli::Store::Jsonl => {
+                let file = fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)?;
+                let size = file.metadata()?.len();
+                Ok((Sink::Jsonl(BufWriter::new(file)), size))
+            }
+            cli::Store::Sqlite => {
+                let writer = sqlite::Writer::open(path)?;
+                let size = writer.size()?;
+                Ok((Sink::Sqlite(writer), size))
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        match self {
+            Sink::Jsonl(out) => writeln!(out, "{}", line)?,
+            Sink::Sqlite(writer) => writer.add(line)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Sink::Jsonl(out) => out.flush()?,
+            Sink::Sqlite(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Flush, and make sure the data is on disk.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        match self {
+            Sink::Jsonl(out) => out.get_ref().sync_data()?,
+            // committed transactions are durable
+            Sink::Sqlite(_) => (),
+        }
+        Ok(())
+    }
+
+    /// Flush, and return how much of the trace can be read: a length in bytes
+    /// for jsonl, the id of the last event for sqlite.
+    fn flush_and_measure(&mut self) -> Result<u64> {
+        self.flush()?;
+        Ok(match self {
+            Sink::Jsonl(out) => out.get_ref().metadata()?.len(),
+            Sink::Sqlite(writer) => writer.last_id()? as u64,
+        })
+    }
+}
+
 struct TraceFile {
     trace_id: TraceID,
     /// The pathbuf of the trace file
     path: PathBuf,
-    /// Opened file descriptor to the file
-    out: Mutex<BufWriter<fs::File>>,
+    /// Where events are written
+    out: Mutex<Sink>,
+    /// Number of bytes written to the file so far
+    size: AtomicU64,
+    /// Number of events dropped because the file reached its maximum size
+    n_dropped: AtomicU64,
+    /// Processes whose name was written by the daemon (for OTLP resources)
+    named_pids: Mutex<HashSet<i64>>,
 }
 
 struct State {
@@ -43,27 +123,61 @@ struct State {
     die_when_idle: AtomicBool,
     /// User might have provided a single file into which all traces go
     into_file: Option<String>,
-    socket_path: PathBuf,
+    /// Fill in missing `pid` fields using the client's credentials
+    fill_pid: bool,
+    /// If non empty, only clients with one of these uids are accepted
+    allow_uids: Vec<u32>,
+    /// uid of the daemon itself
+    uid: u32,
+    /// Socket files to remove on exit. Does not contain the socket passed by systemd.
+    socket_paths: Vec<PathBuf>,
+    /// Maximum size of a trace file
+    max_trace_size: Option<u64>,
+    /// Traces not modified for this long are deleted
+    retention: Option<Duration>,
+    /// Pid file to remove on exit
+    pid_file: Option<PathBuf>,
     dir: PathBuf,
     files: Mutex<HashMap<TraceID, Arc<TraceFile>>>,
+    /// Traces written by OTLP/HTTP clients, kept open between requests
+    otlp_sessions: Mutex<HashMap<String, OtlpSession>>,
+    /// Rules applied to incoming events
+    rules: Rules,
+    /// Number of connected clients
+    n_clients: AtomicU64,
+    /// How traces are stored
+    store: cli::Store,
 }
 
 impl Drop for State {
     fn drop(&mut self) {
-        // remove socket file
-        log::debug!("removing socket file {:?}", self.socket_path);
-        let _ = fs::remove_file(&self.socket_path);
-
+        self.remove_runtime_files();
         self.close_all_force();
     }
 }
 
 impl State {
+    /// Remove socket file and pid file
+    fn remove_runtime_files(&self) {
+        for path in &self.socket_paths {
+            log::debug!("removing socket file {:?}", path);
+            let _ = fs::remove_file(path);
+        }
+        if let Some(path) = &self.pid_file {
+            log::debug!("removing pid file {:?}", path);
+            let _ = fs::remove_file(path);
+        }
+    }
+
     /// path for this trace
     fn trace_file_path(&self, trace_id: &TraceID) -> PathBuf {
         let mut path = self.dir.clone();
         {
-            let filename = format!("{}.jsonl", trace_id.0);
+            let ext = match self.store {
+                cli::Store::Jsonl => "jsonl",
+                cli::Store::Sqlite => "sqlite",
+            };
+            let filename = format!("{}.{ext}", trace_id.0);
             path.push(filename);
         }
         path
@@ -77,20 +191,26 @@ impl State {
             hash_map::Entry::Occupied(trf) => trf.get().clone(),
             hash_map::Entry::Vacant(e) => {
                 let path = match self.into_file.as_ref() {
-                    None => self.trace_file_path(&trace_id),
-                    Some(p) => PathBuf::from_str(&p)?,
+                    None => {
+                        anyhow::ensure!(
+                            utils::is_valid_trace_id(&trace_id.0),
+                            "Invalid trace_id {:?}",
+                            trace_id.0
+                        );
+                        self.trace_file_path(&trace_id)
+                    }
+                    Some(p) => PathBuf::from_str(p)?,
                 };
 
-                let file = fs::OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .write(true)
-                    .open(&path)?;
-                let out = BufWriter::new(file);
+                let (out, size) = Sink::open(&path, self.store)
+                    .with_context(|| format!("opening trace file {path:?}"))?;
                 let trf = Arc::new(TraceFile {
                     trace_id,
                     path,
                     out: Mutex::new(out),
+                    size: AtomicU64::new(size),
+                    n_dropped: AtomicU64::new(0),
+                    named_pids: Mutex::new(HashSet::new()),
                 });
 
                 e.insert(trf.clone());
@@ -100,7 +220,35 @@ impl State {
         Ok(trf)
     }
 
+    /// Delete trace files in `dir` that are too old, except for those currently open.
+    fn remove_old_traces(&self, retention: Duration) -> Result<()> {
+        let now = SystemTime::now();
+        let open_paths: Vec<PathBuf> = {
+            let files = self.files.lock().unwrap();
+            files.values().map(|f| f.path.clone()).collect()
+        };
+
+        for path in crate::list::list_files(&self.dir)? {
+            if open_paths.contains(&path) {
+                continue;
+            }
+            let Ok(modified) = fs::metadata(&path).and_then(|m| m.modified()) else {
+                continue;
+            };
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age > retention {
+                log::info!("Removing old trace file {path:?}");
+                if let Err(err) = fs::remove_file(&path) {
+                    log::error!("Could not remove {path:?}: {err:?}");
+                }
+                let _ = fs::remove_file(index::index_path(&path));
+            }
+        }
+        Ok(())
+    }
+
     fn close_all_force(&self) {
+        self.otlp_sessions.lock().unwrap().clear();
         let mut files = self.files.lock().unwrap();
         for (_, f) in files.drain() {
             if let Err(err) = {
@@ -112,8 +260,52 @@ impl State {
         }
     }
 
+    /// Is a client with these credentials allowed to connect?
+    fn is_allowed(&self, cred: Option<&PeerCred>) -> bool {
+        if self.allow_uids.is_empty() {
+            return true;
+        }
+        match cred {
+            Some(cred) => self.allow_uids.contains(&cred.uid),
+            None => false,
+        }
+    }
+
+    /// Can a client with these credentials send control messages such as `DIE`?
+    /// Only clients running as the same user as the daemon can.
+    fn can_control(&self, cred: Option<&PeerCred>) -> bool {
+        cred.is_some_and(|cred| cred.uid == self.uid)
+    }
+
+    /// State of the daemon, for `STATUS`.
+    fn status(&self) -> serde_json::Value {
+        let mut traces: Vec<serde_json::Value> = {
+            let files = self.files.lock().unwrap();
+            files
+                .values()
+                .map(|f| {
+                    serde_json::json!({
+                        "trace_id": f.trace_id.0,
+                        "path": f.path,
+                        "size": f.size.load(atomic::Ordering::Relaxed),
+                        "dropped": f.n_dropped.load(atomic::Ordering::Relaxed),
+                    })
+                })
+                .collect()
+        };
+        traces.sort_by(|a, b| a["trace_id"].as_str().cmp(&b["trace_id"].as_str()));
+        serde_json::json!({
+            "pid": std::process::id(),
+            "dir": self.dir,
+            "clients": self.n_clients.load(atomic::Ordering::Relaxed),
+            "traces": traces,
+            "rules": self.rules.status(),
+        })
+    }
+
     fn kill(&self) {
         // try to exit gracefully
+        systemd::notify("STOPPING=1");
         self.active.store(false, atomic::Ordering::SeqCst);
         self.close_all_force();
 
@@ -127,53 +319,519 @@ impl State {
 }
 
 impl TraceFile {
-    fn emit_tef(&self, path: PathBuf, len: u64) -> Result<()> {
+    /// Emit the first `len` bytes of the trace (the first `len` events for sqlite).
+    fn emit(&self, path: PathBuf, len: u64, format: cli::OutputFormat) -> Result<()> {
         log::info!(
-            "Emit a TEF trace into {path:?} for {len} bytes of trace {:?}",
+            "Emit a {format:?} trace into {path:?} for {len} bytes of trace {:?}",
             &self.trace_id
         );
 
         // open trace file, read at most `len` bytes from it
-        let file_in = fs::File::open(&self.path)?.take(len);
-        let mut reader = BufReader::new(file_in);
+        let mut reader: Box<dyn BufRead> = if sqlite::is_sqlite(&self.path) {
+            Box::new(sqlite::open_reader(&self.path, Some(len as i64))?)
+        } else {
+            Box::new(BufReader::new(fs::File::open(&self.path)?.take(len)))
+        };
 
-        // open output TEF file
+        // open output file
         let file_out = fs::File::create(&path)?;
         let mut writer = BufWriter::with_capacity(16 * 1024, file_out);
 
-        utils::emit_tef(&mut reader, &mut writer)
+        utils::emit(format, &self.trace_id.0, &mut reader, &mut writer)
     }
+
+    /// Record the credentials of a client in the trace, as a metadata event.
+    fn write_client_metadata(&self, cred: &PeerCred) -> Result<()> {
+        let ev = serde_json::json!({
+            "ph": "M",
+            "name": "tldrs_client",
+            "pid": cred.pid,
+            "tid": 0,
+            "args": {"pid": cred.pid, "uid": cred.uid, "gid": cred.gid},
+        });
+        self.write_line(&ev.to_string(), None)
+    }
+
+    /// Write a `process_name` metadata event, unless one was already written for `pid`.
+    fn write_process_name(&self, pid: i64, name: &str) -> Result<()> {
+        if !self.named_pids.lock().unwrap().insert(pid) {
+            return Ok(());
+        }
+        let ev = serde_json::json!({
+            "ph": "M",
+            "name": "process_name",
+            "pid": pid,
+            "tid": 0,
+            "args": {"name": name},
+        });
+        self.write_line(&ev.to_string(), None)
+    }
+
+    /// Append a line to the file, unless it would make the file larger than `max_size`.
+    fn write_line(&self, line: &str, max_size: Option<u64>) -> Result<()> {
+        self.write_lines([line], max_size)
+    }
+
+    /// Append lines to the file under a single lock, dropping those that would
+    /// make the file larger than `max_size`.
+    fn write_lines<'a>(
+        &self,
+        lines: impl IntoIterator<Item = &'a str>,
+        max_size: Option<u64>,
+    ) -> Result<()> {
+        let mut out = self.out.lock().unwrap();
+        let mut size = self.size.load(atomic::Ordering::Relaxed);
+        for line in lines {
+            let len = line.len() as u64 + 1;
+            if max_size.is_some_and(|max| size + len > max) {
+                if self.n_dropped.fetch_add(1, atomic::Ordering::Relaxed) == 0 {
+                    log::warn!(
+                        "Trace file {:?} reached its maximum size, dropping events",
+                        self.path
+                    );
+                }
+                continue;
+            }
+
+            out.write_line(line)?;
+            size += len;
+            self.size.store(size, atomic::Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Record that a client is done with the trace, as a metadata event.
+    fn write_complete(&self, cred: Option<&PeerCred>) -> Result<()> {
+        let pid = cred.map_or(0, |c| c.pid);
+        let ev = serde_json::json!({
+            "ph": "M",
+            "name": "tldrs_complete",
+            "pid": pid,
+            "tid": 0,
+            "args": {"pid": pid},
+        });
+        self.write_line(&ev.to_string(), None)
+    }
+}
+
+/// Add a `pid` field to `json` if it doesn't have one already.
+/// Returns `None` if the event already has a pid or cannot be parsed.
+fn fill_pid(json: &str, pid: i32) -> Option<String> {
+    let mut obj: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json).ok()?;
+    if obj.contains_key("pid") {
+        return None;
+    }
+    obj.insert("pid".to_string(), pid.into());
+    serde_json::to_string(&obj).ok()
+}
+
+/// Open the trace file for `trace_id`, recording the client's credentials in it.
+fn open_trace_file(
+    st: &State,
+    trace_id: impl Into<TraceID>,
+    cred: Option<&PeerCred>,
+) -> Result<Arc<TraceFile>> {
+    let trf = st.get_trace_file(trace_id)?;
+    if let Some(cred) = cred {
+        trf.write_client_metadata(cred)?;
+    }
+    Ok(trf)
+}
+
+/// Write the current content of `trace_file` into `path`, in the background.
+fn emit_in_background(
+    trace_file: Option<&Arc<TraceFile>>,
+    path: &str,
+    format: cli::OutputFormat,
+) -> Result<()> {
+    let path: PathBuf = PathBuf::from_str(path)?;
+    let trf = trace_file
+        .ok_or_else(|| anyhow::anyhow!("No trace file defined"))?
+        .clone();
+
+    // flush file, measure how long it is
+    let len: u64 = trf.out.lock().unwrap().flush_and_measure()?;
+
+    // emit file in the background
+    thread::spawn(move || {
+        if let Err(e) = trf.emit(path, len, format) {
+            log::error!(
+                "Error when emitting a {format:?} file for trace {:?}: {e:?}",
+                &trf.trace_id
+            )
+        }
+    });
+    Ok(())
 }
 
-fn handle_client(st: Arc<State>, mut client: impl BufRead) -> Result<()> {
-    let mut trace_file: Option<Arc<TraceFile>> = None;
+/// Events of a `BATCH` are read and written at most this many at a time.
+const MAX_BATCH_SIZE: usize = 100_000;
+
+/// OTLP/HTTP connections that send nothing for this long are closed.
+const OTLP_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Traces that receive no OTLP data for this long are released.
+const OTLP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A trace written by OTLP/HTTP clients, which do not stay connected.
+struct OtlpSession {
+    session: Session,
+    last_used: Instant,
+}
+
+/// A trace opened by a client, and the state of the rules for it.
+#[derive(Default)]
+struct Session {
+    trace_file: Option<Arc<TraceFile>>,
+    /// State of the rules for this trace
+    open_spans: rules::OpenSpans,
+    /// Traces that events were routed to, by trace id
+    routes: HashMap<String, Arc<TraceFile>>,
+    /// Latest metadata event of each pid, tid and name, copied into the
+    /// traces that events are routed to
+    metadata: BTreeMap<(i64, i64, String), String>,
+}
+
+impl Session {
+    fn new(trace_file: Arc<TraceFile>) -> Self {
+        Self {
+            trace_file: Some(trace_file),
+            ..Default::default()
+        }
+    }
+
+    /// Write events into the trace, or where the rules send them.
+    /// Events of the trace itself are written under a single lock.
+    fn add_events<'a>(
+        &mut self,
+        st: &State,
+        cred: Option<&PeerCred>,
+        events: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let trf = self
+            .trace_file
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("No trace file defined"))?;
+
+        let mut lines: Vec<Cow<'a, str>> = vec![];
+        for json in events {
+            let json = match cred {
+                Some(cred) if st.fill_pid => {
+                    fill_pid(json, cred.pid).map_or(json.into(), Cow::from)
+                }
+                _ => Cow::from(json),
+            };
+
+            match st.rules.apply(&mut self.open_spans, &trf.trace_id.0, &json) {
+                Verdict::Write => lines.push(json),
+                Verdict::Drop => (),
+                Verdict::Metadata(key) => {
+                    for route in self.routes.values() {
+                        route.write_line(&json, st.max_trace_size)?;
+                    }
+                    if st.rules.has_routes() {
+                        self.metadata.insert(key, json.to_string());
+                    }
+                    lines.push(json);
+                }
+                // a single file for all traces
+                Verdict::Route(_) if st.into_file.is_some() => lines.push(json),
+                Verdict::Route(trace_id) if !utils::is_valid_trace_id(&trace_id) => {
+                    log::warn!("Not routing to invalid trace_id={trace_id:?}");
+                    lines.push(json);
+                }
+                Verdict::Route(trace_id) => {
+                    let route = match self.routes.entry(trace_id) {
+                        hash_map::Entry::Occupied(e) => e.into_mut(),
+                        hash_map::Entry::Vacant(e) => {
+                            log::debug!("Routing events to trace_id={:?}", e.key());
+                            let route = open_trace_file(st, e.key().as_str(), cred)?;
+                            for line in self.metadata.values() {
+                                route.write_line(line, st.max_trace_size)?;
+                            }
+                            e.insert(route)
+                        }
+                    };
+                    route.write_line(&json, st.max_trace_size)?;
+                }
+            }
+        }
+        trf.write_lines(lines.iter().map(|l| l.as_ref()), st.max_trace_size)
+    }
+
+    /// Flush the traces of the session, and sync them to disk if `sync`.
+    fn flush(&self, sync: bool) -> Result<()> {
+        for tr in self.trace_file.iter().chain(self.routes.values()) {
+            let mut out = tr.out.lock().unwrap();
+            if sync { out.sync() } else { out.flush() }
+                .with_context(|| format!("flushing trace file {:?}", tr.path))?;
+        }
+        Ok(())
+    }
+
+    /// Mark the trace as complete and release the traces of the session.
+    fn close(&mut self, cred: Option<&PeerCred>) -> Result<()> {
+        if let Some(trf) = &self.trace_file {
+            log::debug!("Closing trace_id={:?}", trf.trace_id);
+            trf.write_complete(cred)?;
+        }
+        self.flush(false)?;
+        *self = Session::default();
+        Ok(())
+    }
+}
+
+/// A connected client.
+struct Client {
+    st: Arc<State>,
+    cred: Option<PeerCred>,
+    /// The trace of `OPEN <trace-id>`, for events without a handle
+    default: Session,
+    /// Traces opened with `OPEN <trace-id> AS <handle>`
+    handles: HashMap<String, Session>,
+    /// Set when the client switches to the binary protocol
+    decoder: Option<wire::Decoder>,
+    n_errors: usize,
+}
+
+impl Client {
+    /// Read the next message, and its handle, using `line` for its text.
+    /// Returns `None` at the end of the stream.
+    fn read_msg<'a>(
+        &mut self,
+        client: &mut impl BufRead,
+        line: &'a mut String,
+    ) -> Result<Option<(Option<&'a str>, msg::Msg<'a>)>> {
+        line.clear();
+        match &mut self.decoder {
+            None => match client.read_line(line) {
+                Err(e) => {
+                    log::debug!("read_line failed: {e:?}");
+                    Ok(None)
+                }
+                Ok(0) => Ok(None), // EOF
+                Ok(_) => Ok(Some(msg::decode(line))),
+            },
+            // frames can't be resynchronized, errors end the connection
+            Some(decoder) => match decoder.next(client, line) {
+                Err(e) if e.is::<io::Error>() => {
+                    log::debug!("reading frame failed: {e:?}");
+                    Ok(None)
+                }
+                res => res.context("invalid frame"),
+            },
+        }
+    }
+
+    /// The session of `handle`. Unknown handles are an error of the client,
+    /// but its other traces are still written.
+    fn session(&mut self, handle: Option<&str>) -> Option<&mut Session> {
+        match handle {
+            None => Some(&mut self.default),
+            Some(h) => {
+                let session = self.handles.get_mut(h);
+                if session.is_none() {
+                    log::error!("Unknown handle {h:?}");
+                    self.n_errors += 1;
+                }
+                session
+            }
+        }
+    }
+
+    fn open(&mut self, trace_id: &str, handle: Option<&str>) -> Result<()> {
+        let trf = open_trace_file(&self.st, trace_id, self.cred.as_ref())?;
+        // the rules start over with the new trace
+        let previous = match handle {
+            None => Some(std::mem::replace(&mut self.default, Session::new(trf))),
+            Some(h) => self.handles.insert(h.to_string(), Session::new(trf)),
+        };
+        if let Some(previous) = previous {
+            previous.flush(false)?;
+        }
+        Ok(())
+    }
+
+    fn add_events<'a>(
+        &mut self,
+        handle: Option<&str>,
+        events: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        let st = self.st.clone();
+        let cred = self.cred;
+        match self.session(handle) {
+            Some(session) => session.add_events(&st, cred.as_ref(), events),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the events of a batch, each into the trace of its handle.
+    fn add_batch(&mut self, batch: &[(Option<String>, String)]) -> Result<()> {
+        for run in batch.chunk_by(|a, b| a.0 == b.0) {
+            let events = run.iter().map(|(_, json)| json.as_str());
+            self.add_events(run[0].0.as_deref(), events)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the traces of a handle, or all the traces of the client.
+    /// Returns false if the handle is unknown.
+    fn flush(&mut self, handle: Option<&str>, sync: bool) -> Result<bool> {
+        if handle.is_some() {
+            return match self.session(handle) {
+                Some(session) => session.flush(sync).map(|()| true),
+                None => Ok(false),
+            };
+        }
+        for session in std::iter::once(&self.default).chain(self.handles.values()) {
+            session.flush(sync)?;
+        }
+        Ok(true)
+    }
+
+    fn close(&mut self, handle: Option<&str>) -> Result<()> {
+        let cred = self.cred;
+        let Some(session) = self.session(handle) else {
+            return Ok(());
+        };
+        session.close(cred.as_ref())?;
+        if let Some(h) = handle {
+            self.handles.remove(h);
+        }
+        Ok(())
+    }
+}
+
+/// Read the messages of a client until it disconnects.
+/// Returns the number of errors of the client.
+fn handle_client(
+    st: Arc<State>,
+    mut client: impl BufRead,
+    mut reply: impl Write,
+    cred: Option<PeerCred>,
+) -> Result<usize> {
+    let mut cl = Client {
+        st: st.clone(),
+        cred,
+        default: Session::default(),
+        handles: HashMap::new(),
+        decoder: None,
+        n_errors: 0,
+    };
     if st.into_file.is_some() {
         // default file, we assume the "default" trace
         let trace_id = TraceID("default".to_string());
-        trace_file = Some(st.get_trace_file(trace_id)?);
+        cl.default = Session::new(open_trace_file(&st, trace_id, cred.as_ref())?);
     }
 
-    let mut n_errors = 0;
-
     let mut line = String::new();
+    let mut batch: Vec<(Option<String>, String)> = vec![];
     loop {
         if !st.active.load(atomic::Ordering::SeqCst) {
             break;
         }
 
-        line.clear();
-        let msg = match client.read_line(&mut line) {
+        let (handle, msg) = match cl.read_msg(&mut client, &mut line) {
             Err(e) => {
-                log::debug!("read_line failed: {e:?}");
+                log::error!("{e:#}");
+                cl.n_errors += 1;
                 break;
             }
-            Ok(0) => break, // EOF
-            Ok(_) => msg::decode_line(&line),
+            Ok(None) => break,
+            Ok(Some(msg)) => msg,
         };
 
-        log::debug!("got msg {:?}", &msg);
+        log::debug!("got msg {:?} for handle {:?}", &msg, handle);
         match msg {
             msg::Msg::Empty => (),
+            msg::Msg::Add { json } => cl.add_events(handle, [json])?,
+            msg::Msg::Flush { sync } => {
+                if cl.flush(handle, sync)? {
+                    writeln!(reply, "OK")?;
+                } else {
+                    let err = serde_json::json!({"error": "unknown handle"});
+                    writeln!(reply, "{err}")?;
+                }
+            }
+            msg::Msg::Close => cl.close(handle)?,
+            msg::Msg::EmitTef { .. } | msg::Msg::EmitProto { .. }
+                if !st.can_control(cred.as_ref()) =>
+            {
+                log::warn!("client {cred:?} is not allowed to send {msg:?}");
+                cl.n_errors += 1;
+            }
+            msg::Msg::EmitTef { path } => {
+                if let Some(session) = cl.session(handle) {
+                    let trf = session.trace_file.as_ref();
+                    emit_in_background(trf, path, cli::OutputFormat::Tef)?;
+                }
+            }
+            msg::Msg::EmitProto { path } => {
+                if let Some(session) = cl.session(handle) {
+                    let trf = session.trace_file.as_ref();
+                    emit_in_background(trf, path, cli::OutputFormat::PerfettoProto)?;
+                }
+            }
+            msg::Msg::Batch { n } => {
+                // events of the batch go to its handle, unless they have their own
+                let batch_handle = handle.map(|h| h.to_string());
+                let mut remaining = n;
+                let mut eof = false;
+                while remaining > 0 && !eof {
+                    batch.clear();
+                    while batch.len() < remaining.min(MAX_BATCH_SIZE) {
+                        match cl.read_msg(&mut client, &mut line) {
+                            Ok(Some((h, msg::Msg::Add { json }))) => {
+                                let h = h.map(|h| h.to_string()).or(batch_handle.clone());
+                                batch.push((h, json.to_string()))
+                            }
+                            // blank lines, and binary frames that define strings or handles
+                            Ok(Some((_, msg::Msg::Empty))) => (),
+                            Ok(Some((_, msg))) => {
+                                log::error!("Expected an event in a batch, got {msg:?}");
+                                cl.n_errors += 1;
+                                remaining -= 1;
+                            }
+                            Ok(None) => {
+                                log::error!("Connection closed in the middle of a batch");
+                                eof = true;
+                                break;
+                            }
+                            Err(e) => {
+                                log::error!("{e:#}");
+                                cl.n_errors += 1;
+                                eof = true;
+                                break;
+                            }
+                        }
+                    }
+                    remaining -= batch.len();
+                    cl.add_batch(&batch)?;
+                }
+                if eof {
+                    break;
+                }
+            }
+            msg::Msg::ParseError { msg } => {
+                log::error!("Invalid message: {} in line {:?}", msg, line);
+                cl.n_errors += 1;
+            }
+            msg if handle.is_some() => {
+                log::error!("{msg:?} cannot be sent to a handle");
+                cl.n_errors += 1;
+            }
+            msg::Msg::Status if !st.can_control(cred.as_ref()) => {
+                log::warn!("client {cred:?} is not allowed to send {msg:?}");
+                let err = serde_json::json!({"error": "not allowed"});
+                writeln!(reply, "{err}")?;
+            }
+            msg::Msg::Status => {
+                writeln!(reply, "{}", st.status())?;
+            }
+            msg::Msg::Die | msg::Msg::DieWhenIdle if !st.can_control(cred.as_ref()) => {
+                log::warn!("client {cred:?} is not allowed to send {msg:?}");
+                cl.n_errors += 1;
+            }
             msg::Msg::Die => {
                 log::info!("client asked us to quit");
                 st.kill();
@@ -182,69 +840,104 @@ fn handle_client(st: Arc<State>, mut client: impl BufRead) -> Result<()> {
             msg::Msg::DieWhenIdle => {
                 st.die_when_idle.store(true, atomic::Ordering::SeqCst);
             }
-            msg::Msg::Open { trace_id } => {
-                log::debug!("Opening trace file for trace_id={trace_id:?}");
-                trace_file = Some(st.get_trace_file(trace_id)?);
+            msg::Msg::Binary { version } if cl.decoder.is_none() && version == wire::VERSION => {
+                log::debug!("Client switches to the binary protocol");
+                writeln!(reply, "BINARY {version}")?;
+                cl.decoder = Some(wire::Decoder::default());
             }
-            msg::Msg::Add { json } => {
-                let trf = trace_file
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("No trace file defined"))?;
-
-                let mut out = trf.out.lock().unwrap();
-                writeln!(out, "{}", json)?;
+            msg::Msg::Binary { version } => {
+                log::error!("Unsupported binary protocol {version:?}");
+                let err = serde_json::json!({"error": "unsupported protocol"});
+                writeln!(reply, "{err}")?;
+                cl.n_errors += 1;
             }
-            msg::Msg::EmitTef { path } => {
-                let path: PathBuf = PathBuf::from_str(path)?;
-                let trf = trace_file
-                    .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("No trace file defined"))?
-                    .clone();
-
-                // flush file, measure how long it is
-                let len: u64 = {
-                    let mut out = trf.out.lock().unwrap();
-                    out.flush()?;
-
-                    let file = out.get_ref();
-                    file.metadata().unwrap().len()
-                };
-
-                // emit file in the background
-                thread::spawn(move || {
-                    if let Err(e) = trf.emit_tef(path, len) {
-                        log::error!(
-                            "Error when emitting a TEF file for trace {:?}: {e:?}",
-                            &trf.trace_id
-                        )
-                    }
-                });
+            msg::Msg::Open { trace_id, .. } if !utils::is_valid_trace_id(trace_id) => {
+                log::error!("Invalid trace_id {trace_id:?}");
+                cl.n_errors += 1;
             }
-            msg::Msg::ParseError { msg } => {
-                log::error!("Invalid message: {} in line {:?}", msg, line);
-                n_errors += 1;
+            msg::Msg::Open { trace_id, handle } => {
+                log::debug!("Opening trace file for trace_id={trace_id:?} (handle {handle:?})");
+                cl.open(trace_id, handle)?;
             }
         }
     }
 
-    if n_errors > 0 {
-        log::warn!("Client exiting (met {n_errors} parsing errors)");
+    if cl.n_errors > 0 {
+        log::warn!("Client exiting (met {} parsing errors)", cl.n_errors);
     } else {
         log::debug!("Client exiting (no parsing errors)");
     }
 
-    if let Some(tr) = trace_file {
-        // flush on exit
-        let mut out = tr.out.lock().unwrap();
-        out.flush().context("flushing trace file")?;
+    // flush on exit
+    cl.flush(None, false)?;
+    Ok(cl.n_errors)
+}
+
+/// Find the gid for `group`, which is either a group name or a numeric gid.
+fn lookup_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)?;
+    // SAFETY: `name` is a valid C string. The result is only read before
+    // any other call to `getgr*`.
+    let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+    if grp.is_null() {
+        anyhow::bail!("Unknown group {group:?}");
     }
+    Ok(unsafe { (*grp).gr_gid })
+}
+
+/// Set mode and group of the socket file.
+fn set_socket_permissions(path: &Path, mode: Option<&str>, group: Option<&str>) -> Result<()> {
+    let mode = match mode {
+        Some(m) => u32::from_str_radix(m.trim_start_matches("0o"), 8)
+            .with_context(|| format!("Invalid socket mode {m:?}"))?,
+        None if group.is_some() => 0o660,
+        None => 0o600,
+    };
 
+    if let Some(group) = group {
+        let gid = lookup_group(group)?;
+        log::debug!("setting group of socket to {gid}");
+        std::os::unix::fs::chown(path, None, Some(gid))?;
+    }
+
+    log::debug!("setting mode of socket to {mode:o}");
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
     Ok(())
 }
 
 /// Regularly flush files and close unused files.
 fn cleaner_thread(st: Arc<State>) {
+    // how often we look for old traces to delete
+    const RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+    let mut last_retention_check: Option<SystemTime> = None;
+
     while st.active.load(atomic::Ordering::SeqCst) {
+        if let Some(retention) = st.retention {
+            let now = SystemTime::now();
+            let check = last_retention_check.map_or(true, |t| {
+                now.duration_since(t).unwrap_or_default() > RETENTION_CHECK_INTERVAL
+            });
+            if check {
+                last_retention_check = Some(now);
+                if let Err(err) = st.remove_old_traces(retention) {
+                    log::error!("Error while removing old traces: {err:?}");
+                }
+            }
+        }
+
+        // OTLP clients do not stay connected, their traces are released once idle
+        st.otlp_sessions.lock().unwrap().retain(|trace_id, s| {
+            let keep = s.last_used.elapsed() < OTLP_IDLE_TIMEOUT;
+            if !keep {
+                log::debug!("OTLP trace_id={trace_id:?} is idle");
+            }
+            keep
+        });
+
         // collect copies of all files
         let mut files = vec![];
 
@@ -254,7 +947,7 @@ fn cleaner_thread(st: Arc<State>) {
 
             let mut tbl = st.files.lock().unwrap();
             for (_, file) in tbl.iter() {
-                if Arc::strong_count(&file) == 1 {
+                if Arc::strong_count(file) == 1 {
                     // only copy of `f`, no client is currently using it
                     dead_files.push(file.clone());
                 } else {
@@ -268,12 +961,31 @@ fn cleaner_thread(st: Arc<State>) {
                 tbl.remove(&file.trace_id);
                 log::info!("Closing file for trace_id={:?}", file.trace_id);
 
+                let n_dropped = file.n_dropped.load(atomic::Ordering::Relaxed);
+                if n_dropped > 0 {
+                    log::warn!(
+                        "Dropped {n_dropped} events for trace_id={:?} (file too large)",
+                        file.trace_id
+                    );
+                }
+
                 if let Err(err) = {
                     let mut out = file.out.lock().unwrap();
                     out.flush()
                 } {
                     log::error!("Error while flushing {:?}: {:?}", file.path, err)
                 }
+
+                if st.store != cli::Store::Jsonl {
+                    continue;
+                }
+                // index the trace in the background, it can be large
+                let path = file.path.clone();
+                thread::spawn(move || {
+                    if let Err(err) = index::update(&path, false) {
+                        log::error!("Error while indexing {path:?}: {err:?}")
+                    }
+                });
             }
         }
 
@@ -297,47 +1009,339 @@ fn cleaner_thread(st: Arc<State>) {
     }
 }
 
-pub fn run(cli: cli::Serve) -> Result<()> {
-    if cli.daemonize {
-        Daemonize::new().start().context("daemonizing")?;
-    }
+/// Create a unix socket at `socket_path`.
+fn bind(cli: &cli::Serve, socket_path: &Path) -> Result<UnixListener> {
+    log::info!("serving on unix socket {socket_path:?}");
 
-    let dir: PathBuf = match cli.dir {
+    // try to remove file, if already present
+    let _ = std::fs::remove_file(socket_path);
+
+    // create the socket as `0600`, so that nobody can connect before its
+    // permissions are set. The umask is per process, but no other thread
+    // creates files yet.
+    // SAFETY: `umask` cannot fail.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket_path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("binding unix socket {socket_path:?}"))?;
+    set_socket_permissions(
+        socket_path,
+        cli.socket_mode.as_deref(),
+        cli.socket_group.as_deref(),
+    )
+    .context("setting socket permissions")?;
+    Ok(listener)
+}
+
+/// Listen on the socket passed by systemd if we're socket activated,
+/// otherwise create our own at `socket_path`. Also listen on `extra_sockets`.
+/// Returns the listeners and the socket files to remove on exit.
+fn listen(
+    cli: &cli::Serve,
+    socket_path: PathBuf,
+    extra_sockets: Vec<PathBuf>,
+) -> Result<(Vec<UnixListener>, Vec<PathBuf>)> {
+    let mut listeners = vec![];
+    let mut socket_paths = vec![];
+
+    match systemd::take_listener().context("getting socket from systemd")? {
+        Some(listener) => {
+            log::info!("serving on unix socket passed by systemd");
+            if cli.socket_mode.is_some() || cli.socket_group.is_some() {
+                log::warn!(
+                    "--socket-mode and --socket-group are ignored for the socket passed by \
+                     systemd, set SocketMode= and SocketGroup= in its .socket unit instead"
+                );
+            }
+            listeners.push(listener);
+        }
         None => {
-            let xdg = xdg::BaseDirectories::with_prefix(utils::XDG_PREFIX)?;
-            let d = xdg.create_data_directory("")?;
-            d
+            listeners.push(bind(cli, &socket_path)?);
+            socket_paths.push(socket_path);
+        }
+    }
+
+    for path in extra_sockets {
+        listeners.push(bind(cli, &path)?);
+        socket_paths.push(path);
+    }
+
+    Ok((listeners, socket_paths))
+}
+
+/// Accept clients on `listener` until it fails.
+fn accept_loop(st: Arc<State>, listener: UnixListener) {
+    loop {
+        let (client, client_addr) = match listener.accept() {
+            Ok(x) => x,
+            Err(err) => {
+                log::info!("could not accept more clients: {:?}", err);
+                break;
+            }
+        };
+
+        let cred = match peer::peer_cred(&client) {
+            Ok(cred) => {
+                log::debug!("new client {cred:?}");
+                Some(cred)
+            }
+            Err(err) => {
+                log::warn!("could not get credentials of client on {client_addr:?}: {err:?}");
+                None
+            }
+        };
+
+        if !st.is_allowed(cred.as_ref()) {
+            log::warn!("rejecting client with credentials {cred:?}");
+            continue;
         }
-        Some(d) => {
-            let d = PathBuf::from_str(&d)?;
-            d
+
+        let st2 = st.clone();
+        thread::spawn(move || {
+            st2.n_clients.fetch_add(1, atomic::Ordering::Relaxed);
+            let res = client
+                .try_clone()
+                .map_err(anyhow::Error::from)
+                .and_then(|reply| handle_client(st2.clone(), BufReader::new(client), reply, cred));
+            if let Err(e) = res {
+                log::error!("while handling client on {client_addr:?}, got error: {e:?}")
+            }
+            st2.n_clients.fetch_sub(1, atomic::Ordering::Relaxed);
+        });
+    }
+}
+
+/// Write the events of an OTLP request into their trace files.
+fn ingest_otlp(st: &State, signal: Signal, req: &serde_json::Value) -> Result<()> {
+    let mut sessions = st.otlp_sessions.lock().unwrap();
+    for batch in otlp_ingest::to_events(signal, req) {
+        let trace_id = match st.into_file {
+            Some(_) => "default",
+            None => batch.trace_id.as_str(),
+        };
+        // rules apply as for clients of the socket
+        let otlp = match sessions.entry(trace_id.to_string()) {
+            hash_map::Entry::Occupied(e) => e.into_mut(),
+            hash_map::Entry::Vacant(e) => e.insert(OtlpSession {
+                session: Session::new(st.get_trace_file(trace_id)?),
+                last_used: Instant::now(),
+            }),
+        };
+        otlp.last_used = Instant::now();
+        let session = &mut otlp.session;
+        if let Some(trf) = &session.trace_file {
+            trf.write_process_name(batch.pid, &batch.process_name)?;
+        }
+        let lines: Vec<String> = batch.events.iter().map(|ev| ev.to_string()).collect();
+        session.add_events(st, None, lines.iter().map(|l| l.as_str()))?;
+        for route in session.routes.values() {
+            route.write_process_name(batch.pid, &batch.process_name)?;
         }
+    }
+    Ok(())
+}
+
+/// Answer a single OTLP/HTTP request, returning the status and body of the response.
+fn handle_otlp_request(st: &State, req: &http::Request) -> (u16, &'static str, Vec<u8>) {
+    let signal = match req.path.as_str() {
+        "/v1/traces" => Signal::Traces,
+        "/v1/logs" => Signal::Logs,
+        _ => return (404, "Not Found", vec![]),
     };
+    if req.method != "POST" {
+        return (405, "Method Not Allowed", vec![]);
+    }
+    if req
+        .header("content-encoding")
+        .is_some_and(|e| !e.eq_ignore_ascii_case("identity"))
+    {
+        return (
+            415,
+            "Unsupported Media Type",
+            b"compression is not supported".to_vec(),
+        );
+    }
 
-    log::info!("data directory is {:?}", &dir);
+    let content_type = req.header("content-type").unwrap_or("");
+    let decoded = if content_type.starts_with("application/json") {
+        otlp_ingest::decode_json(&req.body)
+    } else if content_type.starts_with("application/x-protobuf") {
+        otlp_ingest::decode_proto(signal, &req.body)
+    } else {
+        return (415, "Unsupported Media Type", vec![]);
+    };
+    let decoded = match decoded {
+        Ok(d) => d,
+        Err(err) => {
+            log::warn!("Invalid OTLP request on {}: {err:#}", req.path);
+            return (400, "Bad Request", format!("{err}").into_bytes());
+        }
+    };
 
-    let socket_path: PathBuf = match cli.unix_socket {
-        Some(d) => PathBuf::from_str(&d)?,
-        None => {
-            let mut path = std::env::temp_dir();
-            path.push("tldrs.socket");
-            path
+    if let Err(err) = ingest_otlp(st, signal, &decoded) {
+        log::error!("Could not write OTLP data: {err:?}");
+        return (500, "Internal Server Error", vec![]);
+    }
+
+    // an empty `Export*ServiceResponse`, in the request's encoding
+    if content_type.starts_with("application/json") {
+        (200, "OK", b"{}".to_vec())
+    } else {
+        (200, "OK", vec![])
+    }
+}
+
+fn handle_otlp_client(st: Arc<State>, client: TcpStream) -> Result<()> {
+    client.set_read_timeout(Some(OTLP_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(client.try_clone()?);
+    let mut writer = client;
+
+    while st.active.load(atomic::Ordering::SeqCst) {
+        let req = match http::read_request(&mut reader) {
+            Ok(Some(req)) => req,
+            Ok(None) => break,
+            // idle for too long, or disconnected
+            Err(err) if err.is::<io::Error>() => {
+                log::debug!("closing OTLP client: {err}");
+                break;
+            }
+            Err(err) => {
+                log::debug!("invalid HTTP request: {err:?}");
+                let msg = format!("{err}");
+                http::write_response(
+                    &mut writer,
+                    400,
+                    "Bad Request",
+                    "text/plain",
+                    msg.as_bytes(),
+                )?;
+                break;
+            }
+        };
+
+        let (status, reason, body) = handle_otlp_request(&st, &req);
+        let content_type = match req.header("content-type") {
+            Some(ct) if status == 200 => ct,
+            _ => "text/plain",
+        };
+        http::write_response(&mut writer, status, reason, content_type, &body)?;
+        if req.wants_close() {
+            break;
         }
+    }
+    Ok(())
+}
+
+/// Accept OTLP/HTTP clients on `listener` until it fails.
+fn otlp_accept_loop(st: Arc<State>, listener: TcpListener) {
+    loop {
+        let (client, client_addr) = match listener.accept() {
+            Ok(x) => x,
+            Err(err) => {
+                log::info!("could not accept more OTLP clients: {:?}", err);
+                break;
+            }
+        };
+        log::debug!("new OTLP client on {client_addr:?}");
+
+        let st2 = st.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_otlp_client(st2, client) {
+                log::error!("while handling OTLP client on {client_addr:?}, got error: {e:?}")
+            }
+        });
+    }
+}
+
+/// Bind the OTLP/HTTP listener, if configured. Clients of a TCP socket have
+/// no credentials, so it cannot be used with `allow_uids`.
+fn bind_otlp(addr: Option<&str>, allow_uids: &[u32]) -> Result<Option<TcpListener>> {
+    let Some(addr) = addr else {
+        return Ok(None);
     };
+    if !allow_uids.is_empty() {
+        anyhow::bail!(
+            "OTLP/HTTP ({addr:?}) cannot be used with --allow-uid: \
+             the uid of TCP clients is not known"
+        );
+    }
+    log::info!("serving OTLP/HTTP on {addr}");
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("binding OTLP/HTTP address {addr:?}"))?;
+    Ok(Some(listener))
+}
 
-    log::info!("serving on unix socket {socket_path:?}");
+pub fn run(cli: cli::Serve, config: &Config) -> Result<()> {
+    let rules = Rules::new(&config.rules).context("invalid rules in config")?;
+    if cli.single_file.is_some() && rules.has_routes() {
+        log::warn!("routes are ignored when writing into a single file");
+    }
 
-    // try to remove file, if already present
-    let _ = std::fs::remove_file(&socket_path);
+    let store = cli.store.or(config.store).unwrap_or_default();
+
+    // resolve paths now, the daemon runs in `dir`
+    let dir: PathBuf = std::path::absolute(config.data_dir(cli.dir.as_ref())?)?;
+    fs::create_dir_all(&dir).with_context(|| format!("creating data directory {dir:?}"))?;
+    let socket_path = std::path::absolute(config.socket_path(cli.unix_socket.as_ref())?)?;
+    let extra_sockets = config
+        .listeners
+        .iter()
+        .map(std::path::absolute)
+        .collect::<Result<Vec<_>, _>>()?;
+    let into_file = match &cli.single_file {
+        Some(f) => Some(std::path::absolute(f)?.to_string_lossy().to_string()),
+        None => None,
+    };
+    let pid_file = match (&cli.pid_file, cli.daemonize) {
+        (None, false) => None,
+        (p, _) => Some(std::path::absolute(utils::pid_file_path(p.as_ref())?)?),
+    };
+
+    let startup = if cli.daemonize {
+        let pid_file = pid_file.as_ref().unwrap();
+        Some(daemon::daemonize(&dir, pid_file)?)
+    } else {
+        if let Some(pid_file) = &pid_file {
+            fs::write(pid_file, format!("{}\n", std::process::id()))
+                .with_context(|| format!("writing pid file {pid_file:?}"))?;
+        }
+        None
+    };
+
+    log::info!("data directory is {:?}", &dir);
+
+    // let the parent process know if we could start listening
+    let otlp_addr = cli.otlp_http.as_ref().or(config.otlp_http.as_ref());
+    let listening = listen(&cli, socket_path, extra_sockets).and_then(|(listeners, paths)| {
+        let otlp_listener = bind_otlp(otlp_addr.map(|a| a.as_str()), &cli.allow_uids)?;
+        Ok((listeners, paths, otlp_listener))
+    });
+    if let Some(startup) = startup {
+        startup.report(&listening);
+    }
+    let (mut listeners, socket_paths, otlp_listener) = listening?;
 
     // shared state
     let st = Arc::new(State {
         active: AtomicBool::new(true),
         die_when_idle: AtomicBool::new(false),
-        into_file: cli.single_file.clone(),
-        socket_path: socket_path.clone(),
+        into_file,
+        fill_pid: cli.fill_pid,
+        allow_uids: cli.allow_uids.clone(),
+        // SAFETY: geteuid cannot fail
+        uid: unsafe { libc::geteuid() },
+        socket_paths,
+        max_trace_size: config.max_trace_size,
+        retention: config
+            .retention_days
+            .map(|days| Duration::from_secs(days * 24 * 3600)),
+        pid_file,
         dir,
         files: Mutex::new(HashMap::new()),
+        otlp_sessions: Mutex::new(HashMap::new()),
+        rules,
+        n_clients: AtomicU64::new(0),
+        store,
     });
 
     thread::spawn({
@@ -347,31 +1351,193 @@ pub fn run(cli: cli::Serve) -> Result<()> {
         }
     });
 
-    let listener = UnixListener::bind(&socket_path)?;
-    loop {
-        let (client, client_addr) = match listener.accept() {
-            Ok(x) => x,
-            Err(err) => {
-                log::info!("could not accept more clients: {:?}", err);
-                break;
-            }
-        };
+    // exit cleanly on SIGTERM/SIGINT
+    ctrlc::set_handler({
+        let st2 = st.clone();
+        move || {
+            log::info!("received termination signal, exiting");
+            st2.kill();
+            st2.remove_runtime_files();
+            std::process::exit(0);
+        }
+    })
+    .context("installing signal handler")?;
 
+    // the main socket is served on this thread, additional ones in the background
+    let main_listener = listeners.remove(0);
+    for listener in listeners {
         let st2 = st.clone();
-        thread::spawn(move || {
-            let client = BufReader::new(client);
-            if let Err(e) = handle_client(st2, client) {
-                log::error!("while handling client on {client_addr:?}, got error: {e:?}")
-            }
-        });
+        thread::spawn(move || accept_loop(st2, listener));
+    }
+
+    if let Some(listener) = otlp_listener {
+        let st2 = st.clone();
+        thread::spawn(move || otlp_accept_loop(st2, listener));
     }
 
+    systemd::notify("READY=1");
+
+    accept_loop(st.clone(), main_listener);
+
     st.active.store(false, atomic::Ordering::SeqCst);
+    systemd::notify("STOPPING=1");
 
-    // try to remove socket file
-    if let Err(err) = std::fs::remove_file(&socket_path) {
-        log::warn!("Error when deleting socket file at exit: {err:?}")
-    }
+    // try to remove socket file and pid file
+    st.remove_runtime_files();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn state(name: &str) -> Arc<State> {
+        state_with_rules(name, &[])
+    }
+
+    fn state_with_rules(name: &str, rules: &[config::Rule]) -> Arc<State> {
+        let dir = std::env::temp_dir().join(format!("tldrs-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Arc::new(State {
+            active: AtomicBool::new(true),
+            die_when_idle: AtomicBool::new(false),
+            into_file: None,
+            fill_pid: false,
+            allow_uids: vec![],
+            uid: 0,
+            socket_paths: vec![],
+            max_trace_size: None,
+            retention: None,
+            pid_file: None,
+            dir,
+            files: Mutex::new(HashMap::new()),
+            otlp_sessions: Mutex::new(HashMap::new()),
+            rules: Rules::new(rules).unwrap(),
+            n_clients: AtomicU64::new(0),
+            store: cli::Store::Jsonl,
+        })
+    }
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut res = ((payload.len() + 1) as u32).to_le_bytes().to_vec();
+        res.push(kind);
+        res.extend_from_slice(payload);
+        res
+    }
+
+    /// An `i` event named with string 0, 1ns after the previous one.
+    fn event(pid: u8) -> Vec<u8> {
+        frame(2, &[b'i', 0, 0, 2, 2 * pid, 0])
+    }
+
+    fn read_trace(st: &State, trace_id: &str) -> Vec<serde_json::Value> {
+        let path = st.dir.join(format!("{trace_id}.jsonl"));
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn binary_batch_with_handles() {
+        let st = state("binary-batch");
+        let mut input = b"OPEN main\nOPEN t1 AS j1\nOPEN t2 AS j2\nBINARY 1\n".to_vec();
+        input.extend(frame(1, b"\x00ev"));
+        input.extend(frame(0, b"BATCH 4"));
+        input.extend(frame(3, b"j1"));
+        input.extend(event(1));
+        input.extend(event(1));
+        input.extend(frame(3, b"j2"));
+        input.extend(event(2));
+        input.extend(frame(3, b""));
+        input.extend(event(3));
+        input.extend(event(3));
+
+        let mut reply = vec![];
+        let n_errors = handle_client(st.clone(), &input[..], &mut reply, None).unwrap();
+        assert_eq!(n_errors, 0);
+        assert_eq!(reply, b"BINARY 1\n");
+
+        let pids = |trace_id| -> Vec<i64> {
+            let events = read_trace(&st, trace_id);
+            events.iter().map(|e| e["pid"].as_i64().unwrap()).collect()
+        };
+        assert_eq!(pids("t1"), [1, 1]);
+        assert_eq!(pids("t2"), [2]);
+        assert_eq!(pids("main"), [3, 3]);
+        let _ = fs::remove_dir_all(&st.dir);
+    }
+
+    #[test]
+    fn reopen_resets_routes() {
+        let rule = config::Rule {
+            expr: "name == 'gc'".to_string(),
+            drop: false,
+            route: Some("{trace_id}-gc".to_string()),
+        };
+        let st = state_with_rules("reopen", &[rule]);
+        let input = [
+            "OPEN a",
+            r#"{"ph":"M","name":"thread_name","pid":1,"tid":1,"args":{"name":"a"}}"#,
+            r#"{"ph":"i","name":"gc","ts":1,"pid":1,"tid":1}"#,
+            "OPEN b",
+            r#"{"ph":"i","name":"gc","ts":2,"pid":1,"tid":1}"#,
+        ]
+        .join("\n");
+        let n_errors = handle_client(st.clone(), input.as_bytes(), io::sink(), None).unwrap();
+        assert_eq!(n_errors, 0);
+
+        let ts = |trace_id| -> Vec<f64> {
+            let events = read_trace(&st, trace_id);
+            events.iter().filter_map(|e| e["ts"].as_f64()).collect()
+        };
+        assert_eq!(ts("a-gc"), [1.]);
+        // without the metadata of `a`
+        assert_eq!(read_trace(&st, "b-gc").len(), 1);
+        assert_eq!(ts("b-gc"), [2.]);
+        let _ = fs::remove_dir_all(&st.dir);
+    }
+
+    #[test]
+    fn otlp_requests_share_a_session() {
+        let st = state("otlp-session");
+        let request = |body: String| http::Request {
+            method: "POST".to_string(),
+            path: "/v1/traces".to_string(),
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: body.into_bytes(),
+        };
+        for (i, trace_id) in ["aa", "bb"].iter().enumerate() {
+            let body = serde_json::json!({"resourceSpans": [{
+                "resource": {"attributes": [
+                    {"key": "service.name", "value": {"stringValue": "svc"}},
+                    {"key": "process.pid", "value": {"intValue": "3"}},
+                ]},
+                "scopeSpans": [{"spans": [{
+                    "traceId": trace_id, "name": "s", "startTimeUnixNano": (i + 1) * 1000,
+                }]}],
+            }]});
+            let (status, _, _) = handle_otlp_request(&st, &request(body.to_string()));
+            assert_eq!(status, 200);
+        }
+        let (status, _, body) = handle_otlp_request(&st, &request("{".to_string()));
+        assert_eq!(status, 400, "{}", String::from_utf8_lossy(&body));
+
+        // the trace stays open between requests
+        assert_eq!(st.otlp_sessions.lock().unwrap().len(), 1);
+        let file = st.files.lock().unwrap()[&TraceID::from("svc")].clone();
+        assert_eq!(Arc::strong_count(&file), 3);
+        drop(file);
+
+        st.close_all_force();
+        let events = read_trace(&st, "svc");
+        let names: Vec<&str> = events.iter().map(|e| e["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["process_name", "s", "s"]);
+        assert_eq!(events[2]["args"]["otel.trace_id"], "bb");
+        let _ = fs::remove_dir_all(&st.dir);
+    }
+}
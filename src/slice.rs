@@ -0,0 +1,457 @@
+//! Slices of traces for `get-tef`: a time window, some processes, threads or
+//! categories, and a maximum number of events.
+//!
+//! Spans that cross the boundaries of the window are clipped to it, and
+//! counters get the value they had at the start of the window. Metadata events
+//! of the processes that have events in the slice are kept, and written first
+//! so that tracks get their names.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufRead,
+};
+
+use anyhow::Result;
+
+use crate::{cli, event::Event, event::Events, index, utils};
+
+/// A span, or an async span.
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Thread(i64, i64),
+    Async(i64, String, String),
+}
+
+/// A `B` or `b` event, waiting for its end.
+enum Open {
+    /// Not in the slice, and neither is its end
+    Dropped,
+    /// Started before the window. If it ends in the window, it goes into `slot`.
+    Pending {
+        slot: usize,
+        ev: Box<Event>,
+    },
+    Emitted,
+}
+
+pub struct Slice {
+    from: f64,
+    to: f64,
+    pids: Vec<i64>,
+    tids: Vec<i64>,
+    cats: Vec<String>,
+    max_events: usize,
+}
+
+/// `ts` as an absolute timestamp in µs, or a duration since `start` if it has a unit.
+fn parse_time(s: &str, start: impl FnOnce() -> Result<f64>) -> Result<f64> {
+    match s.trim().parse::<f64>() {
+        Ok(ts) => Ok(ts),
+        Err(_) => Ok(start()? + utils::parse_dur(s)?),
+    }
+}
+
+impl Slice {
+    /// The slice requested on the command line, if any. `start` returns the
+    /// start of the trace.
+    pub fn from_cli(cli: &cli::GetTEF, start: impl Fn() -> Result<f64>) -> Result<Option<Self>> {
+        if cli.from.is_none()
+            && cli.to.is_none()
+            && cli.pid.is_empty()
+            && cli.tid.is_empty()
+            && cli.cat.is_empty()
+            && cli.max_events.is_none()
+        {
+            return Ok(None);
+        }
+
+        let from = match &cli.from {
+            Some(s) => parse_time(s, &start)?,
+            None => f64::MIN,
+        };
+        let to = match &cli.to {
+            Some(s) => parse_time(s, &start)?,
+            None => f64::MAX,
+        };
+        if from > to {
+            anyhow::bail!("--from is after --to");
+        }
+
+        Ok(Some(Self {
+            from,
+            to,
+            pids: cli.pid.clone(),
+            tids: cli.tid.clone(),
+            cats: cli.cat.clone(),
+            max_events: cli.max_events.unwrap_or(usize::MAX),
+        }))
+    }
+
+    /// The parts of the trace that the slice needs.
+    pub fn selection(&self) -> index::Selection {
+        index::Selection {
+            from: self.from,
+            to: self.to,
+            pids: self.pids.clone(),
+            tids: self.tids.clone(),
+            names: vec![],
+        }
+    }
+
+    fn selected(&self, ev: &Event) -> bool {
+        (self.pids.is_empty() || self.pids.contains(&ev.pid))
+            && (self.tids.is_empty() || self.tids.contains(&ev.tid))
+            && (self.cats.is_empty() || ev.cat.split(',').any(|c| self.cats.iter().any(|s| s == c)))
+    }
+
+    fn in_window(&self, ts: f64) -> bool {
+        self.from <= ts && ts <= self.to
+    }
+
+    /// Reads jsonl from `reader`, and returns the jsonl of the slice.
+    ///
+    /// The slice is kept in memory: this lets us write metadata events first,
+    /// and spans that started before the window at their place.
+    pub fn apply(&self, reader: impl BufRead) -> Result<Vec<u8>> {
+        let mut s = Slicer {
+            slice: self,
+            out: vec![],
+            open: HashMap::new(),
+            counters: HashMap::new(),
+            metadata: vec![],
+            pids: HashSet::new(),
+            max_ts: f64::MIN,
+            n_kept: 0,
+            n_dropped: 0,
+            n_clipped: 0,
+        };
+        let mut events = Events::new(reader);
+        while let Some(r) = events.next_with_raw() {
+            let (ev, raw) = r?;
+            s.add(ev, raw)?;
+        }
+        events.report_invalid();
+        s.finish()
+    }
+}
+
+struct Slicer<'a> {
+    slice: &'a Slice,
+    /// Lines of the slice, `None` for spans that may or may not be in it
+    out: Vec<Option<String>>,
+    open: HashMap<Key, Vec<Open>>,
+    /// Last counter events before the window, by pid, name and id
+    counters: HashMap<(i64, String, String), Event>,
+    /// Metadata events, with their pid
+    metadata: Vec<(i64, String)>,
+    /// Processes with events in the slice
+    pids: HashSet<i64>,
+    max_ts: f64,
+    n_kept: usize,
+    n_dropped: usize,
+    n_clipped: usize,
+}
+
+impl Slicer<'_> {
+    fn full(&self) -> bool {
+        self.n_kept >= self.slice.max_events
+    }
+
+    /// Keep an event, unless we have enough of them and `force` is false.
+    fn emit(&mut self, pid: i64, line: String, force: bool) -> bool {
+        if self.full() && !force {
+            self.n_dropped += 1;
+            return false;
+        }
+        self.pids.insert(pid);
+        self.n_kept += 1;
+        self.out.push(Some(line));
+        true
+    }
+
+    fn emit_clipped(&mut self, ev: &Event, force: bool) -> Result<bool> {
+        let line = serde_json::to_string(ev)?;
+        let kept = self.emit(ev.pid, line, force);
+        self.n_clipped += kept as usize;
+        Ok(kept)
+    }
+
+    /// Move spans that started before the window into it, once we know they end in it.
+    fn flush_pending(&mut self, key: &Key) -> Result<()> {
+        let from = self.slice.from;
+        let full = self.full();
+        let Some(stack) = self.open.get_mut(key) else {
+            return Ok(());
+        };
+        let mut filled = vec![];
+        let mut n_dropped = 0;
+        for open in stack.iter_mut() {
+            if !matches!(open, Open::Pending { .. }) {
+                continue;
+            }
+            let Open::Pending { slot, mut ev } = std::mem::replace(open, Open::Dropped) else {
+                unreachable!()
+            };
+            if full {
+                n_dropped += 1;
+                continue;
+            }
+            ev.ts = from;
+            filled.push((slot, ev));
+            *open = Open::Emitted;
+        }
+        self.n_dropped += n_dropped;
+        for (slot, ev) in filled {
+            self.pids.insert(ev.pid);
+            self.n_kept += 1;
+            self.n_clipped += 1;
+            self.out[slot] = Some(serde_json::to_string(&ev)?);
+        }
+        Ok(())
+    }
+
+    fn begin(&mut self, key: Key, ev: Event, raw: &str) -> Result<()> {
+        let open = if !self.slice.selected(&ev) || ev.ts > self.slice.to || self.full() {
+            self.n_dropped += 1;
+            Open::Dropped
+        } else if ev.ts < self.slice.from {
+            self.out.push(None);
+            Open::Pending {
+                slot: self.out.len() - 1,
+                ev: Box::new(ev),
+            }
+        } else {
+            self.flush_pending(&key)?;
+            if self.emit(ev.pid, raw.to_string(), false) {
+                Open::Emitted
+            } else {
+                Open::Dropped
+            }
+        };
+        self.open.entry(key).or_default().push(open);
+        Ok(())
+    }
+
+    fn end(&mut self, key: Key, mut ev: Event, raw: &str) -> Result<()> {
+        let to = self.slice.to;
+        let top = self.open.get(&key).and_then(|s| s.last());
+        if matches!(top, Some(Open::Pending { .. })) && ev.ts >= self.slice.from {
+            self.flush_pending(&key)?;
+        }
+        match self.open.get_mut(&key).and_then(|s| s.pop()) {
+            None => {
+                if self.slice.selected(&ev) && self.slice.in_window(ev.ts) {
+                    self.emit(ev.pid, raw.to_string(), false);
+                } else {
+                    self.n_dropped += 1;
+                }
+            }
+            Some(Open::Dropped) => self.n_dropped += 1,
+            Some(Open::Emitted) if ev.ts > to => {
+                ev.ts = to;
+                self.emit_clipped(&ev, true)?;
+            }
+            Some(Open::Emitted) => {
+                self.emit(ev.pid, raw.to_string(), true);
+            }
+            Some(Open::Pending { slot, .. }) => {
+                // ended before the window
+                if slot + 1 == self.out.len() {
+                    self.out.pop();
+                }
+                self.n_dropped += 2;
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, mut ev: Event, raw: &str) -> Result<()> {
+        if ev.ph != 'M' {
+            self.max_ts = self.max_ts.max(ev.ts);
+        }
+        let (from, to) = (self.slice.from, self.slice.to);
+        match ev.ph {
+            'M' => self.metadata.push((ev.pid, raw.to_string())),
+            'B' => self.begin(Key::Thread(ev.pid, ev.tid), ev, raw)?,
+            'E' => self.end(Key::Thread(ev.pid, ev.tid), ev, raw)?,
+            'b' | 'e' => {
+                let key = Key::Async(ev.pid, ev.cat.clone(), ev.id_str().unwrap_or_default());
+                if ev.ph == 'b' {
+                    self.begin(key, ev, raw)?;
+                } else {
+                    self.end(key, ev, raw)?;
+                }
+            }
+            _ if !self.slice.selected(&ev) => self.n_dropped += 1,
+            'X' if ev.end() < from || ev.ts > to => self.n_dropped += 1,
+            'X' if ev.ts < from || ev.end() > to => {
+                let end = ev.end().min(to);
+                ev.ts = ev.ts.max(from);
+                ev.dur = Some(end - ev.ts);
+                self.emit_clipped(&ev, false)?;
+            }
+            'C' if ev.ts < from => {
+                // the value at the start of the window
+                let key = (ev.pid, ev.name.clone(), ev.id_str().unwrap_or_default());
+                if self.counters.insert(key, ev).is_some() {
+                    self.n_dropped += 1;
+                }
+            }
+            _ if self.slice.in_window(ev.ts) => {
+                self.emit(ev.pid, raw.to_string(), false);
+            }
+            _ => self.n_dropped += 1,
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        // nothing to clip if the trace ends before the window
+        let reaches_window = self.max_ts >= self.slice.from;
+
+        // spans that never end, and were open at the start of the window
+        if reaches_window {
+            let keys: Vec<Key> = self.open.keys().cloned().collect();
+            for key in keys {
+                self.flush_pending(&key)?;
+            }
+        }
+
+        let mut counters: Vec<Event> = if reaches_window {
+            self.counters.into_values().collect()
+        } else {
+            vec![]
+        };
+        counters.sort_by(|a, b| a.ts.total_cmp(&b.ts));
+        let mut lines = vec![];
+        for mut ev in counters {
+            if self.n_kept >= self.slice.max_events {
+                self.n_dropped += 1;
+                continue;
+            }
+            ev.ts = self.slice.from;
+            self.pids.insert(ev.pid);
+            self.n_kept += 1;
+            self.n_clipped += 1;
+            lines.push(serde_json::to_string(&ev)?);
+        }
+
+        let mut res = vec![];
+        let metadata = self
+            .metadata
+            .iter()
+            .filter(|(pid, _)| self.pids.contains(pid));
+        for line in metadata
+            .map(|(_, l)| l)
+            .chain(&lines)
+            .chain(self.out.iter().flatten())
+        {
+            res.extend_from_slice(line.as_bytes());
+            res.push(b'\n');
+        }
+
+        log::info!(
+            "kept {} events ({} clipped to the slice), dropped {}",
+            self.n_kept,
+            self.n_clipped,
+            self.n_dropped
+        );
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slice(from: f64, to: f64) -> Slice {
+        Slice {
+            from,
+            to,
+            pids: vec![],
+            tids: vec![],
+            cats: vec![],
+            max_events: usize::MAX,
+        }
+    }
+
+    /// `(ph, name, ts, dur)` of the events of the slice.
+    fn apply(slice: &Slice, trace: &str) -> Vec<(String, String, Option<f64>, Option<f64>)> {
+        let out = slice.apply(trace.as_bytes()).unwrap();
+        Events::new(&out[..])
+            .map(|ev| {
+                let ev = ev.unwrap();
+                let ts = (ev.ph != 'M').then_some(ev.ts);
+                (ev.ph.to_string(), ev.name, ts, ev.dur)
+            })
+            .collect()
+    }
+
+    const TRACE: &str = r#"{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{"name":"a"}}
+{"ph":"M","name":"process_name","pid":2,"tid":0,"args":{"name":"b"}}
+{"ph":"C","name":"mem","pid":1,"tid":1,"ts":10,"args":{"v":1}}
+{"ph":"C","name":"mem","pid":1,"tid":1,"ts":50,"args":{"v":2}}
+{"ph":"B","name":"outer","pid":1,"tid":1,"ts":60}
+{"ph":"X","name":"x","pid":1,"tid":2,"ts":90,"dur":20}
+{"ph":"X","name":"before","pid":2,"tid":1,"ts":0,"dur":10}
+{"ph":"i","name":"tick","pid":1,"tid":1,"ts":150,"s":"t"}
+{"ph":"b","name":"io","cat":"c","id":1,"pid":1,"tid":1,"ts":160}
+{"ph":"E","pid":1,"tid":1,"ts":170}
+{"ph":"X","name":"long","pid":1,"tid":3,"ts":180,"dur":100}
+{"ph":"e","name":"io","cat":"c","id":1,"pid":1,"tid":1,"ts":300}
+{"ph":"i","name":"late","pid":1,"tid":1,"ts":300,"s":"t"}
+"#;
+
+    fn ev(
+        ph: &str,
+        name: &str,
+        ts: f64,
+        dur: Option<f64>,
+    ) -> (String, String, Option<f64>, Option<f64>) {
+        (ph.to_string(), name.to_string(), Some(ts), dur)
+    }
+
+    #[test]
+    fn window() {
+        let res = apply(&slice(100., 200.), TRACE);
+        assert_eq!(
+            res,
+            [
+                ("M".to_string(), "process_name".to_string(), None, None),
+                // the value of the counter at the start of the window
+                ev("C", "mem", 100., None),
+                ev("B", "outer", 100., None),
+                ev("X", "x", 100., Some(10.)),
+                ev("i", "tick", 150., None),
+                ev("b", "io", 160., None),
+                ev("E", "", 170., None),
+                ev("X", "long", 180., Some(20.)),
+                ev("e", "io", 200., None),
+            ]
+        );
+    }
+
+    #[test]
+    fn processes_and_max_events() {
+        let mut s = slice(f64::MIN, f64::MAX);
+        s.pids = vec![2];
+        let res = apply(&s, TRACE);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[1], ev("X", "before", 0., Some(10.)));
+
+        // ends of kept spans are kept past the limit, and pid 2 has no event left
+        let mut s = slice(f64::MIN, f64::MAX);
+        s.tids = vec![1];
+        s.max_events = 3;
+        let names: Vec<String> = apply(&s, TRACE).into_iter().map(|e| e.1).collect();
+        assert_eq!(names, ["process_name", "mem", "mem", "outer", ""]);
+    }
+
+    #[test]
+    fn times() {
+        let start = || Ok(1000.);
+        assert_eq!(parse_time("1500", start).unwrap(), 1500.);
+        assert_eq!(parse_time("2ms", start).unwrap(), 3000.);
+        assert!(parse_time("soon", start).is_err());
+    }
+}
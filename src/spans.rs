@@ -0,0 +1,230 @@
+//! Reconstruct nested spans from `X` and `B`/`E` events, and async spans
+//! from `b`/`e` events.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use crate::event::Event;
+
+/// A complete span on a thread.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub name: String,
+    pub cat: String,
+    pub pid: i64,
+    pub tid: i64,
+    /// Start timestamp, in microseconds
+    pub start: f64,
+    /// End timestamp, in microseconds
+    pub end: f64,
+    pub args: Option<Map<String, Value>>,
+    /// Index of the enclosing span, if any
+    pub parent: Option<usize>,
+    /// Number of ancestors
+    pub depth: usize,
+    /// Duration minus the duration of direct children
+    pub self_dur: f64,
+    /// Thread of the `e` event of an async span, if it was closed
+    pub end_tid: Option<i64>,
+}
+
+impl Span {
+    pub fn dur(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// The spans of a trace, along with process and thread names.
+#[derive(Debug, Default)]
+pub struct Spans {
+    /// Sorted by `(pid, tid, start)`, parents before their children
+    pub spans: Vec<Span>,
+    /// Async spans, from `b`/`e` pairs with the same pid, category and id.
+    /// They are not nested, and are on the thread of their `b` event.
+    pub async_spans: Vec<Span>,
+    pub process_names: HashMap<i64, String>,
+    pub thread_names: HashMap<(i64, i64), String>,
+    /// Number of `E` (or `e`) events without a matching `B` (or `b`)
+    pub n_unmatched: usize,
+}
+
+fn span_of_event(ev: Event, end: f64) -> Span {
+    Span {
+        name: ev.name,
+        cat: ev.cat,
+        pid: ev.pid,
+        tid: ev.tid,
+        start: ev.ts,
+        end,
+        args: ev.args,
+        parent: None,
+        depth: 0,
+        self_dur: 0.,
+        end_tid: None,
+    }
+}
+
+impl Spans {
+    /// Collect spans from `events`. `B` events that are never closed
+    /// end with the last timestamp of the trace.
+    pub fn from_events(events: impl Iterator<Item = Result<Event>>) -> Result<Self> {
+        let mut res = Spans::default();
+        let mut open: HashMap<(i64, i64), Vec<Event>> = HashMap::new();
+        let mut open_async: HashMap<(i64, String, String), Vec<Event>> = HashMap::new();
+        let mut last_ts: f64 = 0.;
+
+        for ev in events {
+            let ev = ev?;
+            last_ts = last_ts.max(ev.end());
+            match ev.ph {
+                'X' => {
+                    let end = ev.end();
+                    res.spans.push(span_of_event(ev, end));
+                }
+                'B' => open.entry((ev.pid, ev.tid)).or_default().push(ev),
+                'E' => match open.get_mut(&(ev.pid, ev.tid)).and_then(|s| s.pop()) {
+                    Some(mut b) => {
+                        // args can be on both events
+                        if let Some(args) = ev.args {
+                            b.args.get_or_insert_with(Map::new).extend(args);
+                        }
+                        res.spans.push(span_of_event(b, ev.ts));
+                    }
+                    None => res.n_unmatched += 1,
+                },
+                'b' => {
+                    let key = (ev.pid, ev.cat.clone(), ev.id_str().unwrap_or_default());
+                    open_async.entry(key).or_default().push(ev);
+                }
+                'e' => {
+                    let key = (ev.pid, ev.cat.clone(), ev.id_str().unwrap_or_default());
+                    match open_async.get_mut(&key).and_then(|s| s.pop()) {
+                        Some(b) => {
+                            let end = ev.ts.max(b.ts);
+                            let mut span = span_of_event(b, end);
+                            span.end_tid = Some(ev.tid);
+                            res.async_spans.push(span);
+                        }
+                        None => res.n_unmatched += 1,
+                    }
+                }
+                'M' => match ev.name.as_str() {
+                    "process_name" => {
+                        if let Some(name) = ev.arg_str("name") {
+                            res.process_names.insert(ev.pid, name.to_string());
+                        }
+                    }
+                    "thread_name" => {
+                        if let Some(name) = ev.arg_str("name") {
+                            res.thread_names.insert((ev.pid, ev.tid), name.to_string());
+                        }
+                    }
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+
+        for (_, stack) in open {
+            for b in stack {
+                res.spans.push(span_of_event(b, last_ts));
+            }
+        }
+        for (_, stack) in open_async {
+            for b in stack {
+                res.async_spans.push(span_of_event(b, last_ts));
+            }
+        }
+
+        if res.n_unmatched > 0 {
+            log::warn!("Ignored {} end events without a begin.", res.n_unmatched);
+        }
+
+        res.compute_nesting();
+        for span in &mut res.async_spans {
+            span.self_dur = span.dur();
+        }
+        res.async_spans.sort_by(|a, b| {
+            (a.pid, a.tid)
+                .cmp(&(b.pid, b.tid))
+                .then(a.start.total_cmp(&b.start))
+        });
+        Ok(res)
+    }
+
+    /// Sort spans and compute parents, depth and self durations.
+    fn compute_nesting(&mut self) {
+        // longer spans first, so that parents come before children starting at the same time
+        self.spans.sort_by(|a, b| {
+            (a.pid, a.tid)
+                .cmp(&(b.pid, b.tid))
+                .then(a.start.total_cmp(&b.start))
+                .then(b.end.total_cmp(&a.end))
+        });
+
+        let mut stack: Vec<usize> = vec![];
+        for i in 0..self.spans.len() {
+            let (pid, tid, start) = {
+                let s = &self.spans[i];
+                (s.pid, s.tid, s.start)
+            };
+            while let Some(&top) = stack.last() {
+                let t = &self.spans[top];
+                if t.pid != pid || t.tid != tid || t.end <= start {
+                    stack.pop();
+                } else {
+                    break;
+                }
+            }
+
+            let span = &mut self.spans[i];
+            span.parent = stack.last().copied();
+            span.depth = stack.len();
+            span.self_dur = span.dur();
+            if let Some(p) = span.parent {
+                // children that overflow their parent are clipped
+                let dur = span.end.min(self.spans[p].end) - start;
+                self.spans[p].self_dur -= dur;
+            }
+            stack.push(i);
+        }
+    }
+
+    /// Names of the span and its ancestors, outermost first.
+    pub fn stack(&self, mut i: usize) -> Vec<&str> {
+        let mut res = vec![self.spans[i].name.as_str()];
+        while let Some(p) = self.spans[i].parent {
+            res.push(&self.spans[p].name);
+            i = p;
+        }
+        res.reverse();
+        res
+    }
+
+    /// Is span `i` nested in a span of the same name?
+    pub fn is_recursive(&self, i: usize) -> bool {
+        let name = &self.spans[i].name;
+        let mut p = self.spans[i].parent;
+        while let Some(j) = p {
+            if &self.spans[j].name == name {
+                return true;
+            }
+            p = self.spans[j].parent;
+        }
+        false
+    }
+
+    /// Human readable name for a thread.
+    pub fn thread_label(&self, pid: i64, tid: i64) -> String {
+        let process = match self.process_names.get(&pid) {
+            Some(name) => format!("{name} ({pid})"),
+            None => format!("pid {pid}"),
+        };
+        match self.thread_names.get(&(pid, tid)) {
+            Some(name) => format!("{process} / {name} ({tid})"),
+            None => format!("{process} / tid {tid}"),
+        }
+    }
+}
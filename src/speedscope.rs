@@ -0,0 +1,119 @@
+//! Conversion to speedscope's file format, with one evented profile per thread.
+//!
+//! See https://www.speedscope.app/file-format-schema.json
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::Result;
+use serde_json::json;
+
+use crate::{event::Events, spans::Spans};
+
+/// Reads jsonl from `reader` and writes a speedscope profile into `writer`.
+pub fn emit_speedscope(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()> {
+    let mut events = Events::new(reader);
+    let spans = Spans::from_events(&mut events)?;
+    events.report_invalid();
+
+    let mut frames = vec![];
+    let mut frame_idx: HashMap<&str, usize> = HashMap::new();
+    let mut profiles = vec![];
+
+    for thread in spans
+        .spans
+        .chunk_by(|a, b| (a.pid, a.tid) == (b.pid, b.tid))
+    {
+        let (pid, tid) = (thread[0].pid, thread[0].tid);
+
+        let mut evs = vec![];
+        // open frames and the time at which they close
+        let mut stack: Vec<(usize, f64)> = vec![];
+        let mut end_value: f64 = 0.;
+
+        for span in thread {
+            while let Some(&(frame, end)) = stack.last() {
+                if end > span.start {
+                    break;
+                }
+                evs.push(json!({"type": "C", "frame": frame, "at": end}));
+                stack.pop();
+            }
+
+            let frame = *frame_idx.entry(&span.name).or_insert_with(|| {
+                frames.push(json!({"name": span.name}));
+                frames.len() - 1
+            });
+
+            // children must not outlive their parent
+            let end = match stack.last() {
+                Some(&(_, parent_end)) => span.end.min(parent_end),
+                None => span.end,
+            };
+            end_value = end_value.max(end);
+            evs.push(json!({"type": "O", "frame": frame, "at": span.start}));
+            stack.push((frame, end));
+        }
+        while let Some((frame, end)) = stack.pop() {
+            evs.push(json!({"type": "C", "frame": frame, "at": end}));
+        }
+
+        profiles.push(json!({
+            "type": "evented",
+            "name": spans.thread_label(pid, tid),
+            "unit": "microseconds",
+            "startValue": thread[0].start,
+            "endValue": end_value,
+            "events": evs,
+        }));
+    }
+
+    let file = json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "exporter": "tldrs",
+        "shared": {"frames": frames},
+        "profiles": profiles,
+    });
+    serde_json::to_writer(&mut *writer, &file)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evented_profile_per_thread() {
+        let trace = r#"{"ph":"M","name":"thread_name","pid":1,"tid":1,"args":{"name":"main"}}
+{"ph":"X","name":"main","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"child","pid":1,"tid":1,"ts":10,"dur":200}
+{"ph":"X","name":"main","pid":1,"tid":2,"ts":5,"dur":5}
+"#;
+        let mut out = vec![];
+        emit_speedscope(&mut trace.as_bytes(), &mut out).unwrap();
+        let file: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            file["shared"]["frames"],
+            json!([{"name": "main"}, {"name": "child"}])
+        );
+        let profiles = file["profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0]["name"], "pid 1 / main (1)");
+        // the child is closed with its parent
+        assert_eq!(
+            profiles[0]["events"],
+            json!([
+                {"type": "O", "frame": 0, "at": 0.0},
+                {"type": "O", "frame": 1, "at": 10.0},
+                {"type": "C", "frame": 1, "at": 100.0},
+                {"type": "C", "frame": 0, "at": 100.0},
+            ])
+        );
+        assert_eq!(profiles[0]["endValue"], 100.0);
+        assert_eq!(profiles[1]["startValue"], 5.0);
+        assert_eq!(profiles[1]["endValue"], 10.0);
+    }
+}
@@ -0,0 +1,434 @@
+//! SQLite storage for traces (`tldrs serve --store sqlite`), and `tldrs sql`.
+//!
+//! Each event is a row of `events`, with its original JSON so that `get-tef`
+//! reproduces the trace exactly, and its main fields in columns for queries.
+//! Process and thread names go into `processes` and `threads`, and the args of
+//! events into `args`, one row per key.
+
+use std::{
+    fs,
+    io::{self, stdout, BufRead, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, types::ValueRef, Connection, OpenFlags};
+use serde_json::Value;
+
+use crate::{cli, config::Config, event::Event, get_tef, stats::csv_field, utils};
+
+/// First bytes of SQLite databases.
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Number of events read at a time from the database.
+const READ_BATCH_SIZE: i64 = 10_000;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS events (
+    id INTEGER PRIMARY KEY,
+    ph TEXT,
+    name TEXT,
+    cat TEXT,
+    ts REAL,
+    dur REAL,
+    pid INTEGER,
+    tid INTEGER,
+    event_id TEXT,
+    json TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_ts ON events (ts);
+CREATE INDEX IF NOT EXISTS events_name ON events (name);
+CREATE INDEX IF NOT EXISTS events_thread ON events (pid, tid);
+CREATE TABLE IF NOT EXISTS processes (
+    pid INTEGER PRIMARY KEY,
+    name TEXT
+);
+CREATE TABLE IF NOT EXISTS threads (
+    pid INTEGER,
+    tid INTEGER,
+    name TEXT,
+    PRIMARY KEY (pid, tid)
+);
+CREATE TABLE IF NOT EXISTS args (
+    event INTEGER NOT NULL REFERENCES events (id),
+    key TEXT NOT NULL,
+    value
+);
+CREATE INDEX IF NOT EXISTS args_event ON args (event);
+CREATE INDEX IF NOT EXISTS args_key ON args (key, value);
+";
+
+/// Is `path` a SQLite database?
+pub fn is_sqlite(path: &Path) -> bool {
+    let mut header = [0u8; 16];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok_and(|()| &header == MAGIC)
+}
+
+/// Files SQLite creates next to a database.
+pub fn is_auxiliary_file(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    ["-journal", "-wal", "-shm"]
+        .iter()
+        .any(|suffix| path.ends_with(suffix))
+}
+
+/// Scalar args are stored as they are, others as JSON.
+fn to_sql(v: &Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value as V;
+    match v {
+        Value::Null => V::Null,
+        Value::Bool(b) => V::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => V::Integer(i),
+            None => V::Real(n.as_f64().unwrap_or(0.)),
+        },
+        Value::String(s) => V::Text(s.clone()),
+        v => V::Text(v.to_string()),
+    }
+}
+
+/// Writes events into a database. Events are written in a transaction,
+/// committed by `flush`.
+pub struct Writer {
+    conn: Connection,
+    in_transaction: bool,
+}
+
+impl Writer {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path).with_context(|| format!("opening database {path:?}"))?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn,
+            in_transaction: false,
+        })
+    }
+
+    /// Total size of the events, as JSON lines.
+    pub fn size(&self) -> Result<u64> {
+        let size: i64 = self.conn.query_row(
+            "SELECT COALESCE(SUM(LENGTH(CAST(json AS BLOB)) + 1), 0) FROM events",
+            [],
+            |r| r.get(0),
+        )?;
+        Ok(size as u64)
+    }
+
+    /// Id of the last event.
+    pub fn last_id(&self) -> Result<i64> {
+        let id = self
+            .conn
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM events", [], |r| r.get(0))?;
+        Ok(id)
+    }
+
+    /// Add an event. Lines that are not valid events are kept as they are.
+    pub fn add(&mut self, line: &str) -> Result<()> {
+        if !self.in_transaction {
+            self.conn.execute_batch("BEGIN")?;
+            self.in_transaction = true;
+        }
+
+        let Ok(ev) = Event::parse(line) else {
+            self.conn
+                .prepare_cached("INSERT INTO events (json) VALUES (?1)")?
+                .execute([line])?;
+            return Ok(());
+        };
+        self.conn
+            .prepare_cached(
+                "INSERT INTO events (ph, name, cat, ts, dur, pid, tid, event_id, json) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?
+            .execute(params![
+                ev.ph.to_string(),
+                ev.name,
+                ev.cat,
+                ev.ts,
+                ev.dur,
+                ev.pid,
+                ev.tid,
+                ev.id_str(),
+                line
+            ])?;
+        let id = self.conn.last_insert_rowid();
+
+        let mut insert_arg = self
+            .conn
+            .prepare_cached("INSERT INTO args (event, key, value) VALUES (?1, ?2, ?3)")?;
+        for (k, v) in ev.args.iter().flatten() {
+            insert_arg.execute(params![id, k, to_sql(v)])?;
+        }
+
+        let name = ev.arg_str("name");
+        match (ev.ph, ev.name.as_str(), name) {
+            ('M', "process_name", Some(name)) => {
+                self.conn
+                    .prepare_cached("INSERT OR REPLACE INTO processes (pid, name) VALUES (?1, ?2)")?
+                    .execute(params![ev.pid, name])?;
+            }
+            ('M', "thread_name", Some(name)) => {
+                self.conn
+                    .prepare_cached(
+                        "INSERT OR REPLACE INTO threads (pid, tid, name) VALUES (?1, ?2, ?3)",
+                    )?
+                    .execute(params![ev.pid, ev.tid, name])?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Commit the events written so far.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.in_transaction {
+            self.conn.execute_batch("COMMIT")?;
+            self.in_transaction = false;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("Error while committing events: {err:?}");
+        }
+    }
+}
+
+/// The events of a database as jsonl, in the order they were added.
+struct Lines {
+    conn: Connection,
+    /// Id of the last event read
+    last_id: i64,
+    /// Only read events up to this id
+    max_id: i64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Lines {
+    /// Read the next events into `buf`. Returns false after the last one.
+    fn next_batch(&mut self) -> Result<bool> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, json FROM events WHERE id > ?1 AND id <= ?2 ORDER BY id LIMIT ?3",
+        )?;
+        let mut rows = stmt.query(params![self.last_id, self.max_id, READ_BATCH_SIZE])?;
+        self.buf.clear();
+        self.pos = 0;
+        while let Some(row) = rows.next()? {
+            self.last_id = row.get(0)?;
+            let json: String = row.get(1)?;
+            self.buf.extend_from_slice(json.as_bytes());
+            self.buf.push(b'\n');
+        }
+        Ok(!self.buf.is_empty())
+    }
+}
+
+impl BufRead for Lines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.buf.len() && !self.next_batch().map_err(io::Error::other)? {
+            return Ok(&[]);
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl Read for Lines {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+fn open_read_only(path: &Path) -> Result<Connection> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    Connection::open_with_flags(path, flags).with_context(|| format!("opening database {path:?}"))
+}
+
+/// Read the events of the database at `path` as jsonl, up to the event `max_id`.
+pub fn open_reader(path: &Path, max_id: Option<i64>) -> Result<impl BufRead> {
+    Ok(Lines {
+        conn: open_read_only(path)?,
+        last_id: 0,
+        max_id: max_id.unwrap_or(i64::MAX),
+        buf: vec![],
+        pos: 0,
+    })
+}
+
+/// Number of events of a database, and its time range.
+pub fn summary(path: &Path) -> Result<(u64, Option<f64>, Option<f64>)> {
+    let conn = open_read_only(path)?;
+    let res = conn.query_row(
+        "SELECT COUNT(*), MIN(ts) FILTER (WHERE ph != 'M'), \
+         MAX(ts + COALESCE(dur, 0)) FILTER (WHERE ph != 'M') FROM events",
+        [],
+        |r| Ok((r.get::<_, i64>(0)? as u64, r.get(1)?, r.get(2)?)),
+    )?;
+    Ok(res)
+}
+
+fn fmt_cell(v: ValueRef) -> String {
+    match v {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).to_string(),
+        ValueRef::Blob(b) => format!("<{} bytes>", b.len()),
+    }
+}
+
+fn to_json(v: ValueRef) -> Value {
+    match v {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+        ValueRef::Blob(b) => format!("<{} bytes>", b.len()).into(),
+    }
+}
+
+pub fn run(cli: cli::Sql, config: &Config) -> Result<()> {
+    let file = get_tef::find_trace_file(cli.jsonl_file, cli.dir.as_ref(), config)?;
+    let path = Path::new(&file);
+    let database;
+    let loaded;
+    let conn: &Connection = if is_sqlite(path) {
+        database = open_read_only(path)?;
+        &database
+    } else {
+        // load the trace into a temporary database
+        log::debug!("loading {file:?} into memory");
+        let mut writer = Writer {
+            conn: Connection::open_in_memory()?,
+            in_transaction: false,
+        };
+        writer.conn.execute_batch(SCHEMA)?;
+        for line in utils::open_trace(path)?.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                writer.add(line.trim())?;
+            }
+        }
+        writer.flush()?;
+        loaded = writer;
+        &loaded.conn
+    };
+
+    let mut stmt = conn
+        .prepare(&cli.query)
+        .with_context(|| format!("invalid query {:?}", cli.query))?;
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+    let mut rows = stmt.query([])?;
+
+    let mut out = BufWriter::new(stdout().lock());
+    match cli.format {
+        cli::StatsFormat::Table => {
+            let mut lines = vec![columns];
+            while let Some(row) = rows.next()? {
+                let n = lines[0].len();
+                lines.push(
+                    (0..n)
+                        .map(|i| row.get_ref(i).map(fmt_cell))
+                        .collect::<Result<_, _>>()?,
+                );
+            }
+            utils::write_columns(&lines, lines[0].len(), &mut out)?;
+        }
+        cli::StatsFormat::Csv => {
+            let header: Vec<String> = columns.iter().map(|c| csv_field(c)).collect();
+            writeln!(out, "{}", header.join(","))?;
+            while let Some(row) = rows.next()? {
+                let cells = (0..columns.len())
+                    .map(|i| row.get_ref(i).map(|v| csv_field(&fmt_cell(v))))
+                    .collect::<Result<Vec<_>, _>>()?;
+                writeln!(out, "{}", cells.join(","))?;
+            }
+        }
+        cli::StatsFormat::Json => {
+            let mut res = vec![];
+            while let Some(row) = rows.next()? {
+                let mut obj = serde_json::Map::new();
+                for (i, c) in columns.iter().enumerate() {
+                    obj.insert(c.clone(), to_json(row.get_ref(i)?));
+                }
+                res.push(Value::Object(obj));
+            }
+            serde_json::to_writer_pretty(&mut out, &res)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("tldrs-test-sqlite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("t.sqlite");
+        let jsonl = dir.join("t.jsonl");
+
+        let lines = [
+            r#"{"ph":"M","name":"process_name","pid":1,"tid":0,"args":{"name":"app"}}"#,
+            r#"{"ph":"X","name":"work","cat":"c","ts":1.5,"dur":2,"pid":1,"tid":2,"args":{"n":3,"s":"x"}}"#,
+            r#"{"name":"b","ph":"b","id":"0x1","ts":2,"pid":1,"tid":2}"#,
+            r#"{"ph":"C","name":"mem","ts":3,"pid":1,"tid":0,"args":{"v":1.25}}"#,
+            "not an event",
+        ];
+        let mut writer = Writer::open(&path).unwrap();
+        for line in lines {
+            writer.add(line).unwrap();
+        }
+        writer.flush().unwrap();
+        assert_eq!(writer.last_id().unwrap(), lines.len() as i64);
+        drop(writer);
+        fs::write(&jsonl, lines.join("\n") + "\n").unwrap();
+
+        assert!(is_sqlite(&path));
+        assert!(!is_sqlite(&jsonl));
+
+        let read: Vec<String> = open_reader(&path, None)
+            .unwrap()
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read, lines);
+        let read: Vec<String> = open_reader(&path, Some(2))
+            .unwrap()
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read, lines[..2]);
+
+        let tef = |p: &Path| {
+            let mut out = Vec::new();
+            utils::emit_tef(&mut utils::open_trace(p).unwrap(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(tef(&path), tef(&jsonl));
+
+        let (count, min, max) = summary(&path).unwrap();
+        assert_eq!(count, 5);
+        assert_eq!(min, Some(1.5));
+        assert_eq!(max, Some(3.5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
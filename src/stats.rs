@@ -0,0 +1,282 @@
+//! Aggregate statistics on the spans of a trace, per name and category.
+
+use std::{
+    collections::BTreeMap,
+    io::{stdout, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    cli,
+    config::Config,
+    event::Events,
+    get_tef,
+    spans::{Span, Spans},
+    utils,
+};
+
+/// Durations of a group of spans.
+#[derive(Default)]
+struct Group {
+    durs: Vec<f64>,
+    /// Sum of durations, not counting spans nested in a span of the same name
+    total: f64,
+    self_dur: f64,
+}
+
+impl Group {
+    fn add(&mut self, span: &Span, recursive: bool) {
+        self.durs.push(span.dur());
+        if !recursive {
+            self.total += span.dur();
+        }
+        self.self_dur += span.self_dur.max(0.);
+    }
+}
+
+/// Spans grouped by `(name, cat)`, and by `(pid, tid)` within each group.
+type Groups = BTreeMap<(String, String), (Group, BTreeMap<(i64, i64), Group>)>;
+
+/// Statistics for a span name and category, or for one of its threads.
+#[derive(Serialize)]
+struct Row {
+    name: String,
+    cat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tid: Option<i64>,
+    count: usize,
+    total_us: f64,
+    self_us: f64,
+    min_us: f64,
+    mean_us: f64,
+    p50_us: f64,
+    p95_us: f64,
+    p99_us: f64,
+    max_us: f64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    threads: Vec<Row>,
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+    let rank = (p / 100. * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn row(name: &str, cat: &str, thread: Option<(i64, i64)>, mut g: Group) -> Row {
+    g.durs.sort_by(f64::total_cmp);
+    let count = g.durs.len();
+    Row {
+        name: name.to_string(),
+        cat: cat.to_string(),
+        pid: thread.map(|t| t.0),
+        tid: thread.map(|t| t.1),
+        count,
+        total_us: g.total,
+        self_us: g.self_dur,
+        min_us: g.durs.first().copied().unwrap_or(0.),
+        mean_us: g.durs.iter().sum::<f64>() / count.max(1) as f64,
+        p50_us: percentile(&g.durs, 50.),
+        p95_us: percentile(&g.durs, 95.),
+        p99_us: percentile(&g.durs, 99.),
+        max_us: g.durs.last().copied().unwrap_or(0.),
+        threads: vec![],
+    }
+}
+
+/// Compute rows, sorted by decreasing total time.
+fn compute(spans: &Spans, by_thread: bool) -> Vec<Row> {
+    let mut groups: Groups = BTreeMap::new();
+
+    let sync = spans
+        .spans
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (s, spans.is_recursive(i)));
+    let asynchronous = spans.async_spans.iter().map(|s| (s, false));
+    for (span, recursive) in sync.chain(asynchronous) {
+        let (all, threads) = groups
+            .entry((span.name.clone(), span.cat.clone()))
+            .or_default();
+        all.add(span, recursive);
+        if by_thread {
+            threads
+                .entry((span.pid, span.tid))
+                .or_default()
+                .add(span, recursive);
+        }
+    }
+
+    let mut rows: Vec<Row> = groups
+        .into_iter()
+        .map(|((name, cat), (all, threads))| {
+            let mut r = row(&name, &cat, None, all);
+            r.threads = threads
+                .into_iter()
+                .map(|(thread, g)| row(&name, &cat, Some(thread), g))
+                .collect();
+            r.threads.sort_by(|a, b| b.total_us.total_cmp(&a.total_us));
+            r
+        })
+        .collect();
+    rows.sort_by(|a, b| b.total_us.total_cmp(&a.total_us));
+    rows
+}
+
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn write_csv(rows: &[Row], w: &mut impl Write) -> Result<()> {
+    writeln!(
+        w,
+        "name,cat,pid,tid,count,total_us,self_us,min_us,mean_us,p50_us,p95_us,p99_us,max_us"
+    )?;
+    for r in rows
+        .iter()
+        .flat_map(|r| std::iter::once(r).chain(&r.threads))
+    {
+        let opt = |x: Option<i64>| x.map(|x| x.to_string()).unwrap_or_default();
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            csv_field(&r.name),
+            csv_field(&r.cat),
+            opt(r.pid),
+            opt(r.tid),
+            r.count,
+            r.total_us,
+            r.self_us,
+            r.min_us,
+            r.mean_us,
+            r.p50_us,
+            r.p95_us,
+            r.p99_us,
+            r.max_us
+        )?;
+    }
+    Ok(())
+}
+
+fn write_table(rows: &[Row], spans: &Spans, w: &mut impl Write) -> Result<()> {
+    let header = [
+        "name", "cat", "count", "total", "self", "min", "mean", "p50", "p95", "p99", "max",
+    ];
+    let mut lines: Vec<Vec<String>> = vec![header.iter().map(|s| s.to_string()).collect()];
+    for r in rows
+        .iter()
+        .flat_map(|r| std::iter::once(r).chain(&r.threads))
+    {
+        let name = match (r.pid, r.tid) {
+            (Some(pid), Some(tid)) => format!("  {}", spans.thread_label(pid, tid)),
+            _ => r.name.clone(),
+        };
+        let mut line = vec![name, r.cat.clone(), r.count.to_string()];
+        for d in [
+            r.total_us, r.self_us, r.min_us, r.mean_us, r.p50_us, r.p95_us, r.p99_us, r.max_us,
+        ] {
+            line.push(utils::fmt_dur(d));
+        }
+        lines.push(line);
+    }
+
+    utils::write_columns(&lines, 2, w)
+}
+
+pub fn run(cli: cli::Stats, config: &Config) -> Result<()> {
+    let file = get_tef::find_trace_file(cli.jsonl_file, cli.dir.as_ref(), config)?;
+    log::debug!("reading trace from file {file:?}");
+    let reader = utils::open_trace(Path::new(&file))?;
+
+    let mut events = Events::new(reader);
+    let spans = Spans::from_events(&mut events)?;
+    events.report_invalid();
+
+    let rows = compute(&spans, cli.by_thread);
+
+    let mut out = BufWriter::new(stdout().lock());
+    match cli.format {
+        cli::StatsFormat::Table => write_table(&rows, &spans, &mut out)?,
+        cli::StatsFormat::Csv => write_csv(&rows, &mut out)?,
+        cli::StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &rows)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(trace: &str) -> Spans {
+        Spans::from_events(Events::new(trace.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn percentiles() {
+        let values: Vec<f64> = (1..=100).map(f64::from).collect();
+        assert_eq!(percentile(&values, 50.), 50.);
+        assert_eq!(percentile(&values, 99.), 99.);
+        assert_eq!(percentile(&values, 100.), 100.);
+        assert_eq!(percentile(&[3.], 0.), 3.);
+        assert_eq!(percentile(&[], 50.), 0.);
+    }
+
+    #[test]
+    fn rows() {
+        let spans = spans(
+            r#"{"ph":"X","name":"f","cat":"c","pid":1,"tid":1,"ts":0,"dur":100}
+{"ph":"X","name":"f","cat":"c","pid":1,"tid":1,"ts":10,"dur":20}
+{"ph":"X","name":"g","pid":1,"tid":1,"ts":50,"dur":30}
+{"ph":"X","name":"f","cat":"c","pid":1,"tid":2,"ts":0,"dur":40}
+{"ph":"b","name":"io","cat":"a","id":1,"pid":1,"tid":2,"ts":5}
+{"ph":"e","name":"io","cat":"a","id":1,"pid":1,"tid":2,"ts":505}
+"#,
+        );
+        let rows = compute(&spans, true);
+        let names: Vec<&str> = rows.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["io", "f", "g"]);
+
+        let f = &rows[1];
+        assert_eq!((f.cat.as_str(), f.count), ("c", 3));
+        // the nested call is not counted twice in the total
+        assert_eq!(f.total_us, 140.);
+        assert_eq!(f.self_us, 50. + 20. + 40.);
+        assert_eq!((f.min_us, f.p50_us, f.max_us), (20., 40., 100.));
+        let threads: Vec<(Option<i64>, usize, f64)> = f
+            .threads
+            .iter()
+            .map(|t| (t.tid, t.count, t.total_us))
+            .collect();
+        assert_eq!(threads, [(Some(1), 2, 100.), (Some(2), 1, 40.)]);
+
+        assert_eq!(rows[0].total_us, 500.);
+        assert!(compute(&spans, false)[1].threads.is_empty());
+    }
+
+    #[test]
+    fn csv() {
+        let spans = spans(r#"{"ph":"X","name":"a,\"b\"","pid":1,"tid":1,"ts":0,"dur":2}"#);
+        let mut out = vec![];
+        write_csv(&compute(&spans, true), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[1], r#""a,""b""",,,,1,2,2,2,2,2,2,2,2"#);
+        assert_eq!(lines[2], r#""a,""b""",,1,1,1,2,2,2,2,2,2,2,2"#);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+use crate::{cli, config::Config, utils};
+
+/// Ask the daemon for its state.
+fn query_daemon(cli: &cli::Status, config: &Config) -> Result<Value> {
+    let socket_path = config.socket_path(cli.unix_socket.as_ref())?;
+    let mut sock = UnixStream::connect(&socket_path)
+        .with_context(|| format!("connecting to {socket_path:?}"))?;
+    sock.write_all(b"STATUS\n")?;
+
+    let mut line = String::new();
+    BufReader::new(sock).read_line(&mut line)?;
+    if line.is_empty() {
+        anyhow::bail!("the daemon closed the connection (is it older than this client?)");
+    }
+    let status: Value = serde_json::from_str(&line).context("invalid answer from the daemon")?;
+    if let Some(err) = status.get("error") {
+        anyhow::bail!("the daemon refused: {err}");
+    }
+    Ok(status)
+}
+
+fn to_string(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        v => v.to_string(),
+    }
+}
+
+pub fn run(cli: cli::Status, config: &Config) -> Result<()> {
+    let status = query_daemon(&cli, config)?;
+    let mut out = io::stdout().lock();
+    if cli.json {
+        writeln!(out, "{status}")?;
+        return Ok(());
+    }
+
+    writeln!(out, "pid: {}", status["pid"])?;
+    writeln!(out, "dir: {}", to_string(&status["dir"]))?;
+    writeln!(out, "clients: {}", status["clients"])?;
+
+    let empty = vec![];
+    let traces = status["traces"].as_array().unwrap_or(&empty);
+    writeln!(out, "\nopen traces: {}", traces.len())?;
+    if !traces.is_empty() {
+        let mut lines = vec![vec![
+            "trace".to_string(),
+            "size".to_string(),
+            "dropped".to_string(),
+        ]];
+        for t in traces {
+            lines.push(vec![
+                to_string(&t["trace_id"]),
+                to_string(&t["size"]),
+                to_string(&t["dropped"]),
+            ]);
+        }
+        utils::write_columns(&lines, 1, &mut out)?;
+    }
+
+    let rules = status["rules"].as_array().unwrap_or(&empty);
+    writeln!(out, "\nrules: {}", rules.len())?;
+    if !rules.is_empty() {
+        let mut lines = vec![vec![
+            "match".to_string(),
+            "action".to_string(),
+            "matched".to_string(),
+        ]];
+        for r in rules {
+            let action = match r.get("route") {
+                Some(route) => format!("route to {}", to_string(route)),
+                None => "drop".to_string(),
+            };
+            lines.push(vec![
+                to_string(&r["match"]),
+                action,
+                to_string(&r["matched"]),
+            ]);
+        }
+        utils::write_columns(&lines, 2, &mut out)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::{fs, io, io::Write, os::unix::net::UnixStream, thread, time::Duration};
+
+use anyhow::{Context, Result};
+
+use crate::{cli, config::Config, utils};
+
+/// Is there a process with this pid?
+fn is_alive(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// Send SIGTERM to `pid` and wait for it to exit.
+fn stop_pid(pid: i32) -> Result<()> {
+    log::info!("sending SIGTERM to daemon with pid {pid}");
+    // SAFETY: just sending a signal
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error()).context("sending SIGTERM");
+    }
+
+    for _ in 0..100 {
+        if !is_alive(pid) {
+            log::info!("daemon exited");
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    anyhow::bail!("daemon with pid {pid} did not exit after 10s")
+}
+
+pub fn run(cli: cli::Stop, config: &Config) -> Result<()> {
+    let pid_file = utils::pid_file_path(cli.pid_file.as_ref())?;
+
+    match fs::read_to_string(&pid_file) {
+        Ok(s) => {
+            let pid: i32 = s
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid pid file {pid_file:?}"))?;
+            if is_alive(pid) {
+                return stop_pid(pid);
+            }
+
+            log::warn!("removing stale pid file {pid_file:?}");
+            let _ = fs::remove_file(&pid_file);
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            // no pid file, ask the daemon to die via its socket
+            let socket_path = config.socket_path(cli.unix_socket.as_ref())?;
+            log::info!("no pid file, sending DIE on socket {socket_path:?}");
+            let mut sock = UnixStream::connect(&socket_path)
+                .with_context(|| format!("connecting to {socket_path:?}"))?;
+            sock.write_all(b"DIE\n")?;
+            Ok(())
+        }
+        Err(err) => Err(err).with_context(|| format!("reading pid file {pid_file:?}")),
+    }
+}
@@ -0,0 +1,81 @@
+//! Minimal support for systemd socket activation and readiness notifications.
+//!
+//! See `sd_listen_fds(3)` and `sd_notify(3)`.
+
+use std::{
+    env,
+    os::{
+        fd::FromRawFd,
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram, UnixListener},
+    },
+};
+
+use anyhow::Result;
+
+/// First file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Take the listening socket passed by systemd, if any.
+///
+/// This follows the `LISTEN_FDS` protocol: the socket is only used if
+/// `$LISTEN_PID` is our pid. The variables are removed from the environment
+/// so that child processes do not use the socket too.
+pub fn take_listener() -> Result<Option<UnixListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let n_fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(n_fds)) = (pid, n_fds) else {
+        return Ok(None);
+    };
+
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        log::debug!("LISTEN_PID={pid} is not for us");
+        return Ok(None);
+    }
+
+    let n_fds: i32 = n_fds.parse()?;
+    if n_fds < 1 {
+        return Ok(None);
+    } else if n_fds > 1 {
+        log::warn!("systemd passed {n_fds} sockets, only the first one is used");
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    // SAFETY: systemd passes us ownership of the socket. We don't want
+    // it inherited by child processes.
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    Ok(Some(listener))
+}
+
+/// Send a notification (e.g. `READY=1`) to systemd.
+/// Does nothing if we're not running under systemd with `Type=notify`.
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let res = (|| -> Result<()> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid NOTIFY_SOCKET"))?;
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+
+        let sock = UnixDatagram::unbound()?;
+        sock.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    })();
+
+    if let Err(err) = res {
+        log::warn!("Could not notify systemd of {state:?}: {err:?}");
+    }
+}
@@ -1,8 +1,168 @@
-use std::io::{BufRead, Write};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::Result;
 
-pub const XDG_PREFIX: &'static str = "tldrs";
+use crate::{cli, firefox, folded, html, otlp, perfetto, speedscope, sqlite};
+
+pub const XDG_PREFIX: &str = "tldrs";
+
+/// Environment variable clients and the daemon use to find the socket.
+pub const SOCKET_ENV_VAR: &str = "TLDRS_SOCKET";
+
+const SOCKET_FILENAME: &str = "tldrs.socket";
+
+const PID_FILENAME: &str = "tldrs.pid";
+
+const LOG_FILENAME: &str = "tldrs.log";
+
+/// Path to a runtime file: `$XDG_RUNTIME_DIR/tldrs/<filename>`,
+/// or `/tmp/<filename>` if there is no runtime directory.
+fn runtime_file(filename: &str) -> Result<PathBuf> {
+    let xdg = xdg::BaseDirectories::with_prefix(XDG_PREFIX)?;
+    if xdg.has_runtime_directory() {
+        // this creates `$XDG_RUNTIME_DIR/tldrs` with mode 0700
+        return Ok(xdg.place_runtime_file(filename)?);
+    }
+
+    let mut path = std::env::temp_dir();
+    path.push(filename);
+    Ok(path)
+}
+
+/// Path to the unix socket. This is `path` if provided, otherwise
+/// `$XDG_RUNTIME_DIR/tldrs/tldrs.socket`, and finally `/tmp/tldrs.socket`.
+///
+/// `$TLDRS_SOCKET` is handled by the [config](crate::config::Config).
+pub fn socket_path(path: Option<impl AsRef<str>>) -> Result<PathBuf> {
+    if let Some(p) = path {
+        return Ok(PathBuf::from_str(p.as_ref())?);
+    }
+
+    runtime_file(SOCKET_FILENAME)
+}
+
+/// Path to the daemon's pid file. Defaults to `$XDG_RUNTIME_DIR/tldrs/tldrs.pid`.
+pub fn pid_file_path(path: Option<impl AsRef<str>>) -> Result<PathBuf> {
+    match path {
+        Some(p) => Ok(PathBuf::from_str(p.as_ref())?),
+        None => runtime_file(PID_FILENAME),
+    }
+}
+
+/// Path to the daemon's log file. Defaults to `$XDG_STATE_HOME/tldrs/tldrs.log`.
+/// The path is absolute, since the daemon runs in its data directory.
+pub fn log_file_path(path: Option<impl AsRef<str>>) -> Result<PathBuf> {
+    match path {
+        Some(p) => Ok(std::path::absolute(p.as_ref())?),
+        None => {
+            let xdg = xdg::BaseDirectories::with_prefix(XDG_PREFIX)?;
+            Ok(xdg.place_state_file(LOG_FILENAME)?)
+        }
+    }
+}
+
+/// Can `s` be used as a trace id (and thus as a file name)?
+pub fn is_valid_trace_id(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 128
+        && !s.starts_with('.')
+        && s.bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.')
+}
+
+/// Parse a duration such as `10ms`, `1.5s`, `200us` or `200µs` into microseconds.
+/// A plain number is in microseconds.
+pub fn parse_dur(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == 'e'))
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration {s:?}"))?;
+    let factor = match unit.trim() {
+        "" | "us" | "µs" => 1.,
+        "ns" => 1e-3,
+        "ms" => 1e3,
+        "s" => 1e6,
+        "m" | "min" => 60e6,
+        "h" => 3600e6,
+        u => anyhow::bail!("Unknown unit {u:?} in duration {s:?}"),
+    };
+    Ok(num * factor)
+}
+
+/// Human readable duration, from microseconds.
+pub fn fmt_dur(us: f64) -> String {
+    if us >= 1e6 {
+        format!("{:.2} s", us / 1e6)
+    } else if us >= 1e3 {
+        format!("{:.2} ms", us / 1e3)
+    } else {
+        format!("{us:.1} µs")
+    }
+}
+
+/// Write rows as aligned columns. The first `n_left` columns are aligned
+/// to the left (text), the others to the right (numbers).
+pub fn write_columns(lines: &[Vec<String>], n_left: usize, w: &mut impl Write) -> Result<()> {
+    let mut widths: Vec<usize> = vec![];
+    for line in lines {
+        widths.resize(widths.len().max(line.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(line) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for line in lines {
+        let mut out = String::new();
+        for (i, (cell, width)) in line.iter().zip(&widths).enumerate() {
+            let pad = " ".repeat(width - cell.chars().count());
+            if i < n_left {
+                out.push_str(&format!("{cell}{pad}  "));
+            } else {
+                out.push_str(&format!("{pad}{cell}  "));
+            }
+        }
+        writeln!(w, "{}", out.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Open a trace for reading, as jsonl: a `.jsonl` file, or a SQLite database.
+pub fn open_trace(path: &Path) -> Result<Box<dyn BufRead>> {
+    if sqlite::is_sqlite(path) {
+        return Ok(Box::new(sqlite::open_reader(path, None)?));
+    }
+    Ok(Box::new(BufReader::new(fs::File::open(path)?)))
+}
+
+/// Reads jsonl from `reader` and writes it into `writer` in the given format.
+///
+/// `trace_id` is the tldrs trace id (the file stem), used by formats that
+/// need a stable identifier.
+pub fn emit(
+    format: cli::OutputFormat,
+    trace_id: &str,
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+) -> Result<()> {
+    match format {
+        cli::OutputFormat::Tef => emit_tef(reader, writer),
+        cli::OutputFormat::PerfettoProto => perfetto::emit_perfetto_proto(reader, writer),
+        cli::OutputFormat::Speedscope => speedscope::emit_speedscope(reader, writer),
+        cli::OutputFormat::Folded => folded::emit_folded(reader, writer),
+        cli::OutputFormat::Firefox => firefox::emit_firefox(reader, writer),
+        cli::OutputFormat::OtlpJson => otlp::emit_otlp_json(trace_id, reader, writer, None),
+        cli::OutputFormat::Html => html::emit_html(trace_id, reader, writer),
+    }
+}
 
 /// Reads jsonl from `reader` and writes a single
 /// TEF-format json object into `writer`.
@@ -22,7 +182,7 @@ pub fn emit_tef(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()
         if json_trimmed.is_empty() {
             continue;
         } else if json_trimmed.as_bytes()[0] != b'{'
-            || json_trimmed.as_bytes()[json_trimmed.as_bytes().len() - 1] != b'}'
+            || json_trimmed.as_bytes()[json_trimmed.len() - 1] != b'}'
         {
             // make sure the object is not trivially invalid
             bad_json += 1;
@@ -33,12 +193,15 @@ pub fn emit_tef(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<()
             write!(writer, "[")?;
             first = false;
         } else {
-            write!(writer, ",\n")?;
+            writeln!(writer, ",")?;
         }
 
         write!(writer, "{}", json.trim())?;
     }
-    write!(writer, "]\n")?;
+    if first {
+        write!(writer, "[")?;
+    }
+    writeln!(writer, "]")?;
 
     if bad_json > 0 {
         log::warn!("Read {bad_json} invalid JSON objects while producing TEF object.");
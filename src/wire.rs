@@ -0,0 +1,475 @@
+//! Binary protocol, for clients that produce many events.
+//!
+//! A client switches to it by sending the line `BINARY 1`; the daemon answers
+//! with the same line, and everything after it is a sequence of frames:
+//! a `u32` little-endian length, then a kind byte and the payload (the length
+//! counts both). Frames are:
+//! - `MSG`: a message of the text protocol, without its newline (`OPEN`,
+//!   `EMIT_TEF`, a JSON event, …);
+//! - `STRING`: a varint id and a UTF-8 string, for events to refer to;
+//! - `EVENT`: an event in a compact encoding, see [`Decoder::event`];
+//! - `HANDLE`: the handle of the trace that the next events go to (see
+//!   `OPEN … AS`), or nothing for the trace of `OPEN`.
+//!
+//! Events are converted to JSON lines, so traces are the same as with the
+//! text protocol.
+
+use std::{fmt::Write as _, io::Read};
+
+use anyhow::{Context, Result};
+
+use crate::msg::{self, Msg};
+
+/// Version of the protocol, in `BINARY <version>`.
+pub const VERSION: &str = "1";
+
+/// Frames larger than this are rejected.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// A client can define at most this many strings.
+const MAX_STRINGS: usize = 1 << 20;
+
+const FRAME_MSG: u8 = 0;
+const FRAME_STRING: u8 = 1;
+const FRAME_EVENT: u8 = 2;
+const FRAME_HANDLE: u8 = 3;
+
+const FLAG_CAT: u8 = 1;
+const FLAG_DUR: u8 = 2;
+const FLAG_ID: u8 = 4;
+const FLAG_ARGS: u8 = 8;
+
+const VALUE_NULL: u8 = 0;
+const VALUE_FALSE: u8 = 1;
+const VALUE_TRUE: u8 = 2;
+const VALUE_INT: u8 = 3;
+const VALUE_FLOAT: u8 = 4;
+const VALUE_STRING: u8 = 5;
+const VALUE_INTERNED: u8 = 6;
+const VALUE_JSON: u8 = 7;
+
+/// Reads the payload of a frame.
+struct Payload<'a>(&'a [u8]);
+
+impl Payload<'_> {
+    fn u8(&mut self) -> Result<u8> {
+        let (&b, rest) = self.0.split_first().context("truncated frame")?;
+        self.0 = rest;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut res = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            res |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(res);
+            }
+        }
+        anyhow::bail!("varint too long")
+    }
+
+    fn zigzag(&mut self) -> Result<i64> {
+        let n = self.varint()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&[u8]> {
+        anyhow::ensure!(n <= self.0.len(), "truncated frame");
+        let (res, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(res)
+    }
+
+    fn str(&mut self) -> Result<&str> {
+        let n = self.varint()? as usize;
+        Ok(std::str::from_utf8(self.bytes(n)?)?)
+    }
+}
+
+/// Append `s` to `out` as a JSON string.
+fn push_json_str(out: &mut String, s: &str) {
+    // serializing a str cannot fail
+    out.push_str(&serde_json::to_string(s).unwrap());
+}
+
+/// Decodes the frames of a client.
+#[derive(Default)]
+pub struct Decoder {
+    /// Strings defined by the client, as JSON strings
+    strings: Vec<String>,
+    /// Timestamp of the previous event, in ns
+    last_ts: i64,
+    /// Handle of the trace that events go to, empty for the default trace
+    handle: String,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    fn string(&self, id: u64) -> Result<&str> {
+        self.strings
+            .get(id as usize)
+            .map(|s| s.as_str())
+            .with_context(|| format!("undefined string {id}"))
+    }
+
+    fn value(&self, p: &mut Payload, out: &mut String) -> Result<()> {
+        match p.u8()? {
+            VALUE_NULL => out.push_str("null"),
+            VALUE_FALSE => out.push_str("false"),
+            VALUE_TRUE => out.push_str("true"),
+            VALUE_INT => write!(out, "{}", p.zigzag()?)?,
+            VALUE_FLOAT => {
+                let f = f64::from_le_bytes(p.bytes(8)?.try_into()?);
+                match serde_json::Number::from_f64(f) {
+                    Some(n) => write!(out, "{n}")?,
+                    None => out.push_str("null"),
+                }
+            }
+            VALUE_STRING => push_json_str(out, p.str()?),
+            VALUE_INTERNED => out.push_str(self.string(p.varint()?)?),
+            VALUE_JSON => {
+                let v: serde_json::Value = serde_json::from_str(p.str()?)?;
+                write!(out, "{v}")?
+            }
+            tag => anyhow::bail!("unknown value tag {tag}"),
+        }
+        Ok(())
+    }
+
+    /// Convert an event to JSON. Its fields are, in order:
+    /// - `ph`: one ASCII letter;
+    /// - flags: one byte, which of the optional fields are present;
+    /// - `name`: varint, a string id;
+    /// - `ts`: zigzag varint, nanoseconds since the previous event of the client;
+    /// - `pid` and `tid`: zigzag varints;
+    /// - `cat` (flag 1): varint, a string id;
+    /// - `dur` (flag 2): varint, in nanoseconds;
+    /// - `id` (flag 4): a value;
+    /// - `args` (flag 8): varint number of args, then for each a string id and a value.
+    ///
+    /// Values start with a tag: 0 for null, 1 false, 2 true, 3 an integer
+    /// (zigzag varint), 4 a float (f64 little-endian), 5 a string (varint
+    /// length and UTF-8), 6 a string id (varint) and 7 JSON text (as a string).
+    fn event(&mut self, p: &mut Payload, out: &mut String) -> Result<()> {
+        let ph = p.u8()?;
+        anyhow::ensure!(ph.is_ascii_alphabetic(), "invalid ph {ph}");
+        let flags = p.u8()?;
+        let name = p.varint()?;
+        self.last_ts = self
+            .last_ts
+            .checked_add(p.zigzag()?)
+            .context("timestamp overflow")?;
+        let pid = p.zigzag()?;
+        let tid = p.zigzag()?;
+
+        write!(
+            out,
+            r#"{{"ph":"{}","name":{}"#,
+            ph as char,
+            self.string(name)?
+        )?;
+        if flags & FLAG_CAT != 0 {
+            write!(out, r#","cat":{}"#, self.string(p.varint()?)?)?;
+        }
+        write!(
+            out,
+            r#","ts":{},"pid":{pid},"tid":{tid}"#,
+            self.last_ts as f64 / 1000.
+        )?;
+        if flags & FLAG_DUR != 0 {
+            write!(out, r#","dur":{}"#, p.varint()? as f64 / 1000.)?;
+        }
+        if flags & FLAG_ID != 0 {
+            out.push_str(r#","id":"#);
+            self.value(p, out)?;
+        }
+        if flags & FLAG_ARGS != 0 {
+            out.push_str(r#","args":{"#);
+            for i in 0..p.varint()? {
+                if i > 0 {
+                    out.push(',');
+                }
+                let key = p.varint()?;
+                write!(out, "{}:", self.string(key)?)?;
+                self.value(p, out)?;
+            }
+            out.push('}');
+        }
+        out.push('}');
+        Ok(())
+    }
+
+    /// Read the next frame from `r`, using `out` for the text of the message.
+    /// Returns `None` at the end of the stream.
+    pub fn next<'a>(
+        &mut self,
+        r: &mut impl Read,
+        out: &'a mut String,
+    ) -> Result<Option<(Option<&'a str>, Msg<'a>)>> {
+        let mut len = [0u8; 4];
+        match r.read_exact(&mut len) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            res => res?,
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        anyhow::ensure!(len > 0, "empty frame");
+        anyhow::ensure!(len <= MAX_FRAME_SIZE, "frame too large ({len} bytes)");
+        self.buf.resize(len, 0);
+        r.read_exact(&mut self.buf)?;
+
+        let buf = std::mem::take(&mut self.buf);
+        let kind = buf[0];
+        let mut p = Payload(&buf[1..]);
+        let res = match kind {
+            FRAME_MSG => std::str::from_utf8(p.0)
+                .map(|s| out.push_str(s))
+                .map_err(anyhow::Error::from),
+            FRAME_STRING => self.define_string(&mut p),
+            FRAME_EVENT => {
+                if !self.handle.is_empty() {
+                    write!(out, "@{} ", self.handle)?;
+                }
+                self.event(&mut p, out)
+            }
+            FRAME_HANDLE => self.set_handle(p.0),
+            kind => Err(anyhow::anyhow!("unknown frame kind {kind}")),
+        };
+        self.buf = buf;
+        res?;
+
+        Ok(Some(match kind {
+            FRAME_MSG => {
+                // JSON events may span several lines, but not in the trace
+                if let Some(i) = out.find('{').filter(|_| out.contains(['\n', '\r'])) {
+                    let prefix = out[..i].trim();
+                    if prefix.is_empty() || prefix.starts_with('@') && !prefix.contains(' ') {
+                        let v: serde_json::Value = serde_json::from_str(&out[i..])?;
+                        *out = format!("{prefix} {v}");
+                    }
+                }
+                msg::decode(out)
+            }
+            FRAME_EVENT => msg::decode(out),
+            _ => (None, Msg::Empty),
+        }))
+    }
+
+    fn set_handle(&mut self, handle: &[u8]) -> Result<()> {
+        let handle = std::str::from_utf8(handle)?;
+        anyhow::ensure!(
+            !handle.contains(char::is_whitespace),
+            "invalid handle {handle:?}"
+        );
+        self.handle = handle.to_string();
+        Ok(())
+    }
+
+    /// Strings are numbered from 0, in order, up to [`MAX_STRINGS`]; an id
+    /// can be redefined.
+    fn define_string(&mut self, p: &mut Payload) -> Result<()> {
+        let id = p.varint()? as usize;
+        anyhow::ensure!(id <= self.strings.len(), "string {id} defined out of order");
+        anyhow::ensure!(id < MAX_STRINGS, "too many strings");
+        let mut s = String::new();
+        push_json_str(&mut s, std::str::from_utf8(p.0)?);
+        if id == self.strings.len() {
+            self.strings.push(s);
+        } else {
+            self.strings[id] = s;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut res = ((payload.len() + 1) as u32).to_le_bytes().to_vec();
+        res.push(kind);
+        res.extend_from_slice(payload);
+        res
+    }
+
+    fn varint(mut n: u64, out: &mut Vec<u8>) {
+        while n >= 0x80 {
+            out.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    fn zigzag(n: i64, out: &mut Vec<u8>) {
+        varint(((n << 1) ^ (n >> 63)) as u64, out)
+    }
+
+    fn string(id: u64, s: &str) -> Vec<u8> {
+        let mut p = vec![];
+        varint(id, &mut p);
+        p.extend_from_slice(s.as_bytes());
+        frame(FRAME_STRING, &p)
+    }
+
+    /// Decode all the frames of `input`, as text messages.
+    fn decode(input: &[u8]) -> Result<Vec<String>> {
+        let mut d = Decoder::default();
+        let mut r = input;
+        let mut res = vec![];
+        let mut out = String::new();
+        loop {
+            out.clear();
+            match d.next(&mut r, &mut out)? {
+                None => return Ok(res),
+                Some((_, Msg::Empty)) => (),
+                Some((handle, msg)) => res.push(format!("{handle:?} {msg:?}")),
+            }
+        }
+    }
+
+    #[test]
+    fn msg() {
+        let input = frame(FRAME_MSG, b"OPEN foo");
+        let res = decode(&input).unwrap();
+        assert_eq!(res, [r#"None Open { trace_id: "foo", handle: None }"#]);
+
+        // multi-line JSON is written on one line
+        let input = frame(FRAME_MSG, b"@h {\n\"ph\": \"i\"\n}");
+        let res = decode(&input).unwrap();
+        assert_eq!(res, [r#"Some("h") Add { json: "{\"ph\":\"i\"}" }"#]);
+    }
+
+    #[test]
+    fn event() {
+        let mut input = string(0, "work");
+        input.extend(string(1, "cat \"x\""));
+        input.extend(string(2, "n"));
+        let mut p = vec![b'X', FLAG_CAT | FLAG_DUR | FLAG_ID | FLAG_ARGS, 0];
+        zigzag(1_500, &mut p); // ts
+        zigzag(-1, &mut p); // pid
+        zigzag(7, &mut p); // tid
+        varint(1, &mut p); // cat
+        varint(2_000, &mut p); // dur
+        p.push(VALUE_INTERNED);
+        varint(2, &mut p);
+        varint(2, &mut p); // args
+        varint(2, &mut p);
+        p.push(VALUE_INT);
+        zigzag(-3, &mut p);
+        varint(0, &mut p);
+        p.extend([VALUE_FLOAT].iter().chain(&0.5f64.to_le_bytes()));
+        input.extend(frame(FRAME_EVENT, &p));
+
+        // timestamps are relative to the previous event
+        input.extend(frame(FRAME_EVENT, &[b'i', 0, 0, 2, 0, 0]));
+
+        let mut d = Decoder::default();
+        let mut r = &input[..];
+        let mut events = vec![];
+        let mut out = String::new();
+        while let Some((_, msg)) = d.next(&mut r, &mut out).unwrap() {
+            if let Msg::Add { json } = msg {
+                events.push(serde_json::from_str::<serde_json::Value>(json).unwrap());
+            }
+            out.clear();
+        }
+        assert_eq!(
+            events,
+            [
+                serde_json::json!({
+                    "ph": "X", "name": "work", "cat": "cat \"x\"", "ts": 1.5, "pid": -1,
+                    "tid": 7, "dur": 2, "id": "n", "args": {"n": -3, "work": 0.5},
+                }),
+                serde_json::json!({"ph": "i", "name": "work", "ts": 1.501, "pid": 0, "tid": 0}),
+            ]
+        );
+    }
+
+    #[test]
+    fn handle() {
+        let mut input = string(0, "a");
+        input.extend(frame(FRAME_HANDLE, b"j1"));
+        input.extend(frame(FRAME_EVENT, &[b'i', 0, 0, 0, 0, 0]));
+        input.extend(frame(FRAME_HANDLE, b""));
+        input.extend(frame(FRAME_EVENT, &[b'i', 0, 0, 0, 0, 0]));
+        let res = decode(&input).unwrap();
+        assert!(res[0].starts_with(r#"Some("j1") Add"#), "{res:?}");
+        assert!(res[1].starts_with("None Add"), "{res:?}");
+
+        assert!(decode(&frame(FRAME_HANDLE, b"j 1")).is_err());
+    }
+
+    #[test]
+    fn invalid_ph() {
+        for ph in [b'"', b'\\', b' ', 0xc3] {
+            let mut input = string(0, "a");
+            input.extend(frame(FRAME_EVENT, &[ph, 0, 0, 0, 0, 0]));
+            assert!(decode(&input).is_err(), "ph {ph}");
+        }
+    }
+
+    #[test]
+    fn strings() {
+        // out of order
+        assert!(decode(&string(1, "a")).is_err());
+        // undefined
+        assert!(decode(&frame(FRAME_EVENT, &[b'i', 0, 0, 0, 0, 0])).is_err());
+        // redefined
+        let mut input = string(0, "a");
+        input.extend(string(0, "b"));
+        input.extend(frame(FRAME_EVENT, &[b'i', 0, 0, 0, 0, 0]));
+        assert!(decode(&input).unwrap()[0].contains(r#"\"name\":\"b\""#));
+
+        let mut d = Decoder {
+            strings: vec![String::new(); MAX_STRINGS],
+            ..Default::default()
+        };
+        let input = string(MAX_STRINGS as u64, "a");
+        assert!(d.next(&mut &input[..], &mut String::new()).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut input = string(0, "a");
+        input.extend(frame(FRAME_EVENT, &[b'i', 0, 0, 0, 0]));
+        assert!(decode(&input).is_err());
+
+        // the stream ends in the middle of a frame
+        let input = frame(FRAME_MSG, b"OPEN foo");
+        assert!(decode(&input[..input.len() - 1]).is_err());
+        assert!(decode(&input[..2]).unwrap().is_empty());
+
+        assert!(decode(&[0, 0, 0, 0]).is_err());
+        assert!(decode(&frame(9, b"")).is_err());
+    }
+
+    #[test]
+    fn overlong_varint() {
+        let mut input = vec![0];
+        input.extend([0xff; 10]);
+        input.push(0);
+        assert!(decode(&frame(FRAME_STRING, &input[1..])).is_err());
+
+        let mut input = string(0, "a");
+        let mut p = vec![b'i', 0, 0];
+        p.extend([0xff; 10]);
+        p.extend([0, 0, 0]);
+        input.extend(frame(FRAME_EVENT, &p));
+        assert!(decode(&input).is_err());
+    }
+
+    #[test]
+    fn ts_overflow() {
+        let mut input = string(0, "a");
+        for _ in 0..2 {
+            let mut p = vec![b'i', 0, 0];
+            zigzag(i64::MAX, &mut p);
+            p.extend([0, 0]);
+            input.extend(frame(FRAME_EVENT, &p));
+        }
+        let err = decode(&input).unwrap_err();
+        assert!(err.to_string().contains("overflow"), "{err}");
+    }
+}